@@ -31,7 +31,7 @@ pub fn binops(input: TS) -> TS {
         Comparison,
     }
 
-    let mut arms: Vec<TS2> = Vec::new();
+    let mut ops: Vec<TS2> = Vec::new();
     let mut second = false;
     let mut ty: Option<Type> = None;
     for i in iter {
@@ -47,11 +47,7 @@ pub fn binops(input: TS) -> TS {
                 let tt: TT = lit.clone().into();
                 let ts: TS2 = tt.into();
                 let ts: TS = ts.into();
-                let closure: TS2 = f(ts).into();
-                let arm = quote! {
-                    #lit => #closure
-                };
-                arms.push(arm);
+                ops.push(f(ts).into());
             } else {
                 panic!("Expected a literal following type.")
             }
@@ -71,12 +67,7 @@ pub fn binops(input: TS) -> TS {
     }
 
     let tokens = quote! {
-        Some(match op {
-            #(#arms,)*
-            _ => {
-                return None;
-            }
-        })
+        vec![#(#ops),*]
     };
 
     tokens.into()
@@ -113,9 +104,29 @@ pub fn cmp_op(input: TS) -> TS {
     }
 }
 
+/// Takes a table of the form `"swap" (a b -- b a) "dup" (a -- a a) ...` and
+/// constructs a `Vec` of the ops, like `binops!`.
 #[proc_macro]
-pub fn shuffle(input: TS) -> TS {
-    let input: TS2 = input.into();
+pub fn shuffles(input: TS) -> TS {
+    let mut iter = TS2::from(input).into_iter();
+
+    let mut ops: Vec<TS2> = Vec::new();
+    while let Some(name) = iter.next() {
+        if let (TT::Literal(name), Some(TT::Group(spec))) = (name, iter.next()) {
+            ops.push(impl_shuffle(&name, spec.stream()));
+        } else {
+            panic!("Expected a name followed by a shuffle in parentheses.")
+        }
+    }
+
+    let tokens = quote! {
+        vec![#(#ops),*]
+    };
+
+    tokens.into()
+}
+
+fn impl_shuffle(name: &Literal, input: TS2) -> TS2 {
     let mut args: Vec<Ident> = Vec::new();
     let mut out: Vec<Ident> = Vec::new();
     let mut iter = input.into_iter();
@@ -142,19 +153,32 @@ pub fn shuffle(input: TS) -> TS {
         }
     }
 
-    let args = args.iter().rev();
-
     let num_in = args.len();
     let num_out = out.len();
 
-    let tokens = quote! {
-        O::new(|env: &mut Env| {
-            #(let #args = env.pop_atom();)*
-            #(env.push_atom(#out);)*
-        }, Some((#num_in as u8, #num_out as u8)))
-    };
+    // Values which are dropped are popped without a binding, and values which
+    // are pushed more than once are cloned for all but their last use.
+    let pops = args.iter().rev().map(|arg| {
+        if out.contains(arg) {
+            quote! { let #arg = env.pop_atom(); }
+        } else {
+            quote! { env.pop_atom(); }
+        }
+    });
+    let pushes = out.iter().enumerate().map(|(i, arg)| {
+        if out[i + 1..].contains(arg) {
+            quote! { env.push_atom(#arg.clone()); }
+        } else {
+            quote! { env.push_atom(#arg); }
+        }
+    });
 
-    tokens.into()
+    quote! {
+        O::new(#name, |env: &mut Env| {
+            #(#pops)*
+            #(#pushes)*
+        }, Some((#num_in as u8, #num_out as u8)))
+    }
 }
 
 /// Takes something similar to the form `"+" ((a: Num, b: Num) -> Num) { a + b }`
@@ -205,7 +229,7 @@ fn impl_atomify(iter: impl Iterator<Item = TT>) -> TS {
     };
 
     let tokens = quote! {
-        O::new(|env: &mut Env| {
+        O::new(#name, |env: &mut Env| {
             #(let #arg_name_rev = env.pop_atom();)*
             if let (#(#arg_type(mut #arg_name)),*) = (#(#arg_name),*) {
                 #expr
@@ -0,0 +1,94 @@
+#[macro_use]
+extern crate criterion;
+
+use criterion::{BenchmarkId, Criterion};
+
+use pancake::ast::{Literal, Pgrm, Term};
+use pancake::inference_data::TVarRegistry;
+use pancake::prelude::prelude;
+use pancake::typeck::{expand_type, infer_pgrm};
+
+use std::rc::Rc;
+
+fn var(name: &str) -> Term {
+    Term::Var(name.into())
+}
+
+fn app(f: Term, args: Vec<Term>) -> Term {
+    args.into_iter()
+        .fold(f, |f, x| Term::App(Rc::new(f), Rc::new(x)))
+}
+
+fn int(n: i32) -> Term {
+    Term::Lit(Literal::Int(n))
+}
+
+/// A chain of `n` functions like
+/// `fn f1 n = n 0 > [ n 1 - f0 ] [ n f0 2 * ] cond`, each of which calls the
+/// one before it twice, so that a blowup in the number of constraints shows up
+/// as superlinear time.
+fn program(n: usize) -> Pgrm {
+    let f0 = Term::Lam(
+        "n".into(),
+        Rc::new(app(var("+"), vec![var("n"), int(1)])),
+    );
+    let mut defs = vec![(false, "f0".into(), f0)];
+    for i in 1..n {
+        let prev = || var(&format!("f{}", i - 1));
+        let body = app(
+            var("cond"),
+            vec![
+                app(var(">"), vec![var("n"), int(0)]),
+                app(prev(), vec![app(var("-"), vec![var("n"), int(1)])]),
+                app(var("*"), vec![app(prev(), vec![var("n")]), int(2)]),
+            ],
+        );
+        let fi = Term::Lam("n".into(), Rc::new(body));
+        defs.push((false, format!("f{}", i).into(), fi));
+    }
+    let fns = (0..n).collect();
+    Pgrm { defs, fns }
+}
+
+/// A single function like `fn big n = n 1 + 1 + ... 1 +` with `n` additions, so
+/// that work done at each term which depends on the size of the body shows up
+/// as superlinear time.
+fn large_body(n: usize) -> Pgrm {
+    let body = (0..n).fold(var("n"), |body, _| app(var("+"), vec![body, int(1)]));
+    let big = Term::Lam("n".into(), Rc::new(body));
+    Pgrm {
+        defs: vec![(false, "big".into(), big)],
+        fns: vec![0].into_iter().collect(),
+    }
+}
+
+fn infer(pgrm: &Pgrm) {
+    let mut tvars = TVarRegistry::new();
+    let mut ctx = prelude(&mut tvars);
+    let types = infer_pgrm(pgrm, &mut ctx, &mut tvars).unwrap();
+    let (_, typ) = types.into_iter().last().unwrap();
+    assert_eq!("int -> int", expand_type(typ, &mut tvars).to_string());
+}
+
+fn criterion_benchmark(c: &mut Criterion) {
+    let mut group = c.benchmark_group("infer chain");
+    for &n in [25, 50, 100, 200].iter() {
+        let pgrm = program(n);
+        group.bench_with_input(BenchmarkId::from_parameter(n), &pgrm, |b, pgrm| {
+            b.iter(|| infer(pgrm))
+        });
+    }
+    group.finish();
+
+    let mut group = c.benchmark_group("infer large body");
+    for &n in [250, 500, 1000, 2000].iter() {
+        let pgrm = large_body(n);
+        group.bench_with_input(BenchmarkId::from_parameter(n), &pgrm, |b, pgrm| {
+            b.iter(|| infer(pgrm))
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, criterion_benchmark);
+criterion_main!(benches);
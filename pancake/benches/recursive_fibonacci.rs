@@ -3,21 +3,66 @@ extern crate criterion;
 
 use criterion::Criterion;
 
-use pancake::eval::eval_program;
+use pancake::bytecode;
+use pancake::eval::{eval_atom, eval_program};
+use pancake::parse::parse_line;
 use pancake::types::Atom;
+use pancake::vm2::Env;
 
-fn fibonacci() {
-    let expected_out = vec![Atom::Num(55)];
-    let mut env = eval_program(
-        r"
+const FIB: &str = r"
 fn fib = dup 2 <= [ drop 1 ] [ 1 - dup 1 - fib swap fib + ] cond
-10 fib",
-    );
+10 fib";
+
+/// Evaluates the lines of `FIB`, which are parsed beforehand as the bytecode
+/// case compiles them beforehand.
+fn fibonacci(lines: &[Vec<Atom>]) {
+    let expected_out = vec![Atom::Num(55)];
+    let mut env = Env::new();
+    for atoms in lines {
+        for atom in atoms.iter().cloned() {
+            eval_atom(atom, &mut env);
+        }
+    }
+    assert_eq!(expected_out, env.pop().unwrap().stack);
+}
+
+fn fibonacci_bytecode(program: &bytecode::Program) {
+    let expected_out = vec![Atom::Num(55)];
+    let mut env = Env::new();
+    bytecode::run_program(program, &mut env);
     assert_eq!(expected_out, env.pop().unwrap().stack);
 }
 
+/// Looks up and indexes a large list many times, which copies it if lists
+/// aren't shared.
+fn large_list(program: &str) {
+    let mut env = eval_program(program);
+    assert_eq!(vec![Atom::Num(100 * 999)], env.pop().unwrap().stack);
+}
+
+/// Checks the arity of a large quotation many times without calling it, which
+/// walks the quotation if its arity isn't cached cheaply.
+fn large_quotation(program: &str) {
+    let mut env = eval_program(program);
+    assert_eq!(vec![Atom::Num(0)], env.pop().unwrap().stack);
+}
+
 fn criterion_benchmark(c: &mut Criterion) {
-    c.bench_function("fib 10", |b| b.iter(|| fibonacci()));
+    let lines: Vec<Vec<Atom>> = FIB.split('\n').map(parse_line).collect();
+    c.bench_function("fib 10", |b| b.iter(|| fibonacci(&lines)));
+    let program = bytecode::compile_program(FIB);
+    c.bench_function("fib 10 (bytecode)", |b| b.iter(|| fibonacci_bytecode(&program)));
+    let numbers: Vec<String> = (0..1000).map(|n| n.to_string()).collect();
+    let program = format!(
+        "let L = [ {} ]list\n0 [ L .999 + ] 100 repeat",
+        numbers.join(" ")
+    );
+    c.bench_function("large list", |b| b.iter(|| large_list(&program)));
+    let program = format!(
+        "let Q = [ {} ]\n0 [ false Q if ] 1000 repeat",
+        vec!["1 drop"; 500].join(" ")
+    );
+    c.bench_function("large quotation", |b| b.iter(|| large_quotation(&program)));
 }
 
 criterion_group!(benches, criterion_benchmark);
@@ -1,90 +1,699 @@
-use crate::ops::get_boolean_op;
-use crate::types::{Arity, Atom};
-use crate::vm::Env;
-
-pub fn arity_atom(atom: &Atom, env: &mut Env, arities: &mut Vec<Arity>) -> Arity {
-    let cond = get_boolean_op("cond").unwrap();
-    use Atom::*;
-    match atom {
-        Bool(_) | Num(_) | Symbol(_) => Some((0, 1)),
-        Quotation(_) | Function(_, _) => arity_fn(atom, env),
-        // TODO: Handle arities of other control flow combinators
-        Op(op) => {
-            if cond != op {
-                op.arity
+use crate::ops::parse_shuffle;
+use crate::types::{Arity, Atom, NumType, Op};
+use crate::vm::Identifier;
+use crate::vm2::Env;
+
+use std::cell::{Cell, RefCell};
+use std::collections::HashMap;
+use std::convert::TryFrom;
+use std::fmt;
+use std::rc::{Rc, Weak};
+
+use StaticArity::*;
+
+/// Builtin words whose arity depends on the operands they are given.
+const COMBINATORS: [&str; 21] = [
+    "cond",
+    "if",
+    "repeat",
+    "for_else",
+    "for_if",
+    "list",
+    "map",
+    "reduce_inner",
+    "keep",
+    "keep2",
+    "dip",
+    "2dip",
+    "bi",
+    "tri",
+    "cleave",
+    "bi*",
+    "tri*",
+    "bi@",
+    "spread",
+    "napply",
+    "shuffle",
+];
+
+/// Builtin words which take or leave a number of values only known at runtime.
+const VARIADIC: [&str; 1] = ["splat"];
+
+/// How many times to re-infer a recursive function, each time assuming the
+/// arity from the last, before giving up on reaching a fixed point.
+const MAX_FIXED_POINT_ITERATIONS: usize = 8;
+
+/// The arity of some code as far as it can be determined statically.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StaticArity {
+    Fixed(u8, u8),
+    /// Depends on values only known at runtime, e.g. `splat` or an `if` whose
+    /// body doesn't leave the stack the same size.
+    Variadic,
+    /// Could not be inferred, e.g. because of a free variable.
+    Unknown,
+}
+
+impl StaticArity {
+    pub fn fixed(self) -> Arity {
+        match self {
+            Fixed(num_in, num_out) => Some((num_in, num_out)),
+            _ => None,
+        }
+    }
+
+    /// The arity of evaluating `self` and then `next`.
+    pub fn then(self, next: StaticArity) -> StaticArity {
+        match (self, next) {
+            (Fixed(in0, out0), Fixed(in1, out1)) => {
+                let (num_in, num_out) = compose((in0, out0), (in1, out1));
+                Fixed(num_in, num_out)
+            }
+            (Unknown, _) | (_, Unknown) => Unknown,
+            _ => Variadic,
+        }
+    }
+
+    /// The arity of evaluating `self` and `other` on adjacent segments of the
+    /// stack, as in `+,+`.
+    pub fn beside(self, other: StaticArity) -> StaticArity {
+        match (self, other) {
+            (Fixed(in0, out0), Fixed(in1, out1)) => Fixed(in0 + in1, out0 + out1),
+            (Unknown, _) | (_, Unknown) => Unknown,
+            _ => Variadic,
+        }
+    }
+}
+
+impl From<Arity> for StaticArity {
+    fn from(arity: Arity) -> Self {
+        match arity {
+            Some((num_in, num_out)) => Fixed(num_in, num_out),
+            None => Unknown,
+        }
+    }
+}
+
+/// The arity of evaluating `a` and then `b`.
+pub fn compose((in0, out0): (u8, u8), (in1, out1): (u8, u8)) -> (u8, u8) {
+    if in1 > out0 {
+        (in0 + in1 - out0, out1)
+    } else {
+        (in0, out0 - in1 + out1)
+    }
+}
+
+fn builtin_name(op: &Op, names: &[&'static str]) -> Option<&'static str> {
+    names.iter().copied().find(|&name| name == op.name)
+}
+
+/// What an arity was inferred for. Bodies are told apart by address rather
+/// than by their contents, so that looking them up doesn't walk them.
+#[derive(Clone, PartialEq, Eq, Hash)]
+enum CacheKey {
+    Name(Identifier),
+    Quotation(*const Vec<Atom>),
+    Function(*const Vec<Atom>, Vec<Identifier>),
+}
+
+impl CacheKey {
+    fn of(atom: &Atom) -> Option<Self> {
+        match atom {
+            Atom::Plain(ident) => Some(CacheKey::Name(ident.clone())),
+            Atom::Quotation(q) => Some(CacheKey::Quotation(Rc::as_ptr(q))),
+            Atom::Function(params, body, _) => {
+                Some(CacheKey::Function(Rc::as_ptr(body), params.clone()))
+            }
+            _ => None,
+        }
+    }
+}
+
+struct CacheEntry {
+    arity: StaticArity,
+    /// The names which were looked up at the top level, since the entry
+    /// doesn't apply where one of them is shadowed.
+    names: Vec<Identifier>,
+    /// The body the entry is keyed by. While there's a weak reference to it,
+    /// its address isn't reused by another body, even once it's dropped.
+    body: Option<Weak<Vec<Atom>>>,
+}
+
+impl CacheEntry {
+    /// Whether the entry can still be looked up, which it can't once its body
+    /// has been dropped.
+    fn is_live(&self) -> bool {
+        self.body
+            .as_ref()
+            .map_or(true, |body| body.strong_count() > 0)
+    }
+}
+
+/// Arities of quotations, functions and the names of top-level functions.
+#[derive(Default)]
+pub struct ArityCache {
+    entries: RefCell<HashMap<CacheKey, CacheEntry>>,
+    /// The number of entries left when dead ones were last removed.
+    live: Cell<usize>,
+}
+
+impl ArityCache {
+    fn get(&self, key: &Atom, is_shadowed: impl Fn(&Identifier) -> bool) -> Option<StaticArity> {
+        match self.entries.borrow().get(&CacheKey::of(key)?) {
+            Some(entry) if !entry.names.iter().any(is_shadowed) => Some(entry.arity),
+            _ => None,
+        }
+    }
+
+    fn insert(&self, key: &Atom, arity: StaticArity, names: Vec<Identifier>) {
+        let body = match key {
+            Atom::Quotation(body) | Atom::Function(_, body, _) => Some(Rc::downgrade(body)),
+            _ => None,
+        };
+        if let Some(key) = CacheKey::of(key) {
+            let mut entries = self.entries.borrow_mut();
+            // The quotations in a function body are built afresh each time it's
+            // called, so drop their entries once they've run rather than
+            // letting the cache grow with every call.
+            if entries.len() >= 2 * self.live.get().max(16) {
+                entries.retain(|_, entry| entry.is_live());
+                self.live.set(entries.len());
+            }
+            entries.insert(key, CacheEntry { arity, names, body });
+        }
+    }
+
+    pub fn clear(&mut self) {
+        self.entries.get_mut().clear();
+        self.live.set(0);
+    }
+
+    /// The number of entries which can still be looked up.
+    fn len(&self) -> usize {
+        self.entries
+            .borrow()
+            .values()
+            .filter(|entry| entry.is_live())
+            .count()
+    }
+}
+
+impl fmt::Debug for ArityCache {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "ArityCache ({} entries)", self.len())
+    }
+}
+
+/// Turns the `[` and `]` tokens in the body of a function, which is parsed but
+/// not evaluated until the function is called, into quotations.
+pub fn nest_quotations(body: &[Atom]) -> Vec<Atom> {
+    let mut nested: Vec<Vec<Atom>> = vec![Vec::new()];
+    for atom in body {
+        match atom {
+            Atom::QuotationStart => nested.push(Vec::new()),
+            Atom::QuotationEnd if nested.len() > 1 => {
+                let q = nested.pop().unwrap();
+                nested.last_mut().unwrap().push(Atom::Quotation(Rc::new(q)));
+            }
+            atom => nested.last_mut().unwrap().push(atom.clone()),
+        }
+    }
+    if nested.len() == 1 {
+        nested.pop().unwrap()
+    } else {
+        body.to_vec()
+    }
+}
+
+/// Arity inference for a single query. This keeps track of the names in scope
+/// which aren't bound in the `Env`: parameters, local definitions and
+/// recursive functions which are still being inferred.
+struct Inference<'a> {
+    env: &'a Env,
+    assumed: Vec<(Identifier, StaticArity)>,
+    /// What the arity being inferred depends on, to decide whether it can be
+    /// cached: the earliest entry of `assumed` used, whether any variables
+    /// bound in local frames were used, and the names looked up at the top
+    /// level.
+    first_assumed_used: usize,
+    used_locals: bool,
+    names_used: Vec<Identifier>,
+}
+
+impl<'a> Inference<'a> {
+    fn new(env: &'a Env) -> Self {
+        Self {
+            env,
+            assumed: Vec::new(),
+            first_assumed_used: usize::MAX,
+            used_locals: false,
+            names_used: Vec::new(),
+        }
+    }
+
+    /// Looks up the arity of `key` in the cache, or else infers it with
+    /// `infer` and caches it if it only depends on the top level.
+    fn cached(&mut self, key: &Atom, infer: impl FnOnce(&mut Self) -> StaticArity) -> StaticArity {
+        let is_shadowed = |name: &Identifier| {
+            self.env.binds_locally(name) || self.assumed.iter().any(|(ident, _)| ident == name)
+        };
+        if let Some(arity) = self.env.arities.get(key, is_shadowed) {
+            return arity;
+        }
+
+        let scope = self.assumed.len();
+        let first_assumed_used = std::mem::replace(&mut self.first_assumed_used, usize::MAX);
+        let used_locals = std::mem::replace(&mut self.used_locals, false);
+        let names_used = std::mem::take(&mut self.names_used);
+
+        let arity = infer(self);
+
+        if self.first_assumed_used >= scope && !self.used_locals {
+            self.env.arities.insert(key, arity, self.names_used.clone());
+        }
+        self.first_assumed_used = self.first_assumed_used.min(first_assumed_used);
+        self.used_locals |= used_locals;
+        self.names_used.extend(names_used);
+        arity
+    }
+
+    fn atom(&mut self, atom: &Atom) -> StaticArity {
+        use Atom::*;
+        match atom {
+            Bool(_) | Num(_) | Str(_) | Symbol(_) | List(_) | Record(_) | Quotation(_)
+            | Function(..) => Fixed(0, 1),
+            Select(_) => Fixed(1, 1),
+            Op(op) if builtin_name(op, &VARIADIC).is_some() => Variadic,
+            Op(op) => op.arity.into(),
+            Plain(ident) => self.name(ident),
+            Concat(words) => words
+                .iter()
+                .fold(Fixed(0, 0), |arity, word| arity.beside(self.word(word))),
+            DefVarLiteral | DefFnLiteral => Fixed(2, 0),
+            _ => Unknown,
+        }
+    }
+
+    /// Like `atom`, but functions are called rather than pushed, as they are
+    /// in a concatenation.
+    fn word(&mut self, atom: &Atom) -> StaticArity {
+        if let Atom::Function(..) = atom {
+            self.function(atom)
+        } else {
+            self.atom(atom)
+        }
+    }
+
+    fn name(&mut self, ident: &Identifier) -> StaticArity {
+        if let Some(i) = self.assumed.iter().rposition(|(name, _)| name == ident) {
+            self.first_assumed_used = self.first_assumed_used.min(i);
+            return self.assumed[i].1;
+        }
+        if self.env.binds_locally(ident) {
+            self.used_locals = true;
+            return self.variable(ident);
+        }
+        self.names_used.push(ident.clone());
+        self.cached(&Atom::Plain(ident.clone()), |inference| {
+            inference.variable(ident)
+        })
+    }
+
+    fn variable(&mut self, ident: &Identifier) -> StaticArity {
+        match self.env.find_var(ident) {
+            Some(f @ Atom::Function(..)) => self.recursive_function(ident, &f),
+            Some(op @ Atom::Op(_)) => self.atom(&op),
+            Some(_) => Fixed(0, 1),
+            None => Unknown,
+        }
+    }
+
+    /// Infers the arity of a function which may call itself by `ident`, by
+    /// assuming an arity for the recursive calls and refining it until it stops
+    /// changing. Nothing is assumed at first, so the arity comes from the
+    /// branches which don't recurse.
+    fn recursive_function(&mut self, ident: &Identifier, f: &Atom) -> StaticArity {
+        if let Atom::Function(_, _, Some((num_in, num_out))) = f {
+            return Fixed(*num_in, *num_out);
+        }
+        let mut assumed = Unknown;
+        for _ in 0..MAX_FIXED_POINT_ITERATIONS {
+            self.assumed.push((ident.clone(), assumed));
+            let inferred = self.infer_function(f);
+            self.assumed.pop();
+            if inferred == assumed {
+                return inferred;
+            }
+            assumed = inferred;
+        }
+        Unknown
+    }
+
+    /// The arity of calling a quotation or function.
+    fn function(&mut self, f: &Atom) -> StaticArity {
+        match f {
+            Atom::Function(_, _, Some((num_in, num_out))) => Fixed(*num_in, *num_out),
+            Atom::Function(..) | Atom::Quotation(_) => {
+                self.cached(f, |inference| inference.infer_function(f))
+            }
+            _ => Unknown,
+        }
+    }
+
+    fn infer_function(&mut self, f: &Atom) -> StaticArity {
+        let (params, body): (&[Identifier], &[Atom]) = match f {
+            Atom::Function(p, b, _) => (p, b),
+            Atom::Quotation(q) => (&[], q),
+            _ => panic!("arity_fn called on non-function"),
+        };
+
+        let scope = self.assumed.len();
+        for param in params {
+            self.assumed.push((param.clone(), Fixed(0, 1)));
+        }
+        let arity = self.infer_body(&nest_quotations(body), params.len() as u8);
+        self.assumed.truncate(scope);
+        arity
+    }
+
+    fn infer_body(&mut self, quot: &[Atom], num_params: u8) -> StaticArity {
+        use Atom::*;
+
+        let mut arities: Vec<StaticArity> = Vec::new();
+
+        for (i, atom) in quot.iter().enumerate() {
+            // Combinators consume the arities of their operands, which must be
+            // literals immediately preceding them.
+            let operands = &quot[..i];
+            let combinator = match atom {
+                Op(op) => self.combinator(op, operands),
+                Call => Some((1, operands.last().map_or(Unknown, |q| self.function(q)))),
+                _ => None,
+            };
+
+            // Local definitions are in scope for the rest of the body.
+            match (operands, atom) {
+                ([.., _, Symbol(ident)], DefVarLiteral) => {
+                    self.assumed.push((ident.clone(), Fixed(0, 1)));
+                }
+                ([.., q, Symbol(ident)], DefFnLiteral) => {
+                    let arity = self.function(q);
+                    self.assumed.push((ident.clone(), arity));
+                }
+                _ => (),
+            }
+
+            if let Some((num_operands, arity)) = combinator {
+                if arities.len() < num_operands {
+                    return Unknown;
+                }
+                arities.truncate(arities.len() - num_operands);
+                arities.push(arity);
             } else {
-                if arities.len() < 2 {
-                    return None;
+                arities.push(self.atom(atom));
+            }
+        }
+
+        arities
+            .into_iter()
+            .fold(Fixed(num_params, 0), StaticArity::then)
+    }
+
+    /// Arities of a quotation (or list) of quotations, as taken by `cleave` and
+    /// `spread`.
+    fn quotations(&mut self, seq: &Atom) -> Vec<StaticArity> {
+        match seq {
+            Atom::Quotation(qs) => qs.iter().map(|q| self.function(q)).collect(),
+            Atom::List(qs) => qs.iter().map(|q| self.function(q)).collect(),
+            _ => vec![Unknown],
+        }
+    }
+
+    fn combinator(&mut self, op: &Op, operands: &[Atom]) -> Option<(usize, StaticArity)> {
+        let name = builtin_name(op, &COMBINATORS)?;
+        let num_operands = match name {
+            "cond" | "repeat" | "bi" | "bi*" | "napply" => 2,
+            "tri" | "tri*" => 3,
+            _ => 1,
+        };
+        if operands.len() < num_operands {
+            return Some((num_operands, Unknown));
+        }
+        let operands = &operands[operands.len() - num_operands..];
+
+        let arity = match name {
+            "cond" => {
+                // We just assume that, if one branch has undefined arity, the
+                // branches must agree.
+                match (self.function(&operands[0]), self.function(&operands[1])) {
+                    (Fixed(num_in, num_out), Unknown) | (Unknown, Fixed(num_in, num_out)) => {
+                        Fixed(num_in + 1, num_out)
+                    }
+                    (a, b) if a == b => Fixed(1, 0).then(a),
+                    (Unknown, _) | (_, Unknown) => Unknown,
+                    _ => Variadic,
                 }
-                let a = arities.pop();
-                let b = arities.pop();
-                // We just assume that, if one branch has undefined arity,
-                // the branches must agree.
-                let arity = if let (Some(a), Some(b)) = (a, b) {
-                    if a.is_some() && b.is_some() {
-                        assert_eq!(a, b);
-                        a
-                    } else if a.is_some() {
-                        a
-                    } else if b.is_some() {
-                        b
-                    } else {
-                        None
+            }
+            // These leave the stack as it was when their bodies don't run, so
+            // they only have a fixed arity if their bodies don't change the
+            // size of the stack either.
+            "if" | "for_else" | "for_if" => {
+                let num_conditions = if name == "if" { 1 } else { 0 };
+                match self.function(&operands[0]) {
+                    Fixed(num_in, num_out) if num_in == num_out => {
+                        Fixed(num_in + num_conditions, num_out)
                     }
-                } else {
-                    None
-                };
-                if let Some((num_in, num_out)) = arity {
-                    Some((num_in + 1, num_out))
-                } else {
-                    None
+                    Unknown => Unknown,
+                    _ => Variadic,
                 }
             }
-        }
-        _ => None,
+            "repeat" => match (self.function(&operands[0]), &operands[1]) {
+                (Fixed(num_in, num_out), Atom::Num(times)) => {
+                    arity_repeat((num_in, num_out), *times)
+                }
+                (Fixed(num_in, num_out), _) if num_in == num_out => Fixed(num_in, num_out),
+                (Unknown, _) => Unknown,
+                _ => Variadic,
+            },
+            // These call their quotations in a new frame.
+            "list" => Fixed(0, 1),
+            "map" | "reduce_inner" => Fixed(1, 1),
+            "shuffle" => match &operands[0] {
+                Atom::Str(spec) => {
+                    let (num_in, outputs) = parse_shuffle(spec);
+                    Fixed(num_in as u8, outputs.len() as u8)
+                }
+                _ => Unknown,
+            },
+            _ => {
+                let quots: Vec<StaticArity> = match name {
+                    "cleave" | "spread" => self.quotations(&operands[0]),
+                    "napply" => match &operands[1] {
+                        Atom::Num(n) => match u8::try_from(*n) {
+                            Ok(n) => vec![self.function(&operands[0]); n as usize],
+                            Err(_) => vec![Unknown],
+                        },
+                        _ => vec![Unknown],
+                    },
+                    "bi@" => vec![self.function(&operands[0]); 2],
+                    _ => operands.iter().map(|q| self.function(q)).collect(),
+                };
+                arity_dataflow(name, &quots)
+            }
+        };
+
+        Some((num_operands, arity))
     }
 }
 
-pub fn arity_fn(f: &Atom, env: &mut Env) -> Arity {
-    use Atom::*;
+/// Pops `num_objects` values, then pushes them back one at a time, calling the
+/// next quotation after each push. This covers `bi`, `tri` and `cleave` (with
+/// one object pushed repeatedly) as well as `bi*`, `tri*`, `bi@`, `spread` and
+/// `napply`.
+fn arity_fan_out(num_objects: u8, quots: &[StaticArity]) -> StaticArity {
+    quots.iter().fold(Fixed(num_objects, 0), |acc, &q| {
+        acc.then(Fixed(0, 1)).then(q)
+    })
+}
 
-    let num_in: u8;
-    let quot: &Vec<Atom>;
-    if let Function(p, q) = f {
-        num_in = p.len() as u8;
-        quot = q;
-    } else if let Quotation(q) = f {
-        num_in = 0;
-        quot = q;
+/// The arity of calling a function with the given arity `times` times in a row.
+fn arity_repeat((num_in, num_out): (u8, u8), times: NumType) -> StaticArity {
+    if times <= 0 {
+        return Fixed(0, 0);
+    }
+    let extra = |growth: u8| {
+        NumType::from(growth)
+            .checked_mul(times - 1)
+            .and_then(|extra| u8::try_from(extra).ok())
+    };
+    let arity = if num_out >= num_in {
+        extra(num_out - num_in)
+            .and_then(|extra| num_out.checked_add(extra))
+            .map(|out| (num_in, out))
     } else {
-        panic!("arity_fn called on non-function");
+        extra(num_in - num_out)
+            .and_then(|extra| num_in.checked_add(extra))
+            .map(|num_in| (num_in, num_out))
+    };
+    arity.into()
+}
+
+/// The arity of a dataflow combinator, given the arities of its quotations.
+fn arity_dataflow(name: &str, quots: &[StaticArity]) -> StaticArity {
+    match (name, quots.first().copied().unwrap_or(Unknown)) {
+        (_, Unknown) => Unknown,
+        ("keep", Fixed(num_in, _)) => Fixed(num_in, num_in + 1),
+        ("keep2", Fixed(num_in, num_out)) => {
+            let num_in_kept = num_in.max(2);
+            Fixed(num_in_kept, num_in_kept - num_in + num_out + 2)
+        }
+        ("dip", Fixed(num_in, num_out)) => Fixed(num_in + 1, num_out + 1),
+        ("2dip", Fixed(num_in, num_out)) => Fixed(num_in + 2, num_out + 2),
+        ("bi", _) | ("tri", _) | ("cleave", _) => arity_fan_out(1, quots),
+        ("bi*", _) | ("tri*", _) | ("bi@", _) | ("spread", _) | ("napply", _) => {
+            arity_fan_out(quots.len() as u8, quots)
+        }
+        _ => Variadic,
     }
+}
 
-    let mut arities: Vec<Arity> = Vec::new();
+/// The arity of evaluating `atom`, resolving names through `env`.
+pub fn arity_atom(atom: &Atom, env: &Env) -> StaticArity {
+    Inference::new(env).atom(atom)
+}
 
-    for atom in quot {
-        let arity = arity_atom(atom, env, &mut arities);
-        arities.push(arity);
-    }
+/// Like `arity_atom`, taking the names in `assumed` to have the given arities
+/// rather than looking them up in `env`.
+pub fn arity_atom_assuming(
+    atom: &Atom,
+    env: &Env,
+    assumed: Vec<(Identifier, StaticArity)>,
+) -> StaticArity {
+    let mut inference = Inference::new(env);
+    inference.assumed = assumed;
+    inference.atom(atom)
+}
+
+/// Like `arity_atom`, but functions are called rather than pushed, as they are
+/// in a concatenation.
+pub fn arity_word(atom: &Atom, env: &Env) -> StaticArity {
+    Inference::new(env).word(atom)
+}
+
+/// If `op` is a combinator, returns how many of the trailing `operands` (the
+/// quotations, the count for `napply` and `repeat` and the spec for `shuffle`)
+/// it consumes, along with its arity excluding those operands.
+pub fn arity_combinator(op: &Op, operands: &[Atom], env: &Env) -> Option<(usize, StaticArity)> {
+    Inference::new(env).combinator(op, operands)
+}
 
-    let mut num_in = num_in;
-    let mut num_out: u8 = 0;
+/// The arity of calling a quotation or function, going by a function's
+/// declared stack effect if it has one.
+pub fn arity_fn(f: &Atom, env: &Env) -> StaticArity {
+    if let Atom::Function(..) | Atom::Quotation(_) = f {
+        Inference::new(env).function(f)
+    } else {
+        panic!("arity_fn called on non-function");
+    }
+}
 
-    for arity in arities {
-        if let Some((in1, out1)) = arity {
-            if in1 > num_out {
-                num_in += in1 - num_out;
-                num_out = out1;
-            } else if in1 == num_out {
-                num_out = out1;
-            } else if in1 < num_out {
-                num_out -= in1;
-                num_out += out1;
+/// Checks that the declared stack effect of the function `ident` matches its
+/// body, as far as the arity of its body can be inferred.
+pub fn check_declared_arity(ident: &str, f: &Atom, env: &Env) {
+    if let Atom::Function(_, _, Some((num_in, num_out))) = f {
+        let mut inference = Inference::new(env);
+        // Recursive calls are assumed to behave as declared.
+        inference
+            .assumed
+            .push((Identifier::from(ident), Fixed(*num_in, *num_out)));
+        if let Fixed(body_in, body_out) = inference.infer_function(f) {
+            if (body_in, body_out) != (*num_in, *num_out) {
+                panic!(
+                    "{} is declared to take {} and leave {} values, but its body takes {} and leaves {}.",
+                    ident, num_in, num_out, body_in, body_out
+                );
             }
-        } else {
-            return None;
         }
     }
+}
+
+#[test]
+fn test_arity_combinators() {
+    use crate::eval::eval_program;
+
+    let arity = |body: &str| {
+        let mut env = eval_program(&format!("[{}]", body));
+        let q = env.pop_atom();
+        arity_fn(&q, &env)
+    };
+    assert_eq!(Fixed(2, 2), arity("[1 +] dip"));
+    assert_eq!(Fixed(1, 2), arity("[1 +] [2 *] bi"));
+    assert_eq!(Fixed(2, 2), arity("[1 +] [2 *] bi*"));
+    assert_eq!(Fixed(3, 3), arity("[1 +] 3 napply"));
+    assert_eq!(Fixed(1, 3), arity("[[1 +] [2 *] [drop 0]] cleave"));
+    assert_eq!(Fixed(2, 1), arity("[1 +] [2 +] cond"));
+    assert_eq!(Variadic, arity("[1 +] [drop] cond"));
+    assert_eq!(Fixed(2, 1), arity("[1 +] if"));
+    assert_eq!(Variadic, arity("[1] if"));
+    assert_eq!(Fixed(1, 1), arity("[1 +] 5 repeat"));
+    assert_eq!(Fixed(0, 2), arity("0 [dup] 1 repeat"));
+    assert_eq!(Fixed(1, 1), arity("[1 +] map"));
+    assert_eq!(Fixed(0, 1), arity("[1 2 3] list"));
+    assert_eq!(Fixed(1, 1), arity("[1 +] call"));
+    assert_eq!(Variadic, arity("splat"));
+}
+
+#[test]
+fn test_arity_user_words() {
+    use crate::eval::eval_program;
+
+    let env = eval_program(
+        "
+fn inc = 1 +
+fn fib = dup 2 <= [ drop 1 ] [ 1 - dup 1 - fib swap fib + ] cond
+fn fibn a b c = a 0 > [ a 1 - c b c + fibn ] [ c ] cond
+fn free = x 1 +
+",
+    );
+    let arity = |name: &str| arity_atom(&Atom::Plain(Identifier::from(name)), &env);
+
+    assert_eq!(Fixed(1, 1), arity("inc"));
+    assert_eq!(Fixed(1, 1), arity("fib"));
+    assert_eq!(Fixed(3, 1), arity("fibn"));
+    assert_eq!(Unknown, arity("free"));
+    assert_eq!(Unknown, arity("x"));
+}
+
+#[test]
+fn test_arity_cache() {
+    use crate::eval::eval_program;
+
+    let mut env = eval_program("fn g = h");
+    let g = Atom::Plain(Identifier::from("g"));
+    assert_eq!(Unknown, arity_atom(&g, &env));
+    assert_eq!(Unknown, arity_atom(&g, &env));
+
+    // Binding `h` makes the arity of `g` known.
+    env.bind_var(
+        "h",
+        Atom::Function(Vec::new(), Rc::new(vec![Atom::Num(1)]), None),
+    );
+    assert_eq!(Fixed(0, 1), arity_atom(&g, &env));
+
+    // A parameter named `h` shadows the function.
+    env.push_atom(Atom::Num(0));
+    env.bind_params(&[Identifier::from("h")]);
+    assert_eq!(Fixed(0, 1), arity_atom(&g, &env));
+    env.unbind_params();
+    env.redefine_var("h", Atom::Function(Vec::new(), Rc::new(vec![]), None));
+    assert_eq!(Fixed(0, 0), arity_atom(&g, &env));
 
-    Some((num_in, num_out))
+    // Quotations built by each call don't outlive it, and nor do their entries.
+    let fib = "fn fib = dup 2 <= [ drop 1 ] [ 1 - dup 1 - fib swap fib + ] cond";
+    let sizes: Vec<usize> = [5, 10, 15]
+        .iter()
+        .map(|n| eval_program(&format!("{}\n{} fib", fib, n)).arities.len())
+        .collect();
+    assert_eq!(sizes[0], sizes[1]);
+    assert_eq!(sizes[0], sizes[2]);
 }
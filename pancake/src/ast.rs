@@ -1,37 +1,107 @@
 use crate::vm2::{Identifier};
 
+use std::collections::HashSet;
 use std::convert::From;
+use std::fmt;
+use std::rc::Rc;
 
 pub type NumType = i32;
 
-struct Pgrm {
-    defs: Vec<(bool, String, Term)>,
+/// A program as a sequence of definitions, each of which may be recursive.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Pgrm {
+    pub defs: Vec<(bool, Identifier, Term)>,
+    /// The indices of the definitions which are functions, which may be
+    /// referred to by definitions before them.
+    pub fns: HashSet<usize>,
 }
 
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub enum Literal {
-    //Bool(bool),
+    Bool(bool),
     Int(i32)
 }
 
-type BTerm = Box<Term>;
+/// Subterms are shared, so that types can refer to the terms they came from
+/// without copying them.
+type BTerm = Rc<Term>;
 
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub struct Let {
     pub rec: bool,
     pub name: Identifier,
     pub rhs: BTerm
 }
 
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub enum Term {
     Lit(Literal),
     Var(Identifier),
     Lam(Identifier, BTerm),
     App(BTerm, BTerm),
-    // Rcd(Vec<Identifier, BTerm>)
-    // Sel(BTerm, Identifier)
+    Rcd(Vec<(Identifier, BTerm)>),
+    Sel(BTerm, Identifier),
     Let {
         binding: Let,
         body: BTerm
     }
 }
+
+impl Term {
+    /// Adds the variables which `self` refers to without binding them to
+    /// `vars`.
+    pub fn free_vars(&self, vars: &mut HashSet<Identifier>) {
+        use Term::*;
+        match self {
+            Lit(_) => (),
+            Var(name) => { vars.insert(name.clone()); }
+            Lam(name, body) => {
+                let mut inner = HashSet::new();
+                body.free_vars(&mut inner);
+                inner.remove(name);
+                vars.extend(inner);
+            }
+            App(f, x) => { f.free_vars(vars); x.free_vars(vars); }
+            Rcd(fields) => for (_, rhs) in fields { rhs.free_vars(vars) },
+            Sel(rcd, _) => rcd.free_vars(vars),
+            Let { binding, body } => {
+                let mut inner = HashSet::new();
+                body.free_vars(&mut inner);
+                if binding.rec { binding.rhs.free_vars(&mut inner) }
+                inner.remove(&binding.name);
+                if !binding.rec { binding.rhs.free_vars(&mut inner) }
+                vars.extend(inner);
+            }
+        }
+    }
+
+    fn show(&self, outer: u8) -> String {
+        use Term::*;
+        let (prec, shown) = match self {
+            Lit(Literal::Bool(b)) => return b.to_string(),
+            Lit(Literal::Int(n)) => return n.to_string(),
+            Var(name) => return name.to_string(),
+            Rcd(fields) => return format!("{{{}}}", fields.iter()
+                .map(|(name, rhs)| format!("{} = {}", name, rhs.show(0)))
+                .collect::<Vec<_>>().join("; ")),
+            Sel(rcd, field) => return format!("{}.{}", rcd.show(3), field),
+            App(f, x) => (2, format!("{} {}", f.show(2), x.show(3))),
+            Lam(name, body) => (1, format!("fun {} -> {}", name, body.show(1))),
+            Let { binding, body } => (1, format!(
+                "let {}{} = {} in {}",
+                if binding.rec { "rec " } else { "" },
+                binding.name,
+                binding.rhs.show(1),
+                body.show(1)
+            ))
+        };
+        if outer > prec { format!("({})", shown) } else { shown }
+    }
+}
+
+impl fmt::Display for Term {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.show(0))
+    }
+}
@@ -0,0 +1,674 @@
+//! Compiles programs into bytecode, which is run by a tight interpreter loop
+//! instead of evaluating atoms one at a time.
+//!
+//! Top-level functions are called by index, their parameters are kept in
+//! slots rather than looked up by name, and literal quotations passed to
+//! `cond` or `call` are compiled inline as jumps. Everything which can't be
+//! compiled, like local definitions or quotations which capture parameters,
+//! is left to the tree-walking evaluator in `eval`, so a whole program always
+//! runs the same way it would there. Definitions are evaluated as they're
+//! reached as well, so that the evaluator can call the functions they define.
+//!
+//! Top-level variables which are only bound once, and which nothing shadows,
+//! are read from slots too. Other variables, like local definitions and the
+//! variables of the caller, are still looked up by name, as they are
+//! everywhere in `eval`: a function sees the variables of whoever calls it, so
+//! which binding such a name refers to isn't known until it runs.
+
+use crate::arity::nest_quotations;
+use crate::check::builtin_name;
+use crate::eval::eval_atom;
+use crate::ops::{is_shuffle, parse_shuffle};
+use crate::parse::parse_line;
+use crate::types::{Arity, Atom, Op};
+use crate::vm::Identifier;
+use crate::vm2::Env;
+
+use std::collections::{HashMap, HashSet};
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Instr {
+    /// Pushes a constant.
+    Const(u32),
+    /// Pushes a parameter of the current function.
+    Load(u32),
+    /// Calls a compiled function.
+    Call(u32),
+    Return,
+    Jump(u32),
+    /// Pops a boolean, and jumps if it's false.
+    JumpUnless(u32),
+    Add,
+    Sub,
+    Mul,
+    Div,
+    Rem,
+    Lt,
+    Gt,
+    Le,
+    Ge,
+    Eq,
+    Ne,
+    And,
+    Or,
+    Not,
+    Dup,
+    Drop,
+    Swap,
+    /// Any other shuffle.
+    Shuffle(u32),
+    /// Runs a builtin on the stack.
+    Op(u32),
+    /// Pushes the value of a top-level variable, or calls it if it's a
+    /// function.
+    Global(u32),
+    /// Evaluates a constant atom with `eval_atom`.
+    Eval(u32),
+}
+
+#[derive(Debug, Clone)]
+pub struct Function {
+    pub name: Identifier,
+    pub num_params: usize,
+    pub declared: Arity,
+    pub code: Vec<Instr>,
+}
+
+/// A line of a program, which is run as bytecode if it could be compiled.
+#[derive(Debug, Clone)]
+pub struct Line {
+    pub atoms: Vec<Atom>,
+    pub code: Option<Vec<Instr>>,
+}
+
+/// A compiled program, with the pools of values its code refers to.
+#[derive(Debug, Clone, Default)]
+pub struct Program {
+    pub functions: Vec<Function>,
+    pub lines: Vec<Line>,
+    pub consts: Vec<Atom>,
+    pub ops: Vec<Op>,
+    /// The number of inputs of each shuffle, and the inputs it outputs.
+    pub shuffles: Vec<(usize, Vec<usize>)>,
+    /// The top-level variables which are read from slots.
+    pub globals: Vec<Identifier>,
+}
+
+/// Whether `atoms` use any of `params`, or bind names of their own.
+fn captures(atoms: &[Atom], params: &[Identifier]) -> bool {
+    atoms.iter().any(|atom| match atom {
+        Atom::Plain(ident) => params.contains(ident),
+        Atom::Quotation(body) | Atom::Function(_, body, _) => captures(body, params),
+        Atom::Concat(words) => captures(words, params),
+        Atom::DefVarLiteral | Atom::DefFnLiteral | Atom::DefVar | Atom::RedefVar => true,
+        _ => false,
+    })
+}
+
+/// Adds the names used but not bound by `atoms` to `names`.
+fn free_names(atoms: &[Atom], params: &[Identifier], names: &mut HashSet<Identifier>) {
+    for atom in atoms {
+        match atom {
+            Atom::Plain(ident) if !params.contains(ident) => {
+                names.insert(ident.clone());
+            }
+            Atom::Quotation(body) => free_names(body, params, names),
+            Atom::Concat(words) => free_names(words, params, names),
+            Atom::Function(inner, body, _) => {
+                let params: Vec<Identifier> = params.iter().chain(inner).cloned().collect();
+                free_names(body, &params, names)
+            }
+            _ => (),
+        }
+    }
+}
+
+/// Adds the names bound by `let` and `fn` literals in `atoms` to `names`.
+fn local_definitions(atoms: &[Atom], names: &mut HashSet<Identifier>) {
+    for (atom, next) in atoms.iter().zip(atoms.iter().skip(1)) {
+        if let (Atom::Symbol(ident), Atom::DefVarLiteral)
+        | (Atom::Symbol(ident), Atom::DefFnLiteral) = (atom, next)
+        {
+            names.insert(ident.clone());
+        }
+    }
+    for atom in atoms {
+        if let Atom::Quotation(body) | Atom::Function(_, body, _) = atom {
+            local_definitions(body, names);
+        }
+    }
+}
+
+struct Compiler {
+    program: Program,
+    /// The functions which can be called by index: those defined once, with
+    /// `fn`.
+    indices: HashMap<Identifier, usize>,
+    /// The slots of the other variables which are only ever bound once, at
+    /// the top level.
+    globals: HashMap<Identifier, usize>,
+}
+
+impl Compiler {
+    fn constant(&mut self, atom: Atom) -> Instr {
+        self.program.consts.push(atom);
+        Instr::Const(self.program.consts.len() as u32 - 1)
+    }
+
+    fn eval(&mut self, atom: Atom) -> Instr {
+        self.program.consts.push(atom);
+        Instr::Eval(self.program.consts.len() as u32 - 1)
+    }
+
+    fn op(&mut self, op: &Op) -> Instr {
+        use Instr::*;
+        let name = match builtin_name(op) {
+            Some(name) => name,
+            None => {
+                self.program.ops.push(op.clone());
+                return Op(self.program.ops.len() as u32 - 1);
+            }
+        };
+        match name {
+            "+" => Add,
+            "-" => Sub,
+            "*" => Mul,
+            "/" => Div,
+            "%" => Rem,
+            "<" => Lt,
+            ">" => Gt,
+            "<=" => Le,
+            ">=" => Ge,
+            "==" => Eq,
+            "!=" => Ne,
+            "and" => And,
+            "or" => Or,
+            "not" => Not,
+            "dup" => Dup,
+            "drop" => Drop,
+            "swap" => Swap,
+            name if is_shuffle(name) => {
+                self.program.shuffles.push(parse_shuffle(op.signature));
+                Shuffle(self.program.shuffles.len() as u32 - 1)
+            }
+            _ => {
+                self.program.ops.push(op.clone());
+                Op(self.program.ops.len() as u32 - 1)
+            }
+        }
+    }
+
+    /// Compiles `body`, a function body taking `params` with its quotations
+    /// nested, onto the end of `code`. Returns `None` if it can't be compiled.
+    fn body(&mut self, params: &[Identifier], body: &[Atom], code: &mut Vec<Instr>) -> Option<()> {
+        use Atom::*;
+        let mut i = 0;
+        while i < body.len() {
+            match &body[i..] {
+                [Quotation(if_q), Quotation(else_q), Op(op), ..]
+                    if builtin_name(op) == Some("cond") =>
+                {
+                    let branch = code.len();
+                    code.push(Instr::JumpUnless(0));
+                    self.body(params, if_q, code)?;
+                    let jump = code.len();
+                    code.push(Instr::Jump(0));
+                    code[branch] = Instr::JumpUnless(code.len() as u32);
+                    self.body(params, else_q, code)?;
+                    code[jump] = Instr::Jump(code.len() as u32);
+                    i += 3;
+                    continue;
+                }
+                [Quotation(q), Call, ..] => {
+                    self.body(params, q, code)?;
+                    i += 2;
+                    continue;
+                }
+                _ => (),
+            }
+            let instr = match &body[i] {
+                Quotation(q) if captures(q, params) => return None,
+                atom @ Bool(_)
+                | atom @ Num(_)
+                | atom @ Str(_)
+                | atom @ Symbol(_)
+                | atom @ Quotation(_) => self.constant(atom.clone()),
+                Plain(ident) => match params.iter().rposition(|p| p == ident) {
+                    Some(slot) => Instr::Load(slot as u32),
+                    None => match (self.indices.get(ident), self.globals.get(ident)) {
+                        (Some(&f), _) => Instr::Call(f as u32),
+                        (None, Some(&slot)) => Instr::Global(slot as u32),
+                        (None, None) => self.eval(Plain(ident.clone())),
+                    },
+                },
+                Op(op) => self.op(op),
+                atom @ Call | atom @ Select(_) => self.eval(atom.clone()),
+                Concat(words) if !captures(words, params) => self.eval(Concat(words.clone())),
+                _ => return None,
+            };
+            code.push(instr);
+            i += 1;
+        }
+        Some(())
+    }
+
+    fn function(&mut self, params: &[Identifier], body: &[Atom]) -> Option<Vec<Instr>> {
+        let mut code = Vec::new();
+        self.body(params, &nest_quotations(body), &mut code)?;
+        code.push(Instr::Return);
+        Some(code)
+    }
+}
+
+/// The function defined by a line like `fn f a b = ...`.
+fn definition(atoms: &[Atom]) -> Option<(&Identifier, &Atom)> {
+    match atoms {
+        [Atom::Quotation(expr), Atom::Symbol(ident), Atom::DefVar] => match expr.as_slice() {
+            [f @ Atom::Function(..)] => Some((ident, f)),
+            _ => None,
+        },
+        _ => None,
+    }
+}
+
+pub fn compile_program(program: &str) -> Program {
+    let lines: Vec<Vec<Atom>> = program.split('\n').map(parse_line).collect();
+
+    // Names which are bound more than once may refer to different values in
+    // different places, so those are left to the evaluator to look up.
+    let mut definitions: HashMap<&Identifier, usize> = HashMap::new();
+    for atoms in &lines {
+        if let [.., Atom::Symbol(ident), Atom::DefVar] | [.., Atom::Symbol(ident), Atom::RedefVar] =
+            atoms.as_slice()
+        {
+            *definitions.entry(ident).or_insert(0) += 1;
+        }
+    }
+    let functions: Vec<(&Identifier, &Atom)> = lines
+        .iter()
+        .filter_map(|atoms| definition(atoms))
+        .filter(|(ident, _)| definitions[ident] == 1)
+        .collect();
+
+    let mut compiler = Compiler {
+        program: Program::default(),
+        indices: functions
+            .iter()
+            .enumerate()
+            .map(|(i, (ident, _))| ((*ident).clone(), i))
+            .collect(),
+        globals: HashMap::new(),
+    };
+    let functions: Vec<_> = functions
+        .into_iter()
+        .map(|(ident, f)| match f {
+            Atom::Function(params, body, declared) => (ident, params, body, *declared),
+            _ => unreachable!(),
+        })
+        .collect();
+
+    // Variables are scoped dynamically, so a function can use the parameters
+    // of whatever called it. Neither function can keep those in slots, since
+    // the evaluator has to find them in the environment.
+    let mut free = HashSet::new();
+    for (_, params, body, _) in &functions {
+        free_names(body, params, &mut free);
+    }
+    let bound: HashSet<&Identifier> = functions
+        .iter()
+        .flat_map(|(_, params, _, _)| *params)
+        .collect();
+    let dynamic = |params: &[Identifier], body: &[Atom]| {
+        let mut names = HashSet::new();
+        free_names(body, params, &mut names);
+        params.iter().any(|p| free.contains(p)) || names.iter().any(|n| bound.contains(n))
+    };
+
+    // Other variables can be read from slots if nothing else can shadow them.
+    let mut locals = HashSet::new();
+    for atoms in &lines {
+        local_definitions(atoms, &mut locals);
+    }
+    for atoms in &lines {
+        if let [.., Atom::Symbol(ident), Atom::DefVar] = atoms.as_slice() {
+            if definitions[ident] == 1
+                && !compiler.indices.contains_key(ident)
+                && !bound.contains(ident)
+                && !locals.contains(ident)
+            {
+                let slot = compiler.program.globals.len();
+                compiler.globals.insert(ident.clone(), slot);
+                compiler.program.globals.push(ident.clone());
+            }
+        }
+    }
+
+    for (ident, params, body, declared) in functions.iter().copied() {
+        let code = if dynamic(params, body) {
+            None
+        } else {
+            compiler.function(params, body)
+        };
+        let function = match code {
+            Some(code) => Function {
+                name: ident.clone(),
+                num_params: params.len(),
+                declared,
+                code,
+            },
+            // Calls the function as it's bound in the environment instead.
+            None => {
+                let call = compiler.eval(Atom::Plain(ident.clone()));
+                Function {
+                    name: ident.clone(),
+                    num_params: 0,
+                    declared: None,
+                    code: vec![call, Instr::Return],
+                }
+            }
+        };
+        compiler.program.functions.push(function);
+    }
+
+    for atoms in lines {
+        let is_definition = matches!(atoms.last(), Some(Atom::DefVar) | Some(Atom::RedefVar));
+        let code = if is_definition {
+            None
+        } else {
+            compiler.function(&[], &atoms)
+        };
+        compiler.program.lines.push(Line { atoms, code });
+    }
+    compiler.program
+}
+
+/// Runs `f` on `stack` as the stack of the last frame of `env`.
+fn with_env(stack: &mut Vec<Atom>, env: &mut Env, f: impl FnOnce(&mut Env)) {
+    env.last_frame().stack = std::mem::take(stack);
+    f(env);
+    *stack = std::mem::take(&mut env.last_frame().stack);
+}
+
+fn pop(stack: &mut Vec<Atom>) -> Atom {
+    match stack.pop() {
+        Some(atom) => atom,
+        None => panic!("Popped atom from empty frame"),
+    }
+}
+
+fn binop(stack: &mut Vec<Atom>, name: &str, f: impl FnOnce(i32, i32) -> Atom) {
+    let b = pop(stack);
+    let a = pop(stack);
+    match (a, b) {
+        (Atom::Num(a), Atom::Num(b)) => stack.push(f(a, b)),
+        (a, b) => panic!("Expected numbers for {} but got {:?} and {:?}.", name, a, b),
+    }
+}
+
+fn boolop(stack: &mut Vec<Atom>, name: &str, f: impl FnOnce(bool, bool) -> bool) {
+    let b = pop(stack);
+    let a = pop(stack);
+    match (a, b) {
+        (Atom::Bool(a), Atom::Bool(b)) => stack.push(Atom::Bool(f(a, b))),
+        (a, b) => panic!(
+            "Expected booleans for {} but got {:?} and {:?}.",
+            name, a, b
+        ),
+    }
+}
+
+/// Runs `code` on the stack of the last frame of `env`, with the values of the
+/// program's global slots which have been bound so far.
+pub fn run(program: &Program, code: &[Instr], globals: &[Option<Atom>], env: &mut Env) {
+    use Atom::{Bool, Num};
+    use Instr::*;
+
+    let mut stack = std::mem::take(&mut env.last_frame().stack);
+    let mut locals: Vec<Atom> = Vec::new();
+    let mut calls: Vec<(&[Instr], usize, usize)> = Vec::new();
+    let (mut code, mut pc, mut base) = (code, 0, 0);
+    loop {
+        let instr = code[pc];
+        pc += 1;
+        match instr {
+            Const(i) => stack.push(program.consts[i as usize].clone()),
+            Load(slot) => stack.push(locals[base + slot as usize].clone()),
+            Call(f) => {
+                let f = &program.functions[f as usize];
+                if let Some((num_in, _)) = f.declared {
+                    if stack.len() < num_in as usize {
+                        panic!(
+                            "{:#?} expected {} arguments but {} were given",
+                            f.name,
+                            num_in,
+                            stack.len()
+                        );
+                    }
+                }
+                if stack.len() < f.num_params {
+                    panic!(
+                        "Expected {} atoms but {} were given",
+                        f.num_params,
+                        stack.len()
+                    );
+                }
+                calls.push((code, pc, base));
+                base = locals.len();
+                locals.extend(stack.drain(stack.len() - f.num_params..));
+                code = &f.code;
+                pc = 0;
+            }
+            Return => {
+                locals.truncate(base);
+                match calls.pop() {
+                    Some((c, p, b)) => {
+                        code = c;
+                        pc = p;
+                        base = b;
+                    }
+                    None => break,
+                }
+            }
+            Jump(target) => pc = target as usize,
+            JumpUnless(target) => match pop(&mut stack) {
+                Bool(true) => (),
+                Bool(false) => pc = target as usize,
+                atom => panic!("Expected a boolean condition but got {:?}.", atom),
+            },
+            Add => binop(&mut stack, "+", |a, b| Num(a + b)),
+            Sub => binop(&mut stack, "-", |a, b| Num(a - b)),
+            Mul => binop(&mut stack, "*", |a, b| Num(a * b)),
+            Div => binop(&mut stack, "/", |a, b| Num(a / b)),
+            Rem => binop(&mut stack, "%", |a, b| Num(a % b)),
+            Lt => binop(&mut stack, "<", |a, b| Bool(a < b)),
+            Gt => binop(&mut stack, ">", |a, b| Bool(a > b)),
+            Le => binop(&mut stack, "<=", |a, b| Bool(a <= b)),
+            Ge => binop(&mut stack, ">=", |a, b| Bool(a >= b)),
+            Eq => binop(&mut stack, "==", |a, b| Bool(a == b)),
+            Ne => binop(&mut stack, "!=", |a, b| Bool(a != b)),
+            And => boolop(&mut stack, "and", |a, b| a && b),
+            Or => boolop(&mut stack, "or", |a, b| a || b),
+            Not => match pop(&mut stack) {
+                Bool(a) => stack.push(Bool(!a)),
+                atom => panic!("Expected a boolean for not but got {:?}.", atom),
+            },
+            Dup => {
+                let a = pop(&mut stack);
+                stack.push(a.clone());
+                stack.push(a);
+            }
+            Drop => {
+                pop(&mut stack);
+            }
+            Swap => {
+                let b = pop(&mut stack);
+                let a = pop(&mut stack);
+                stack.push(b);
+                stack.push(a);
+            }
+            Shuffle(i) => {
+                let (num_in, outputs) = &program.shuffles[i as usize];
+                if stack.len() < *num_in {
+                    panic!("Expected {} atoms but {} were given", num_in, stack.len());
+                }
+                let inputs = stack.split_off(stack.len() - num_in);
+                stack.extend(outputs.iter().map(|&i| inputs[i].clone()));
+            }
+            Op(i) => with_env(&mut stack, env, &*program.ops[i as usize].f),
+            Global(slot) => match &globals[slot as usize] {
+                Some(value) => stack.push(value.clone()),
+                None => {
+                    let atom = Atom::Plain(program.globals[slot as usize].clone());
+                    with_env(&mut stack, env, |env| eval_atom(atom, env));
+                }
+            },
+            Eval(i) => {
+                let atom = program.consts[i as usize].clone();
+                with_env(&mut stack, env, |env| eval_atom(atom, env));
+            }
+        }
+    }
+    env.last_frame().stack = stack;
+}
+
+/// Runs a compiled program in `env`. Lines which couldn't be compiled, or which
+/// are inside a quotation begun on an earlier line, are evaluated instead.
+pub fn run_program(program: &Program, env: &mut Env) {
+    let mut globals = vec![None; program.globals.len()];
+    for line in &program.lines {
+        match &line.code {
+            Some(code) if !env.lazy_mode() => run(program, code, &globals, env),
+            _ => {
+                for atom in line.atoms.iter().cloned() {
+                    eval_atom(atom, env);
+                }
+            }
+        }
+        // Functions and native words are left in the environment, so that
+        // calling them evaluates them like anything else which isn't compiled.
+        if let [.., Atom::Symbol(ident), Atom::DefVar] = line.atoms.as_slice() {
+            if let Some(slot) = program.globals.iter().position(|name| name == ident) {
+                globals[slot] = match env.find_var(ident) {
+                    Some(Atom::Function(..)) | Some(Atom::Op(_)) | None => None,
+                    value => value,
+                };
+            }
+        }
+    }
+}
+
+/// Like `eval::eval_program`, but compiles the program first.
+pub fn eval_program(program: &str) -> Env {
+    let compiled = compile_program(program);
+    let mut env = Env::new();
+    run_program(&compiled, &mut env);
+    env
+}
+
+#[test]
+fn test_compile_fib() {
+    use Instr::*;
+    let program = compile_program(
+        r"
+fn fib = dup 2 <= [ drop 1 ] [ 1 - dup 1 - fib swap fib + ] cond
+10 fib",
+    );
+    let fib = &program.functions[0];
+    let consts: Vec<&Atom> = program.consts.iter().collect();
+    let code: Vec<String> = fib
+        .code
+        .iter()
+        .map(|instr| match instr {
+            Const(i) => format!("{:?}", consts[*i as usize]),
+            instr => format!("{:?}", instr),
+        })
+        .collect();
+    assert_eq!(
+        vec![
+            "Dup",
+            "Num(2)",
+            "Le",
+            "JumpUnless(7)",
+            "Drop",
+            "Num(1)",
+            "Jump(16)",
+            "Num(1)",
+            "Sub",
+            "Dup",
+            "Num(1)",
+            "Sub",
+            "Call(0)",
+            "Swap",
+            "Call(0)",
+            "Add",
+            "Return",
+        ],
+        code
+    );
+    assert!(program.lines[1].code.is_none());
+    assert!(program.lines[2].code.is_some());
+}
+
+#[test]
+fn test_compile_fallbacks() {
+    let program = compile_program(
+        r"
+fn adder n = [ n + ]
+fn f = 1 'y let y
+fn g x = x 5 adder call
+fn mid = lo hi + 2 /
+fn avg lo hi = mid
+let a = 1
+a g
+",
+    );
+    let code = |name: &str| {
+        let f = program.functions.iter().find(|f| f.name == name).unwrap();
+        f.code.clone()
+    };
+    // Quotations which capture parameters, local definitions and parameters
+    // used by callees are left to the evaluator.
+    assert_eq!(2, code("adder").len());
+    assert_eq!(2, code("f").len());
+    assert_eq!(2, code("mid").len());
+    assert_eq!(2, code("avg").len());
+    assert_eq!(Instr::Load(0), code("g")[0]);
+    assert!(program.lines[7].code.is_some());
+
+    let mut env = eval_program(
+        r"
+fn adder n = [ n + ]
+fn g x = x 5 adder call
+let a = 1
+a g",
+    );
+    assert_eq!(vec![Atom::Num(6)], env.pop().unwrap().stack);
+}
+
+#[test]
+fn test_compile_globals() {
+    use Instr::*;
+    let source = r"
+let a = 1
+fn f x = x a +
+let b = 2
+fn g b = b
+let h = [ 3 ] 'h2 fn 'h2 get
+2 f b g h";
+    let program = compile_program(source);
+    assert_eq!(
+        vec![Identifier::from("a"), Identifier::from("h")],
+        program.globals
+    );
+    assert_eq!(
+        vec![Load(0), Global(0), Add, Return],
+        program.functions[0].code
+    );
+
+    // `b` is shadowed by the parameter of `g`, and `h` is bound to a function.
+    let mut env = eval_program(source);
+    assert_eq!(
+        vec![Atom::Num(3), Atom::Num(2), Atom::Num(3)],
+        env.pop().unwrap().stack
+    );
+}
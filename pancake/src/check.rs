@@ -0,0 +1,1469 @@
+//! A static checker which infers a row-polymorphic stack effect like
+//! `( ..a int int -- ..a int )` for every `fn` in a program, and reports type
+//! errors before the program is run.
+//!
+//! The stack is checked like a running program: words pop and push types, and
+//! popping below what is known refines the row variable standing for the rest
+//! of the stack. As in Factor, quotations which are literals are checked where
+//! they're called rather than given a type of their own, so combinators like
+//! `bi@` can use them at several stack depths.
+
+use crate::arity::{arity_atom_assuming, nest_quotations, StaticArity};
+use crate::ops::{builtin_names, get_op, parse_shuffle};
+use crate::parse::parse_line;
+use crate::types::{Arity, Atom, Op};
+use crate::vm::Identifier;
+use crate::vm2::Env;
+
+use std::collections::HashMap;
+use std::fmt;
+use std::rc::Rc;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Type {
+    Int,
+    Bool,
+    Str,
+    Sym,
+    List,
+    Record,
+    Quot(Box<StackType>, Box<StackType>),
+    Var(usize),
+}
+
+/// The type of a stack: a sequence of types on top of either the empty stack
+/// or a row variable standing for any number of values.
+#[derive(Debug, Clone, PartialEq)]
+pub enum StackType {
+    Empty,
+    Row(usize),
+    Push(Box<StackType>, Type),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Effect {
+    pub inputs: StackType,
+    pub outputs: StackType,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct CheckError {
+    pub line: usize,
+    pub function: Option<Identifier>,
+    pub word: String,
+    pub message: String,
+}
+
+impl fmt::Display for CheckError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "line {}", self.line)?;
+        if let Some(function) = &self.function {
+            write!(f, ", in fn {}", function)?;
+        }
+        if !self.word.is_empty() {
+            write!(f, ", at `{}`", self.word)?;
+        }
+        write!(f, ": {}", self.message)
+    }
+}
+
+type CheckResult<T> = Result<T, CheckError>;
+
+/// Builtins which are checked specially, mostly because they call quotations.
+pub const COMBINATORS: [&str; 24] = [
+    "cond",
+    "if",
+    "repeat",
+    "for_else",
+    "for_if",
+    "list",
+    "record",
+    "map",
+    "reduce_inner",
+    "splat",
+    "shuffle",
+    "get",
+    "keep",
+    "keep2",
+    "dip",
+    "2dip",
+    "bi",
+    "tri",
+    "cleave",
+    "bi*",
+    "tri*",
+    "bi@",
+    "spread",
+    "napply",
+];
+
+/// The stack effect of the builtin called `name`, as documented in the ops
+/// registry, if the builtin just pops and pushes values. Names other than the
+/// primitive types are type variables, so e.g. `a -- a a` duplicates any
+/// value.
+pub fn builtin_signature(name: &str) -> Option<&'static str> {
+    if COMBINATORS.contains(&name) {
+        return None;
+    }
+    get_op(name)
+        .map(|op| op.signature)
+        .filter(|signature| !signature.is_empty())
+}
+
+/// The builtins which just pop and push values, with their stack effects,
+/// sorted by name.
+pub fn builtin_signatures() -> Vec<(&'static str, &'static str)> {
+    builtin_names()
+        .into_iter()
+        .filter_map(|name| Some((name, builtin_signature(name)?)))
+        .collect()
+}
+
+/// The name of `op` if it has a signature or is in `COMBINATORS`.
+pub fn builtin_name(op: &Op) -> Option<&'static str> {
+    if COMBINATORS.contains(&op.name) || builtin_signature(op.name).is_some() {
+        Some(op.name)
+    } else {
+        None
+    }
+}
+
+/// A value on the stack being checked, along with the literal it came from if
+/// it's known statically. Literal quotations are called by checking their
+/// bodies, and their types are only worked out when they're needed.
+#[derive(Debug, Clone)]
+struct Entry {
+    ty: Type,
+    literal: Option<Atom>,
+}
+
+impl Entry {
+    fn new(ty: Type) -> Self {
+        Self { ty, literal: None }
+    }
+}
+
+/// A stack effect generalised over the listed type and row variables.
+#[derive(Debug, Clone)]
+struct Scheme {
+    types: Vec<usize>,
+    rows: Vec<usize>,
+    effect: Effect,
+}
+
+#[derive(Debug, Clone)]
+enum Binding {
+    /// A variable, which is pushed when used.
+    Value(Entry),
+    /// A function defined with `fn` inside a body, which is called when used.
+    Callable(Entry),
+    /// A top-level function.
+    Word(Scheme),
+    /// A recursive top-level function which is still being checked, with a
+    /// known arity. Its row variable is fresh at each call, so that it can
+    /// recurse at a different stack depth.
+    Recursive {
+        inputs: Vec<Type>,
+        outputs: Vec<Type>,
+    },
+}
+
+/// A top-level function definition.
+#[derive(Debug, Clone)]
+struct Definition {
+    params: Vec<Identifier>,
+    body: Vec<Atom>,
+    declared: Arity,
+}
+
+pub struct Checker {
+    types: Vec<Option<Type>>,
+    rows: Vec<Option<StackType>>,
+    /// The stack being checked, as a stack type with entries on top of it.
+    base: StackType,
+    items: Vec<Entry>,
+    scopes: Vec<HashMap<Identifier, Binding>>,
+    /// Top-level functions which haven't been checked yet. Function bodies may
+    /// call these before they're defined.
+    pending: HashMap<Identifier, Definition>,
+    in_progress: Vec<Identifier>,
+    /// The top-level functions, used to infer the arities of recursive ones.
+    env: Env,
+    /// The top-level variables, which arity inference takes to push a value.
+    values: Vec<Identifier>,
+    line: usize,
+    function: Option<Identifier>,
+    word: String,
+    effects: Vec<(Identifier, String)>,
+}
+
+impl Default for Checker {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Checker {
+    pub fn new() -> Self {
+        Self {
+            types: Vec::new(),
+            rows: Vec::new(),
+            base: StackType::Empty,
+            items: Vec::new(),
+            scopes: vec![HashMap::new()],
+            pending: HashMap::new(),
+            in_progress: Vec::new(),
+            env: Env::new(),
+            values: Vec::new(),
+            line: 0,
+            function: None,
+            word: String::new(),
+            effects: Vec::new(),
+        }
+    }
+
+    fn fail<T>(&self, message: String) -> CheckResult<T> {
+        Err(CheckError {
+            line: self.line,
+            function: self.function.clone(),
+            word: self.word.clone(),
+            message,
+        })
+    }
+
+    fn fresh_type(&mut self) -> Type {
+        self.types.push(None);
+        Type::Var(self.types.len() - 1)
+    }
+
+    fn fresh_row(&mut self) -> StackType {
+        self.rows.push(None);
+        StackType::Row(self.rows.len() - 1)
+    }
+
+    fn shallow_type(&self, mut ty: Type) -> Type {
+        while let Type::Var(v) = ty {
+            match &self.types[v] {
+                Some(bound) => ty = bound.clone(),
+                None => break,
+            }
+        }
+        ty
+    }
+
+    fn shallow_stack(&self, mut stack: StackType) -> StackType {
+        while let StackType::Row(r) = stack {
+            match &self.rows[r] {
+                Some(bound) => stack = bound.clone(),
+                None => break,
+            }
+        }
+        stack
+    }
+
+    /// Resolves all the variables in `ty` which have been bound.
+    fn zonk_type(&self, ty: &Type) -> Type {
+        match self.shallow_type(ty.clone()) {
+            Type::Quot(inputs, outputs) => Type::Quot(
+                Box::new(self.zonk_stack(&inputs)),
+                Box::new(self.zonk_stack(&outputs)),
+            ),
+            ty => ty,
+        }
+    }
+
+    fn zonk_stack(&self, stack: &StackType) -> StackType {
+        match self.shallow_stack(stack.clone()) {
+            StackType::Push(rest, ty) => {
+                StackType::Push(Box::new(self.zonk_stack(&rest)), self.zonk_type(&ty))
+            }
+            stack => stack,
+        }
+    }
+
+    fn zonk_effect(&self, effect: &Effect) -> Effect {
+        Effect {
+            inputs: self.zonk_stack(&effect.inputs),
+            outputs: self.zonk_stack(&effect.outputs),
+        }
+    }
+
+    fn show_type(&self, ty: &Type) -> String {
+        Names::default().type_(&self.zonk_type(ty))
+    }
+
+    fn show_stack(&self, stack: &StackType) -> String {
+        format!("( {} )", Names::default().stack(&self.zonk_stack(stack)))
+    }
+
+    pub fn show_effect(&self, effect: &Effect) -> String {
+        Names::default().effect(&self.zonk_effect(effect))
+    }
+
+    fn unify_types(&mut self, actual: &Type, expected: &Type) -> CheckResult<()> {
+        let actual = self.shallow_type(actual.clone());
+        let expected = self.shallow_type(expected.clone());
+        match (&actual, &expected) {
+            (Type::Var(a), Type::Var(b)) if a == b => Ok(()),
+            (Type::Var(v), ty) | (ty, Type::Var(v)) => {
+                if free_vars_type(&self.zonk_type(ty)).0.contains(v) {
+                    return self.fail(format!(
+                        "{} can't contain itself",
+                        self.show_type(&Type::Var(*v))
+                    ));
+                }
+                self.types[*v] = Some(ty.clone());
+                Ok(())
+            }
+            (Type::Quot(in0, out0), Type::Quot(in1, out1)) => {
+                self.unify_stacks(in0, in1)?;
+                self.unify_stacks(out0, out1)
+            }
+            (a, b) if a == b => Ok(()),
+            _ => self.fail(format!(
+                "expected {} but found {}",
+                self.show_type(&expected),
+                self.show_type(&actual)
+            )),
+        }
+    }
+
+    fn unify_stacks(&mut self, actual: &StackType, expected: &StackType) -> CheckResult<()> {
+        let actual = self.shallow_stack(actual.clone());
+        let expected = self.shallow_stack(expected.clone());
+        match (&actual, &expected) {
+            (StackType::Row(a), StackType::Row(b)) if a == b => Ok(()),
+            (StackType::Row(r), stack) | (stack, StackType::Row(r)) => {
+                if free_vars_stack(&self.zonk_stack(stack)).1.contains(r) {
+                    return self.fail(format!(
+                        "the stack {} can't contain itself",
+                        self.show_stack(&StackType::Row(*r))
+                    ));
+                }
+                self.rows[*r] = Some(stack.clone());
+                Ok(())
+            }
+            (StackType::Empty, StackType::Empty) => Ok(()),
+            (StackType::Push(rest0, ty0), StackType::Push(rest1, ty1)) => {
+                self.unify_types(ty0, ty1)?;
+                self.unify_stacks(rest0, rest1)
+            }
+            _ => self.fail(format!(
+                "expected the stack {} but found {}",
+                self.show_stack(&expected),
+                self.show_stack(&actual)
+            )),
+        }
+    }
+
+    fn push(&mut self, entry: Entry) {
+        self.items.push(entry);
+    }
+
+    fn pop(&mut self) -> CheckResult<Entry> {
+        if let Some(entry) = self.items.pop() {
+            return Ok(entry);
+        }
+        match self.shallow_stack(self.base.clone()) {
+            StackType::Push(rest, ty) => {
+                self.base = *rest;
+                Ok(Entry::new(ty))
+            }
+            StackType::Row(r) => {
+                let ty = self.fresh_type();
+                let rest = self.fresh_row();
+                self.rows[r] = Some(StackType::Push(Box::new(rest.clone()), ty.clone()));
+                self.base = rest;
+                Ok(Entry::new(ty))
+            }
+            StackType::Empty => self.fail("stack underflow".into()),
+        }
+    }
+
+    /// Pops `n` entries, keeping them in stack order.
+    fn pop_n(&mut self, n: usize) -> CheckResult<Vec<Entry>> {
+        let mut entries = (0..n)
+            .map(|_| self.pop())
+            .collect::<CheckResult<Vec<_>>>()?;
+        entries.reverse();
+        Ok(entries)
+    }
+
+    fn pop_typed(&mut self, expected: Type) -> CheckResult<Entry> {
+        let entry = self.pop()?;
+        let ty = self.entry_type(&entry)?;
+        self.unify_types(&ty, &expected)?;
+        Ok(entry)
+    }
+
+    /// Pops a literal, as taken by combinators like `napply` and `shuffle`.
+    fn pop_literal(&mut self, what: &str) -> CheckResult<Atom> {
+        match self.pop()?.literal {
+            Some(literal) => Ok(literal),
+            None => self.fail(format!("expected a literal {}", what)),
+        }
+    }
+
+    fn entry_type(&mut self, entry: &Entry) -> CheckResult<Type> {
+        if let (Some(literal @ Atom::Quotation(_)), Type::Var(_))
+        | (Some(literal @ Atom::Function(..)), Type::Var(_)) =
+            (&entry.literal, self.shallow_type(entry.ty.clone()))
+        {
+            let ty = self.quotation_type(literal)?;
+            self.unify_types(&entry.ty, &ty)?;
+        }
+        Ok(entry.ty.clone())
+    }
+
+    /// Turns the entries on top of the stack into a stack type.
+    fn flush(&mut self) -> CheckResult<StackType> {
+        for entry in std::mem::take(&mut self.items) {
+            let ty = self.entry_type(&entry)?;
+            self.base = StackType::Push(Box::new(self.base.clone()), ty);
+        }
+        Ok(self.base.clone())
+    }
+
+    fn save(&mut self) -> (StackType, Vec<Entry>) {
+        (self.base.clone(), self.items.clone())
+    }
+
+    fn restore(&mut self, (base, items): (StackType, Vec<Entry>)) {
+        self.base = base;
+        self.items = items;
+    }
+
+    /// The type of a literal quotation, checked on a stack of unknown values.
+    fn quotation_type(&mut self, literal: &Atom) -> CheckResult<Type> {
+        let saved = self.save();
+        let inputs = self.fresh_row();
+        self.restore((inputs.clone(), Vec::new()));
+        self.inline_literal(literal)?;
+        let outputs = self.flush()?;
+        self.restore(saved);
+        Ok(Type::Quot(Box::new(inputs), Box::new(outputs)))
+    }
+
+    fn apply(&mut self, effect: Effect) -> CheckResult<()> {
+        let stack = self.flush()?;
+        self.unify_stacks(&stack, &effect.inputs)?;
+        self.base = effect.outputs;
+        Ok(())
+    }
+
+    fn inline_literal(&mut self, literal: &Atom) -> CheckResult<()> {
+        match literal {
+            Atom::Quotation(body) => self.inline(&[], body),
+            Atom::Function(params, body, _) => self.inline(params, &nest_quotations(body)),
+            _ => unreachable!(),
+        }
+    }
+
+    fn call(&mut self, callee: Entry) -> CheckResult<()> {
+        match &callee.literal {
+            Some(literal @ Atom::Quotation(_)) | Some(literal @ Atom::Function(..)) => {
+                self.inline_literal(literal)
+            }
+            _ => {
+                let inputs = self.flush()?;
+                let outputs = self.fresh_row();
+                let ty = Type::Quot(Box::new(inputs), Box::new(outputs.clone()));
+                self.unify_types(&callee.ty, &ty)?;
+                self.base = outputs;
+                Ok(())
+            }
+        }
+    }
+
+    fn inline(&mut self, params: &[Identifier], body: &[Atom]) -> CheckResult<()> {
+        let mut scope = HashMap::new();
+        for param in params.iter().rev() {
+            scope.insert(param.clone(), Binding::Value(self.pop()?));
+        }
+        self.scopes.push(scope);
+        let result = self.check_atoms(body);
+        self.scopes.pop();
+        result
+    }
+
+    /// Checks each of `branches` called on the current stack, of which one
+    /// will run, so they must all leave the same stack.
+    fn branches(&mut self, branches: Vec<Entry>) -> CheckResult<()> {
+        let saved = self.save();
+        let mut result: Option<StackType> = None;
+        for branch in branches {
+            self.restore(saved.clone());
+            self.call(branch)?;
+            let outputs = self.flush()?;
+            match &result {
+                Some(stack) => self.unify_stacks(&outputs, &stack.clone())?,
+                None => result = Some(outputs),
+            }
+        }
+        self.restore((result.unwrap(), Vec::new()));
+        Ok(())
+    }
+
+    /// Checks `callee` on a new stack holding `items`, as `list` and `map` do.
+    fn call_in_new_frame(&mut self, callee: Entry, items: Vec<Entry>) -> CheckResult<Entry> {
+        let saved = self.save();
+        self.restore((StackType::Empty, items));
+        self.call(callee)?;
+        let result =
+            if self.items.is_empty() && self.shallow_stack(self.base.clone()) == StackType::Empty {
+                Entry::new(Type::List)
+            } else {
+                self.pop()?
+            };
+        self.restore(saved);
+        Ok(result)
+    }
+
+    fn lookup(&self, ident: &Identifier) -> Option<Binding> {
+        self.scopes
+            .iter()
+            .rev()
+            .find_map(|scope| scope.get(ident).cloned())
+    }
+
+    fn bind(&mut self, ident: &Identifier, binding: Binding) {
+        self.scopes
+            .last_mut()
+            .unwrap()
+            .insert(ident.clone(), binding);
+    }
+
+    fn instantiate(&mut self, scheme: &Scheme) -> Effect {
+        let mut types = HashMap::new();
+        for &v in &scheme.types {
+            types.insert(v, self.fresh_type());
+        }
+        let mut rows = HashMap::new();
+        for &r in &scheme.rows {
+            rows.insert(r, self.fresh_row());
+        }
+        Effect {
+            inputs: substitute_stack(&scheme.effect.inputs, &types, &rows),
+            outputs: substitute_stack(&scheme.effect.outputs, &types, &rows),
+        }
+    }
+
+    /// Generalises `effect` over the variables which don't belong to any
+    /// function still being checked.
+    fn generalize(&self, effect: &Effect) -> Scheme {
+        let effect = self.zonk_effect(effect);
+        let (mut types, mut rows) = free_vars_effect(&effect);
+        for ident in &self.in_progress {
+            let (in_types, in_rows) = match self.scopes[0].get(ident) {
+                Some(Binding::Recursive { inputs, outputs }) => {
+                    let stack = inputs
+                        .iter()
+                        .chain(outputs)
+                        .fold(StackType::Empty, |stack, ty| {
+                            StackType::Push(Box::new(stack), ty.clone())
+                        });
+                    free_vars_stack(&self.zonk_stack(&stack))
+                }
+                Some(Binding::Word(scheme)) => free_vars_effect(&self.zonk_effect(&scheme.effect)),
+                _ => (Vec::new(), Vec::new()),
+            };
+            types.retain(|v| !in_types.contains(v));
+            rows.retain(|r| !in_rows.contains(r));
+        }
+        Scheme {
+            types,
+            rows,
+            effect,
+        }
+    }
+
+    fn signature(&mut self, signature: &str) -> CheckResult<()> {
+        let mut sides = signature.split("--");
+        let inputs: Vec<&str> = sides.next().unwrap().split_whitespace().collect();
+        let outputs: Vec<&str> = sides.next().unwrap().split_whitespace().collect();
+
+        let mut vars: HashMap<&str, Entry> = HashMap::new();
+        for (name, entry) in inputs.iter().zip(self.pop_n(inputs.len())?) {
+            match primitive(name) {
+                Some(ty) => {
+                    let actual = self.entry_type(&entry)?;
+                    self.unify_types(&actual, &ty)?;
+                }
+                None => {
+                    vars.insert(name, entry);
+                }
+            }
+        }
+        for name in outputs {
+            let entry = match primitive(name) {
+                Some(ty) => Entry::new(ty),
+                None => match vars.get(name) {
+                    Some(entry) => entry.clone(),
+                    None => Entry::new(self.fresh_type()),
+                },
+            };
+            self.push(entry);
+        }
+        Ok(())
+    }
+
+    /// The quotations in a literal quotation of quotations, as taken by
+    /// `cleave` and `spread`.
+    fn quotations(&mut self) -> CheckResult<Vec<Entry>> {
+        match self.pop_literal("quotation of quotations")? {
+            Atom::Quotation(quots) => Ok(quots.iter().map(|q| self.literal(q.clone())).collect()),
+            _ => self.fail("expected a literal quotation of quotations".into()),
+        }
+    }
+
+    fn spread(&mut self, objects: Vec<Entry>, quots: Vec<Entry>) -> CheckResult<()> {
+        for (object, q) in objects.into_iter().zip(quots) {
+            self.push(object);
+            self.call(q)?;
+        }
+        Ok(())
+    }
+
+    fn combinator(&mut self, name: &str) -> CheckResult<()> {
+        match name {
+            "cond" => {
+                let else_q = self.pop()?;
+                let if_q = self.pop()?;
+                self.pop_typed(Type::Bool)?;
+                self.branches(vec![if_q, else_q])
+            }
+            "if" | "for_else" | "for_if" => {
+                let body = self.pop()?;
+                if name == "if" {
+                    self.pop_typed(Type::Bool)?;
+                }
+                let skip = self.literal(Atom::Quotation(Rc::new(Vec::new())));
+                self.branches(vec![body, skip])
+            }
+            "repeat" => {
+                self.pop_typed(Type::Int)?;
+                let body = self.pop()?;
+                let skip = self.literal(Atom::Quotation(Rc::new(Vec::new())));
+                self.branches(vec![body, skip])
+            }
+            "list" => {
+                let q = self.pop()?;
+                self.call_in_new_frame(q, Vec::new())?;
+                self.push(Entry::new(Type::List));
+                Ok(())
+            }
+            "record" => {
+                // The types of the fields are only checked by `typeck`.
+                let q = self.pop()?;
+                self.call_in_new_frame(q, Vec::new())?;
+                self.push(Entry::new(Type::Record));
+                Ok(())
+            }
+            "map" | "reduce_inner" => {
+                let q = self.pop()?;
+                self.pop_typed(Type::List)?;
+                let num_items = if name == "map" { 1 } else { 2 };
+                let items = (0..num_items)
+                    .map(|_| Entry::new(self.fresh_type()))
+                    .collect();
+                self.call_in_new_frame(q, items)?;
+                self.push(Entry::new(Type::List));
+                Ok(())
+            }
+            "splat" => {
+                // The values in a list aren't known statically.
+                self.pop_typed(Type::List)?;
+                self.flush()?;
+                self.base = self.fresh_row();
+                Ok(())
+            }
+            "shuffle" => match self.pop_literal("shuffle spec")? {
+                Atom::Str(spec) => {
+                    let (num_in, outputs) = parse_shuffle(&spec);
+                    let inputs = self.pop_n(num_in)?;
+                    for i in outputs {
+                        self.push(inputs[i].clone());
+                    }
+                    Ok(())
+                }
+                _ => self.fail("expected a literal shuffle spec".into()),
+            },
+            "get" => match self.pop_literal("symbol")? {
+                Atom::Symbol(ident) => {
+                    let entry = match self.lookup(&ident) {
+                        Some(Binding::Value(entry)) | Some(Binding::Callable(entry)) => entry,
+                        Some(Binding::Word(scheme)) => {
+                            let effect = self.instantiate(&scheme);
+                            Entry::new(Type::Quot(
+                                Box::new(effect.inputs),
+                                Box::new(effect.outputs),
+                            ))
+                        }
+                        Some(Binding::Recursive { inputs, outputs }) => {
+                            let row = self.fresh_row();
+                            Entry::new(Type::Quot(
+                                Box::new(push_all(row.clone(), inputs)),
+                                Box::new(push_all(row, outputs)),
+                            ))
+                        }
+                        None => return self.fail(format!("unknown word {}", ident)),
+                    };
+                    self.push(entry);
+                    Ok(())
+                }
+                _ => self.fail("expected a literal symbol".into()),
+            },
+            "keep" => {
+                let q = self.pop()?;
+                let ty = self.entry_type(&q)?;
+                let num_in = match self.shallow_type(ty) {
+                    Type::Quot(inputs, _) => self.depth(&inputs),
+                    _ => None,
+                };
+                let num_in = match num_in {
+                    Some(num_in) => num_in,
+                    None => return self.fail("keep needs a quotation of known arity".into()),
+                };
+                let kept = self.pop_n(num_in)?;
+                let result = self.call_in_new_frame(q, kept.clone())?;
+                self.items.extend(kept);
+                self.push(result);
+                Ok(())
+            }
+            "keep2" => {
+                let q = self.pop()?;
+                let kept = self.pop_n(2)?;
+                self.items.extend(kept.clone());
+                self.call(q)?;
+                self.items.extend(kept);
+                Ok(())
+            }
+            "dip" | "2dip" => {
+                let q = self.pop()?;
+                let hidden = self.pop_n(if name == "dip" { 1 } else { 2 })?;
+                self.call(q)?;
+                self.items.extend(hidden);
+                Ok(())
+            }
+            "bi" | "tri" => {
+                let quots = self.pop_n(if name == "bi" { 2 } else { 3 })?;
+                let x = self.pop()?;
+                self.spread(vec![x; quots.len()], quots)
+            }
+            "cleave" => {
+                let quots = self.quotations()?;
+                let x = self.pop()?;
+                self.spread(vec![x; quots.len()], quots)
+            }
+            "bi*" | "tri*" => {
+                let quots = self.pop_n(if name == "bi*" { 2 } else { 3 })?;
+                let objects = self.pop_n(quots.len())?;
+                self.spread(objects, quots)
+            }
+            "bi@" => {
+                let q = self.pop()?;
+                let objects = self.pop_n(2)?;
+                self.spread(objects, vec![q; 2])
+            }
+            "spread" => {
+                let quots = self.quotations()?;
+                let objects = self.pop_n(quots.len())?;
+                self.spread(objects, quots)
+            }
+            "napply" => match self.pop_literal("count")? {
+                Atom::Num(n) if n >= 0 => {
+                    let q = self.pop()?;
+                    let objects = self.pop_n(n as usize)?;
+                    self.spread(objects, vec![q; n as usize])
+                }
+                _ => self.fail("expected a literal count".into()),
+            },
+            _ => self.fail(format!("no type is known for {}", name)),
+        }
+    }
+
+    fn literal(&mut self, literal: Atom) -> Entry {
+        let ty = match &literal {
+            Atom::Bool(_) => Type::Bool,
+            Atom::Num(_) => Type::Int,
+            Atom::Str(_) => Type::Str,
+            Atom::Symbol(_) => Type::Sym,
+            Atom::List(_) => Type::List,
+            Atom::Record(_) => Type::Record,
+            _ => self.fresh_type(),
+        };
+        Entry {
+            ty,
+            literal: Some(literal),
+        }
+    }
+
+    /// The number of values on top of the row variable at the bottom of
+    /// `stack`, if it has one.
+    fn depth(&self, stack: &StackType) -> Option<usize> {
+        match self.shallow_stack(stack.clone()) {
+            StackType::Push(rest, _) => Some(self.depth(&rest)? + 1),
+            StackType::Row(_) => Some(0),
+            StackType::Empty => None,
+        }
+    }
+
+    /// The effect of `word` by itself, which must take and leave a fixed
+    /// number of values for it to be concatenated.
+    fn word_effect(&mut self, word: &Atom) -> CheckResult<(Vec<Type>, Vec<Type>)> {
+        let saved = self.save();
+        let row = self.fresh_row();
+        self.restore((row.clone(), Vec::new()));
+        match word {
+            Atom::Function(..) => {
+                let callee = self.literal(word.clone());
+                self.call(callee)?;
+            }
+            word => self.check_atom(word)?,
+        }
+        let outputs = self.flush()?;
+        self.restore(saved);
+
+        let (inputs, input_base) = self.split_stack(&row);
+        let (outputs, output_base) = self.split_stack(&outputs);
+        if input_base != output_base {
+            return self.fail(format!(
+                "can't concatenate {}, which doesn't take and leave a fixed number of values",
+                describe(word)
+            ));
+        }
+        Ok((inputs, outputs))
+    }
+
+    /// Splits a stack type into the types on top of its base, bottom first.
+    fn split_stack(&self, stack: &StackType) -> (Vec<Type>, StackType) {
+        match self.shallow_stack(stack.clone()) {
+            StackType::Push(rest, ty) => {
+                let (mut types, base) = self.split_stack(&rest);
+                types.push(ty);
+                (types, base)
+            }
+            base => (Vec::new(), base),
+        }
+    }
+
+    fn concat(&mut self, words: &[Atom]) -> CheckResult<()> {
+        let effects = words
+            .iter()
+            .map(|word| self.word_effect(word))
+            .collect::<CheckResult<Vec<_>>>()?;
+        for (inputs, _) in effects.iter().rev() {
+            for ty in inputs.iter().rev() {
+                self.pop_typed(ty.clone())?;
+            }
+        }
+        for (_, outputs) in effects {
+            for ty in outputs {
+                self.push(Entry::new(ty));
+            }
+        }
+        Ok(())
+    }
+
+    fn word(&mut self, ident: &Identifier) -> CheckResult<()> {
+        if self.lookup(ident).is_none() && self.function.is_some() {
+            if let Some(definition) = self.pending.get(ident).cloned() {
+                self.check_definition(ident, definition)?;
+            }
+        }
+        match self.lookup(ident) {
+            Some(Binding::Value(entry)) => {
+                self.push(entry);
+                Ok(())
+            }
+            Some(Binding::Callable(entry)) => self.call(entry),
+            Some(Binding::Word(scheme)) => {
+                let effect = self.instantiate(&scheme);
+                self.apply(effect)
+            }
+            Some(Binding::Recursive { inputs, outputs }) => {
+                for ty in inputs.into_iter().rev() {
+                    self.pop_typed(ty)?;
+                }
+                for ty in outputs {
+                    self.push(Entry::new(ty));
+                }
+                Ok(())
+            }
+            None => self.fail(format!("unknown word {}", ident)),
+        }
+    }
+
+    fn check_atom(&mut self, atom: &Atom) -> CheckResult<()> {
+        use Atom::*;
+        let word = std::mem::replace(&mut self.word, describe(atom));
+        let result = match atom {
+            Bool(_) | Num(_) | Str(_) | Symbol(_) | List(_) | Record(_) | Quotation(_)
+            | Function(..) => {
+                let entry = self.literal(atom.clone());
+                self.push(entry);
+                Ok(())
+            }
+            // Field names are identifiers, so a selection like `.0` must index
+            // a list.
+            Select(field) => {
+                let indexed = if field.chars().all(|c| c.is_ascii_digit()) {
+                    Type::List
+                } else {
+                    Type::Record
+                };
+                self.pop_typed(indexed)?;
+                let ty = self.fresh_type();
+                self.push(Entry::new(ty));
+                Ok(())
+            }
+            Op(op) => match builtin_name(op) {
+                Some(name) => match builtin_signature(name) {
+                    Some(signature) => self.signature(signature),
+                    None => self.combinator(name),
+                },
+                None => self.fail("no type is known for this builtin".into()),
+            },
+            Plain(ident) => self.word(ident),
+            Call => {
+                let callee = self.pop()?;
+                self.call(callee)
+            }
+            Concat(words) => self.concat(words),
+            DefVarLiteral | DefFnLiteral => match self.pop_literal("symbol")? {
+                Symbol(ident) => {
+                    let value = self.pop()?;
+                    let binding = if *atom == DefVarLiteral {
+                        Binding::Value(value)
+                    } else {
+                        Binding::Callable(value)
+                    };
+                    self.bind(&ident, binding);
+                    Ok(())
+                }
+                _ => self.fail("expected a literal symbol".into()),
+            },
+            DefVar | RedefVar => self.fail("definitions must be on their own line".into()),
+            QuotationStart | QuotationEnd => self.fail("unbalanced brackets".into()),
+        };
+        if result.is_ok() {
+            self.word = word;
+        }
+        result
+    }
+
+    fn check_atoms(&mut self, atoms: &[Atom]) -> CheckResult<()> {
+        for atom in atoms {
+            self.check_atom(atom)?;
+        }
+        Ok(())
+    }
+
+    /// Checks a top-level function, generalising its stack effect.
+    fn check_definition(&mut self, ident: &Identifier, definition: Definition) -> CheckResult<()> {
+        self.pending.remove(ident);
+
+        let saved = self.save();
+        let locals = self.scopes.split_off(1);
+        let function = self.function.replace(ident.clone());
+        let word = std::mem::take(&mut self.word);
+
+        // Recursive calls may be at a different stack depth, which can only
+        // be allowed for if the arity of the function is known. The inferred
+        // arity ignores types, so if it doesn't fit, check again without it.
+        let snapshot = (
+            self.types.len(),
+            self.rows.len(),
+            self.scopes[0].clone(),
+            self.pending.clone(),
+            self.effects.len(),
+        );
+        let arity = match definition.declared {
+            Some((num_in, num_out)) => StaticArity::Fixed(num_in, num_out),
+            None => {
+                let values = self
+                    .values
+                    .iter()
+                    .map(|value| (value.clone(), StaticArity::Fixed(0, 1)));
+                arity_atom_assuming(&Atom::Plain(ident.clone()), &self.env, values.collect())
+            }
+        };
+        let effect = match self.infer_definition(ident, &definition, arity.fixed()) {
+            Err(_) if arity.fixed().is_some() => {
+                let (types, rows, globals, pending, effects) = snapshot;
+                self.types.truncate(types);
+                self.rows.truncate(rows);
+                self.scopes[0] = globals;
+                self.pending = pending;
+                self.effects.truncate(effects);
+                self.infer_definition(ident, &definition, None)?
+            }
+            result => result?,
+        };
+
+        let scheme = self.generalize(&effect);
+        if let Some((num_in, num_out)) = definition.declared {
+            let (inputs, input_base) = self.split_stack(&scheme.effect.inputs);
+            let (outputs, output_base) = self.split_stack(&scheme.effect.outputs);
+            if input_base != output_base
+                || (inputs.len(), outputs.len()) != (num_in as usize, num_out as usize)
+            {
+                return self.fail(format!(
+                    "declared to take {} and leave {} values, but its effect is {}",
+                    num_in,
+                    num_out,
+                    Names::default().effect(&scheme.effect)
+                ));
+            }
+        }
+        self.effects
+            .push((ident.clone(), Names::default().effect(&scheme.effect)));
+        self.scopes[0].insert(ident.clone(), Binding::Word(scheme));
+
+        self.scopes.extend(locals);
+        self.function = function;
+        self.word = word;
+        self.restore(saved);
+        Ok(())
+    }
+
+    /// Infers the stack effect of a top-level function, assuming `arity` for
+    /// recursive calls if it's given.
+    fn infer_definition(
+        &mut self,
+        ident: &Identifier,
+        definition: &Definition,
+        arity: Arity,
+    ) -> CheckResult<Effect> {
+        let assumption = match arity {
+            Some((num_in, num_out)) => Binding::Recursive {
+                inputs: (0..num_in).map(|_| self.fresh_type()).collect(),
+                outputs: (0..num_out).map(|_| self.fresh_type()).collect(),
+            },
+            None => Binding::Word(Scheme {
+                types: Vec::new(),
+                rows: Vec::new(),
+                effect: Effect {
+                    inputs: self.fresh_row(),
+                    outputs: self.fresh_row(),
+                },
+            }),
+        };
+        self.scopes[0].insert(ident.clone(), assumption.clone());
+        self.in_progress.push(ident.clone());
+
+        let row = self.fresh_row();
+        let params: Vec<Type> = definition
+            .params
+            .iter()
+            .map(|_| self.fresh_type())
+            .collect();
+        let inputs = push_all(row.clone(), params.clone());
+        self.restore((row, Vec::new()));
+        let scope = definition
+            .params
+            .iter()
+            .cloned()
+            .zip(params.into_iter().map(|ty| Binding::Value(Entry::new(ty))))
+            .collect();
+        self.scopes.push(scope);
+
+        let result = self.check_atoms(&definition.body).and_then(|_| {
+            let effect = Effect {
+                inputs,
+                outputs: self.flush()?,
+            };
+            self.word = String::new();
+            match assumption {
+                Binding::Recursive { inputs, outputs } => {
+                    let row = self.fresh_row();
+                    self.unify_stacks(&effect.inputs, &push_all(row.clone(), inputs))?;
+                    self.unify_stacks(&effect.outputs, &push_all(row, outputs))?;
+                }
+                Binding::Word(scheme) => {
+                    self.unify_stacks(&effect.inputs, &scheme.effect.inputs)?;
+                    self.unify_stacks(&effect.outputs, &scheme.effect.outputs)?;
+                }
+                _ => unreachable!(),
+            }
+            Ok(effect)
+        });
+
+        self.scopes.truncate(1);
+        self.in_progress.pop();
+        result
+    }
+
+    /// Checks a `let` definition, which evaluates its expression on a new
+    /// stack and binds the value left on top.
+    fn check_let(&mut self, ident: &Identifier, expr: &[Atom]) -> CheckResult<()> {
+        let saved = self.save();
+        self.restore((StackType::Empty, Vec::new()));
+        self.scopes.push(HashMap::new());
+        let result = self.check_atoms(expr);
+        self.scopes.pop();
+        result?;
+        if self.items.is_empty() && self.shallow_stack(self.base.clone()) == StackType::Empty {
+            return self.fail("expected a result but the stack was empty".into());
+        }
+        let value = self.pop()?;
+        self.restore(saved);
+        // Literals are checked wherever they're used, but other values have a
+        // single type, which is generalised so that e.g. a quotation can be
+        // called at any stack depth.
+        let binding = match value.literal {
+            Some(_) => Binding::Value(value),
+            None => {
+                let row = self.fresh_row();
+                let effect = Effect {
+                    inputs: row.clone(),
+                    outputs: push_all(row, vec![value.ty]),
+                };
+                Binding::Word(self.generalize(&effect))
+            }
+        };
+        self.bind(ident, binding);
+        if !self.values.contains(ident) {
+            self.values.push(ident.clone());
+        }
+        Ok(())
+    }
+
+    fn check_line(&mut self, atoms: Vec<Atom>) -> CheckResult<()> {
+        use Atom::*;
+        match atoms.as_slice() {
+            [Quotation(expr), Symbol(ident), def @ DefVar]
+            | [Quotation(expr), Symbol(ident), def @ RedefVar] => {
+                match expr.as_slice() {
+                    [Function(params, body, declared)] => {
+                        let definition = Definition {
+                            params: params.clone(),
+                            body: nest_quotations(body),
+                            declared: *declared,
+                        };
+                        if *def == RedefVar {
+                            self.values.retain(|value| value != ident);
+                            let f = Function(params.clone(), body.clone(), *declared);
+                            self.env.redefine_var(ident, f);
+                            self.check_definition(ident, definition)
+                        } else if self.pending.contains_key(ident) {
+                            self.check_definition(ident, definition)
+                        } else {
+                            // Already checked, since an earlier function
+                            // called it.
+                            Ok(())
+                        }
+                    }
+                    expr => self.check_let(ident, &nest_quotations(expr)),
+                }
+            }
+            atoms => self.check_atoms(&nest_quotations(atoms)),
+        }
+    }
+
+    /// Checks a whole program, returning the stack effect of each `fn` in the
+    /// order they were checked.
+    pub fn check_program(mut self, program: &str) -> CheckResult<Vec<(Identifier, String)>> {
+        let lines: Vec<Vec<Atom>> = program.split('\n').map(parse_line).collect();
+
+        for atoms in &lines {
+            if let [Atom::Quotation(expr), Atom::Symbol(ident), Atom::DefVar] = atoms.as_slice() {
+                if let [Atom::Function(params, body, declared)] = expr.as_slice() {
+                    if !self.pending.contains_key(ident) {
+                        let definition = Definition {
+                            params: params.clone(),
+                            body: nest_quotations(body),
+                            declared: *declared,
+                        };
+                        self.pending.insert(ident.clone(), definition);
+                        let f = Atom::Function(params.clone(), body.clone(), *declared);
+                        self.env.redefine_var(ident, f);
+                    }
+                }
+            }
+        }
+
+        for (i, atoms) in lines.into_iter().enumerate() {
+            self.line = i + 1;
+            self.check_line(atoms)?;
+        }
+        Ok(self.effects)
+    }
+}
+
+/// Checks `program`, returning the stack effect of each `fn`.
+pub fn check_program(program: &str) -> CheckResult<Vec<(Identifier, String)>> {
+    Checker::new().check_program(program)
+}
+
+pub fn primitive(name: &str) -> Option<Type> {
+    Some(match name {
+        "int" => Type::Int,
+        "bool" => Type::Bool,
+        "str" => Type::Str,
+        "sym" => Type::Sym,
+        "list" => Type::List,
+        "record" => Type::Record,
+        _ => return None,
+    })
+}
+
+fn push_all(base: StackType, types: Vec<Type>) -> StackType {
+    types
+        .into_iter()
+        .fold(base, |stack, ty| StackType::Push(Box::new(stack), ty))
+}
+
+pub(crate) fn describe(atom: &Atom) -> String {
+    use Atom::*;
+    match atom {
+        Bool(b) => b.to_string(),
+        Num(n) => n.to_string(),
+        Str(s) => format!("{:?}", s.to_string()),
+        Symbol(ident) => format!("'{}", ident),
+        Plain(ident) => ident.to_string(),
+        Op(op) => builtin_name(op).unwrap_or("builtin").to_string(),
+        Quotation(_) | Function(..) => "[ ... ]".into(),
+        List(_) => "list".into(),
+        Record(_) => "record".into(),
+        Select(field) => format!(".{}", field),
+        Call => "call".into(),
+        Concat(words) => words.iter().map(describe).collect::<Vec<_>>().join(","),
+        DefVarLiteral => "let".into(),
+        DefFnLiteral => "fn".into(),
+        DefVar | RedefVar | QuotationStart | QuotationEnd => String::new(),
+    }
+}
+
+type FreeVars = (Vec<usize>, Vec<usize>);
+
+fn free_vars_type(ty: &Type) -> FreeVars {
+    let mut vars = (Vec::new(), Vec::new());
+    collect_type(ty, &mut vars);
+    vars
+}
+
+fn free_vars_stack(stack: &StackType) -> FreeVars {
+    let mut vars = (Vec::new(), Vec::new());
+    collect_stack(stack, &mut vars);
+    vars
+}
+
+fn free_vars_effect(effect: &Effect) -> FreeVars {
+    let mut vars = (Vec::new(), Vec::new());
+    collect_stack(&effect.inputs, &mut vars);
+    collect_stack(&effect.outputs, &mut vars);
+    vars
+}
+
+fn collect_type(ty: &Type, vars: &mut FreeVars) {
+    match ty {
+        Type::Var(v) if !vars.0.contains(v) => vars.0.push(*v),
+        Type::Quot(inputs, outputs) => {
+            collect_stack(inputs, vars);
+            collect_stack(outputs, vars);
+        }
+        _ => (),
+    }
+}
+
+fn collect_stack(stack: &StackType, vars: &mut FreeVars) {
+    match stack {
+        StackType::Row(r) if !vars.1.contains(r) => vars.1.push(*r),
+        StackType::Push(rest, ty) => {
+            collect_stack(rest, vars);
+            collect_type(ty, vars);
+        }
+        _ => (),
+    }
+}
+
+fn substitute_type(
+    ty: &Type,
+    types: &HashMap<usize, Type>,
+    rows: &HashMap<usize, StackType>,
+) -> Type {
+    match ty {
+        Type::Var(v) => types.get(v).cloned().unwrap_or(Type::Var(*v)),
+        Type::Quot(inputs, outputs) => Type::Quot(
+            Box::new(substitute_stack(inputs, types, rows)),
+            Box::new(substitute_stack(outputs, types, rows)),
+        ),
+        ty => ty.clone(),
+    }
+}
+
+fn substitute_stack(
+    stack: &StackType,
+    types: &HashMap<usize, Type>,
+    rows: &HashMap<usize, StackType>,
+) -> StackType {
+    match stack {
+        StackType::Row(r) => rows.get(r).cloned().unwrap_or(StackType::Row(*r)),
+        StackType::Push(rest, ty) => StackType::Push(
+            Box::new(substitute_stack(rest, types, rows)),
+            substitute_type(ty, types, rows),
+        ),
+        StackType::Empty => StackType::Empty,
+    }
+}
+
+/// Names variables for printing in order of appearance: rows as `..a`,
+/// `..b`, ..., and types as `x`, `y`, ....
+#[derive(Default)]
+struct Names {
+    types: HashMap<usize, String>,
+    rows: HashMap<usize, String>,
+}
+
+impl Names {
+    fn type_(&mut self, ty: &Type) -> String {
+        match ty {
+            Type::Int => "int".into(),
+            Type::Bool => "bool".into(),
+            Type::Str => "str".into(),
+            Type::Sym => "sym".into(),
+            Type::List => "list".into(),
+            Type::Record => "record".into(),
+            Type::Quot(inputs, outputs) => {
+                format!("[ {} -- {} ]", self.stack(inputs), self.stack(outputs))
+            }
+            Type::Var(v) => {
+                let n = self.types.len();
+                self.types
+                    .entry(*v)
+                    .or_insert_with(|| match ["x", "y", "z", "w"].get(n) {
+                        Some(name) => name.to_string(),
+                        None => format!("t{}", n),
+                    })
+                    .clone()
+            }
+        }
+    }
+
+    fn stack(&mut self, stack: &StackType) -> String {
+        match stack {
+            StackType::Empty => String::new(),
+            StackType::Row(r) => {
+                let n = self.rows.len();
+                let name = self.rows.entry(*r).or_insert_with(|| {
+                    let letter = (b'a' + (n % 26) as u8) as char;
+                    if n < 26 {
+                        letter.to_string()
+                    } else {
+                        format!("{}{}", letter, n / 26)
+                    }
+                });
+                format!("..{}", name)
+            }
+            StackType::Push(rest, ty) => {
+                let rest = self.stack(rest);
+                let ty = self.type_(ty);
+                if rest.is_empty() {
+                    ty
+                } else {
+                    format!("{} {}", rest, ty)
+                }
+            }
+        }
+    }
+
+    fn effect(&mut self, effect: &Effect) -> String {
+        let inputs = self.stack(&effect.inputs);
+        let outputs = self.stack(&effect.outputs);
+        format!("( {} -- {} )", inputs, outputs)
+            .replace("(  --", "( --")
+            .replace("--  )", "-- )")
+    }
+}
+
+#[test]
+fn test_check_effects() {
+    let effects = check_program(
+        r"
+fn inc = 1 +
+fn sq n = n n *
+fn id a = a
+fn fib = dup 2 <= [ drop 1 ] [ 1 - dup 1 - fib swap fib + ] cond
+fn fibn a b c = a 0 > [ a 1 - c b c + fibn ] [ c ] cond
+fn apply q = q call
+fn twice = [1 +] bi@
+fn ifib n = 1 1 [ dup rot3 + ] n 2 - repeat swap drop
+fn both = [1 +] [0 >] bi
+fn pair = 1 true
+fn adder n = [n +]
+let add5 = 5 adder
+fn point = [ 'x 1 'y 2 ] record
+fn getx = .x
+fn second = .1
+let limit = 10
+fn upto n = n limit < [ n 1 + upto ] [ n ] cond
+point getx
+1 add5 call 2 3 add5 call
+1 inc sq id
+true id
+",
+    )
+    .unwrap();
+    let effect = |name: &str| {
+        effects
+            .iter()
+            .find(|(ident, _)| ident == name)
+            .map(|(_, effect)| effect.as_str())
+            .unwrap()
+    };
+    assert_eq!("( ..a int -- ..a int )", effect("inc"));
+    assert_eq!("( ..a int -- ..a int )", effect("sq"));
+    assert_eq!("( ..a x -- ..a x )", effect("id"));
+    assert_eq!("( ..a int -- ..a int )", effect("fib"));
+    assert_eq!("( ..a int int int -- ..a int )", effect("fibn"));
+    assert_eq!("( ..a [ ..a -- ..b ] -- ..b )", effect("apply"));
+    assert_eq!("( ..a int int -- ..a int int )", effect("twice"));
+    assert_eq!("( ..a int -- ..a int )", effect("ifib"));
+    assert_eq!("( ..a int -- ..a int bool )", effect("both"));
+    assert_eq!("( ..a -- ..a int bool )", effect("pair"));
+    assert_eq!("( ..a int -- ..a [ ..b int -- ..b int ] )", effect("adder"));
+    assert_eq!("( ..a -- ..a record )", effect("point"));
+    assert_eq!("( ..a record -- ..a x )", effect("getx"));
+    assert_eq!("( ..a list -- ..a x )", effect("second"));
+    assert_eq!("( ..a int -- ..a int )", effect("upto"));
+}
+
+#[test]
+fn test_check_errors() {
+    let error = |program: &str| check_program(program).unwrap_err();
+
+    let e = error("fn f = true 1 +");
+    assert_eq!(
+        (1, Some("f".into()), "+".to_string()),
+        (e.line, e.function, e.word)
+    );
+    assert_eq!("expected int but found bool", e.message);
+
+    let e = error("1 2 +\n+");
+    assert_eq!((2, "stack underflow"), (e.line, e.message.as_str()));
+
+    let e = error("true [1] [false] cond");
+    assert_eq!(
+        (1, "expected int but found bool"),
+        (e.line, e.message.as_str())
+    );
+
+    let e = error("fn f = g 1 +\nfn g = true");
+    assert_eq!((Some("f".into()), "+".to_string()), (e.function, e.word));
+
+    let e = error("fn f ( n -- n ) = 1 + g\nfn g = 0");
+    assert_eq!(
+        "declared to take 1 and leave 1 values, but its effect is ( ..a int -- ..a int int )",
+        e.message
+    );
+
+    let e = error("undefined");
+    assert_eq!("unknown word undefined", e.message);
+
+    let e = error("fn f = undefned 1 +");
+    assert_eq!(
+        (Some("f".into()), "unknown word undefned"),
+        (e.function, e.message.as_str())
+    );
+}
@@ -1,22 +1,27 @@
+use crate::arity::check_declared_arity;
+use crate::ops::select;
 use crate::parse::*;
-use crate::types::Atom;
-use crate::vm::{Env, Identifier, Stack};
+use crate::types::{Arity, Atom};
+use crate::vm::{Identifier, Stack};
+use crate::vm2::Env;
 
-pub fn eval_call(q: Vec<Atom>, env: &mut Env) {
+use std::rc::Rc;
+
+pub fn eval_call(q: &[Atom], env: &mut Env) {
     for atom in q {
-        eval_atom(atom, env);
+        eval_atom(atom.clone(), env);
     }
 }
 
 pub fn eval_call_quotation(callee: Atom, env: &mut Env) {
-    if let Atom::Quotation(q) = callee {
-        eval_call(q, env);
-    } else {
-        panic!("Tried to call a non-quotation.");
+    match callee {
+        Atom::Quotation(q) => eval_call(&q, env),
+        Atom::Function(p, b, _) => eval_call_function(&p, &b, env),
+        _ => panic!("Tried to call a non-quotation."),
     }
 }
 
-pub fn eval_call_function(params: &Vec<Identifier>, body: Stack, env: &mut Env) {
+pub fn eval_call_function(params: &[Identifier], body: &[Atom], env: &mut Env) {
     if params.is_empty() {
         eval_call(body, env);
     } else {
@@ -26,6 +31,137 @@ pub fn eval_call_function(params: &Vec<Identifier>, body: Stack, env: &mut Env)
     }
 }
 
+/// Evaluate a concatenation like `+,+`: each word is applied to its own segment
+/// of the stack, with the last word taking the topmost segment.
+pub fn eval_concat(words: Vec<Atom>, env: &mut Env) {
+    use crate::arity::{arity_word, StaticArity::*};
+
+    let arities: Vec<u8> = words
+        .iter()
+        .map(|word| match arity_word(word, env) {
+            Fixed(num_in, _) => num_in,
+            Variadic => panic!("Cannot concatenate {:?}, which has variadic arity.", word),
+            Unknown => panic!("Cannot concatenate {:?}, which has unknown arity.", word),
+        })
+        .collect();
+
+    let mut segments: Vec<Stack> = arities
+        .iter()
+        .rev()
+        .map(|&num_in| env.pop_atoms(num_in as usize))
+        .collect();
+    segments.reverse();
+
+    for (word, segment) in words.into_iter().zip(segments) {
+        env.append_atoms(segment);
+        match word {
+            Atom::Function(p, b, _) => eval_call_function(&p, &b, env),
+            word => eval_atom(word, env),
+        }
+    }
+}
+
+/// Names bound by `let` and `fn` within a function body, which shadow any
+/// outer bindings.
+fn local_defs(body: &[Atom], defs: &mut Vec<Identifier>) {
+    use Atom::*;
+    for (atom, next) in body.iter().zip(body.iter().skip(1)) {
+        if let (Symbol(ident), DefVarLiteral) | (Symbol(ident), DefFnLiteral) = (atom, next) {
+            defs.push(ident.clone());
+        }
+    }
+    for atom in body {
+        if let Quotation(q) = atom {
+            local_defs(q, defs);
+        }
+    }
+}
+
+fn capture(
+    ident: &Identifier,
+    bound: &[Identifier],
+    captured: &mut Vec<Identifier>,
+    env: &Env,
+) -> Option<Atom> {
+    if bound.contains(ident) {
+        return None;
+    }
+    let value = env.find_var(ident);
+    if value.is_some() && !captured.contains(ident) {
+        captured.push(ident.clone());
+    }
+    value
+}
+
+fn close_over_body(
+    body: &[Atom],
+    bound: &[Identifier],
+    captured: &mut Vec<Identifier>,
+    env: &Env,
+) -> Vec<Atom> {
+    use Atom::*;
+
+    let mut closed = Vec::with_capacity(body.len());
+    for atom in body {
+        match atom {
+            Plain(ident) => match capture(ident, bound, captured, env) {
+                Some(f @ Function(..)) => {
+                    closed.push(f);
+                    closed.push(Call);
+                }
+                Some(value) => closed.push(value),
+                None => closed.push(Plain(ident.clone())),
+            },
+            Quotation(q) => {
+                closed.push(Quotation(Rc::new(close_over_body(q, bound, captured, env))))
+            }
+            Concat(words) => closed.push(Concat(
+                words
+                    .iter()
+                    .map(|word| match word {
+                        Plain(ident) => capture(ident, bound, captured, env)
+                            .unwrap_or_else(|| Plain(ident.clone())),
+                        word => word.clone(),
+                    })
+                    .collect(),
+            )),
+            atom => closed.push(atom.clone()),
+        }
+    }
+    closed
+}
+
+/// Capture the free variables of a function at the point of its definition, the
+/// same way quotations capture them as they are built, so that they can't be
+/// shadowed by the scope it is eventually called from. Names which aren't bound
+/// yet, such as recursive references, are still looked up when called.
+///
+/// Also returns the names which were captured.
+pub fn close_over(f: Atom, env: &Env) -> (Atom, Vec<Identifier>) {
+    let mut captured = Vec::new();
+    if let Atom::Function(params, body, declared) = f {
+        let mut bound = params.clone();
+        local_defs(&body, &mut bound);
+        let body = close_over_body(&body, &bound, &mut captured, env);
+        (Atom::Function(params, Rc::new(body), declared), captured)
+    } else {
+        (f, captured)
+    }
+}
+
+/// Panics if there are fewer atoms on the stack than `callee` takes.
+fn check_arity<T: std::fmt::Debug>(callee: T, arity: Arity, env: &Env) {
+    if let Some((num_in, _)) = arity {
+        let stack_len = env.stack().len();
+        if stack_len < num_in as usize {
+            panic!(
+                "{:#?} expected {} arguments but {} were given",
+                callee, num_in, stack_len
+            );
+        }
+    }
+}
+
 /// Take an Atom and evaluate its effect on the stack. For basic primitives,
 /// this simply pushes them onto the stack.
 pub fn eval_atom(atom: Atom, env: &mut Env) {
@@ -36,8 +172,8 @@ pub fn eval_atom(atom: Atom, env: &mut Env) {
             QuotationStart | QuotationEnd => (),
             Plain(ident) => {
                 match env.find_var(&ident) {
-                    Some(Function(params, body)) => {
-                        env.push_atom(Function(params, body));
+                    Some(f @ Function(..)) => {
+                        env.push_atom(f);
                         env.push_atom(Call);
                     }
                     Some(found_atom) => env.push_atom(found_atom),
@@ -55,24 +191,29 @@ pub fn eval_atom(atom: Atom, env: &mut Env) {
     }
 
     if !env.lazy_mode() {
-        if let Quotation(_) | Function(_, _) = atom {
+        if let Quotation(_) | Function(..) = atom {
         } else {
-            use crate::arity::arity_atom;
-            let arity = arity_atom(&atom, env, &mut Vec::new());
-            if let Some((num_in, _)) = arity {
-                let stack_len = env.last_frame().stack.len();
-                if stack_len < num_in as usize {
-                    panic!(
-                        "{:#?} expected {} arguments but {} were given",
-                        atom, num_in, stack_len
-                    );
-                }
-            }
+            use crate::arity::{arity_atom, arity_combinator};
+            let arity = match &atom {
+                // Combinators also need their operands on the stack.
+                Op(op) => match arity_combinator(op, env.stack(), env) {
+                    Some((num_operands, arity)) => arity
+                        .fixed()
+                        .map(|(num_in, num_out)| (num_in + num_operands as u8, num_out)),
+                    None => arity_atom(&atom, env).fixed(),
+                },
+                // Functions are checked against their declared stack effects
+                // as they are called instead.
+                Plain(_) | Call => None,
+                _ => arity_atom(&atom, env).fixed(),
+            };
+            check_arity(&atom, arity, env);
         }
     }
 
     match atom {
-        Bool(_) | Num(_) | Quotation(_) | Symbol(_) | Function(_, _) | List(_) => {
+        Bool(_) | Num(_) | Str(_) | Quotation(_) | Symbol(_) | Function(..) | List(_)
+        | Record(_) => {
             env.push_atom(atom);
         }
         Op(op) => {
@@ -83,15 +224,23 @@ pub fn eval_atom(atom: Atom, env: &mut Env) {
         }
         QuotationEnd => {
             let stack: Stack = env.pop().unwrap().stack;
-            let quotation = Quotation(stack);
+            let quotation = Quotation(Rc::new(stack));
             env.push_atom(quotation);
         }
-        DefVar => {
+        DefVar | RedefVar => {
+            let redef = atom == RedefVar;
             let a = env.pop_atom();
             let b = env.pop_atom();
             if let (Symbol(ident), Quotation(expr)) = (a, b) {
-                let result_of_expr = eval_with_new_scope(expr, env);
-                env.bind_var(&ident, result_of_expr);
+                let result_of_expr = eval_with_new_scope(&expr, env);
+                let (result_of_expr, captured) = close_over(result_of_expr, env);
+                check_declared_arity(&ident, &result_of_expr, env);
+                if redef {
+                    env.redefine_var(&ident, result_of_expr);
+                } else {
+                    env.bind_var(&ident, result_of_expr);
+                }
+                env.note_captures(&ident, captured);
             } else {
                 unreachable!();
             }
@@ -100,7 +249,9 @@ pub fn eval_atom(atom: Atom, env: &mut Env) {
             let a = env.pop_atom();
             let b = env.pop_atom();
             if let Symbol(ident) = a {
+                let (b, captured) = close_over(b, env);
                 env.bind_var(&ident, b);
+                env.note_captures(&ident, captured);
             } else {
                 panic!("Expected '<value> <ident> let'.")
             }
@@ -109,34 +260,45 @@ pub fn eval_atom(atom: Atom, env: &mut Env) {
             let a = env.pop_atom();
             let b = env.pop_atom();
             if let (Symbol(ident), Quotation(q)) = (a, b) {
-                env.bind_var(&ident, Function(Vec::new(), q));
+                let (f, captured) = close_over(Function(Vec::new(), q, None), env);
+                env.bind_var(&ident, f);
+                env.note_captures(&ident, captured);
             } else {
                 panic!("Expected '<quotation> <ident> fn'.")
             }
         }
         Call => {
-            match env.pop_atom() {
-                Quotation(q) => eval_call(q, env),
-                Function(p, b) => eval_call_function(&p, b, env),
-                _ => {
-                    panic!("Tried to call non-quotation.");
-                }
-            };
+            let callee = env.pop_atom();
+            if let Function(_, _, declared) = &callee {
+                check_arity(&callee, *declared, env);
+            }
+            eval_call_quotation(callee, env);
+        }
+        Concat(words) => eval_concat(words, env),
+        Select(field) => {
+            let value = env.pop_atom();
+            env.push_atom(select(value, &field));
         }
         Plain(ident) => match env.find_var(&ident) {
-            Some(Function(p, b)) => eval_call_function(&p, b, env),
+            Some(Function(p, b, declared)) => {
+                check_arity(&ident, declared, env);
+                eval_call_function(&p, &b, env)
+            }
+            // Native words are bound like variables, and called like builtins.
+            Some(Op(op)) => {
+                check_arity(&ident, op.arity, env);
+                (op.f)(env)
+            }
             Some(atom) => env.push_atom(atom),
             None => panic!("Unrecognized identifier: {}", ident),
         },
     }
 }
 
-pub fn eval_with_new_scope(expr: Vec<Atom>, env: &mut Env) -> Atom {
+pub fn eval_with_new_scope(expr: &[Atom], env: &mut Env) -> Atom {
     env.push_blank(false);
 
-    for atom in expr {
-        eval_atom(atom, env);
-    }
+    eval_call(expr, env);
 
     let mut stack: Stack = env.pop().unwrap().stack;
     if let Some(atom) = stack.pop() {
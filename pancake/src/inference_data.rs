@@ -1,6 +1,9 @@
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::hash::{Hash, Hasher};
 use std::rc::Rc;
 
+use crate::ast::Term;
 use crate::vm2::Identifier;
 use crate::typeck::freshen_above;
 
@@ -16,17 +19,41 @@ pub enum TypeScheme {
     SimpleType(SimpleTypeRef)
 }
 
+/// The term which introduced a type, if any, for pointing at in errors.
+/// Types from the initial context have none. The term is shared rather than
+/// shown, since most types never end up in an error.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct Provenance(pub Option<Rc<Term>>);
+
+impl Provenance {
+    pub fn of(term: &Rc<Term>) -> Self {
+        Self(Some(term.clone()))
+    }
+}
+
+impl fmt::Display for Provenance {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match &self.0 {
+            Some(term) => write!(f, "`{}`", term),
+            None => write!(f, "a built-in")
+        }
+    }
+}
+
 #[derive(Clone, Debug, Eq, PartialEq)]
 pub enum SimpleType {
     Function {
         lhs: SimpleTypeRef,
-        rhs: SimpleTypeRef
+        rhs: SimpleTypeRef,
+        prov: Provenance
     },
     Record {
-        fields: HashMap<Identifier, SimpleTypeRef>
+        fields: HashMap<Identifier, SimpleTypeRef>,
+        prov: Provenance
     },
     Primitive {
-        name: Identifier
+        name: Identifier,
+        prov: Provenance
     },
     Variable(TVarId)
 }
@@ -39,16 +66,47 @@ pub struct TypeVariable {
 }
 
 pub struct TVarRegistry {
-    tvars: Vec<TypeVariable>
+    tvars: Vec<TypeVariable>,
+    /// The constraints involving type variables which have been propagated,
+    /// so that they aren't propagated again, which would never end for
+    /// recursive types.
+    constraints: HashSet<(CachedType, CachedType)>
 }
 
-#[derive(Copy, Clone, Debug, Eq, Hash, PartialEq)]
+/// A type in a cached constraint. Variables are compared by their ids, and
+/// other types by their addresses, which can't be reused while the cache holds
+/// on to them.
+#[derive(Clone, Debug)]
+struct CachedType(SimpleTypeRef);
+
+impl PartialEq for CachedType {
+    fn eq(&self, other: &Self) -> bool {
+        match (&*self.0, &*other.0) {
+            (SimpleType::Variable(a), SimpleType::Variable(b)) => a == b,
+            _ => Rc::ptr_eq(&self.0, &other.0)
+        }
+    }
+}
+
+impl Eq for CachedType {}
+
+impl Hash for CachedType {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        match &*self.0 {
+            SimpleType::Variable(key) => key.hash(state),
+            _ => (Rc::as_ptr(&self.0) as usize).hash(state)
+        }
+    }
+}
+
+#[derive(Copy, Clone, Debug, Eq, Hash, Ord, PartialEq, PartialOrd)]
 pub struct TVarId(usize);
 
 impl TVarRegistry {
     pub fn new() -> Self {
         Self {
-            tvars: Vec::new()
+            tvars: Vec::new(),
+            constraints: HashSet::new()
         }
     }
 
@@ -68,6 +126,14 @@ impl TVarRegistry {
     pub fn get_mut(&mut self, key: &TVarId) -> &mut TypeVariable {
         self.tvars.get_mut(key.0).unwrap()
     }
+
+    /// Records that `lhs` <: `rhs` has been constrained, returning whether it
+    /// already had been.
+    pub fn cache_constraint(&mut self, lhs: &SimpleTypeRef, rhs: &SimpleTypeRef)
+                            -> bool {
+        let key = (CachedType(lhs.clone()), CachedType(rhs.clone()));
+        !self.constraints.insert(key)
+    }
 }
 
 impl TypeScheme {
@@ -106,20 +172,55 @@ impl SimpleType {
     pub fn level(&self, tvars: &mut TVarRegistry) -> i32 {
         use SimpleType::*;
         match self {
-            Function { lhs, rhs } =>
+            Function { lhs, rhs, .. } =>
                 std::cmp::max(lhs.level(tvars), rhs.level(tvars)),
-            Record { fields } => {
+            Record { fields, .. } => {
                 let mut max = 0;
                 for (_, typ) in fields {
                     max = std::cmp::max(max, typ.level(tvars));
                 }
                 max
             }
-            Primitive { name: _ } => 0,
+            Primitive { .. } => 0,
             Variable(key) => tvars.get(key).level
         }
     }
 
+    pub fn prov(&self) -> Provenance {
+        use SimpleType::*;
+        match self {
+            Function { prov, .. } | Record { prov, .. } | Primitive { prov, .. } =>
+                prov.clone(),
+            Variable(_) => Provenance::default()
+        }
+    }
+
+    /// Copies this type, attributing the parts which have no provenance to
+    /// `prov`. Type variables are left alone, since they're shared.
+    pub fn with_prov(typ: &Rc<Self>, prov: &Provenance) -> Rc<Self> {
+        use SimpleType::*;
+        let fill = |p: &Provenance| if p.0.is_some() { p.clone() }
+                                    else { prov.clone() };
+        Rc::new(match &**typ {
+            Function { lhs, rhs, prov: p } => Function {
+                lhs: Self::with_prov(lhs, prov),
+                rhs: Self::with_prov(rhs, prov),
+                prov: fill(p)
+            },
+            Record { fields, prov: p } => Record {
+                fields: fields.iter()
+                    .map(|(k, v)| (k.clone(), Self::with_prov(v, prov)))
+                    .collect(),
+                prov: fill(p)
+            },
+            Primitive { name, prov: p } => Primitive {
+                name: name.clone(),
+                prov: fill(p)
+            },
+            Variable(_) => return typ.clone()
+        })
+    }
+
     pub fn tvar_id(&self) -> Option<TVarId> {
         match self {
             Self::Variable(key) => Some(*key),
@@ -127,3 +228,132 @@ impl SimpleType {
         }
     }
 }
+
+/// A type as shown to users, where the bounds of each type variable have been
+/// coalesced into unions and intersections.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum Type {
+    Top,
+    Bot,
+    Union(Vec<Type>),
+    Inter(Vec<Type>),
+    Function(Box<Type>, Box<Type>),
+    Record(Vec<(Identifier, Type)>),
+    Primitive(Identifier),
+    Variable(TVarId),
+    Recursive(TVarId, Box<Type>)
+}
+
+impl Type {
+    /// The union of `types`, flattening nested unions and dropping duplicates.
+    /// Records are merged into one, with only the fields they have in common.
+    pub fn union(types: Vec<Type>) -> Type {
+        Self::flatten(types, true)
+    }
+
+    /// The intersection of `types`, flattening nested intersections and
+    /// dropping duplicates. Records are merged into one, with the fields of
+    /// all of them.
+    pub fn inter(types: Vec<Type>) -> Type {
+        Self::flatten(types, false)
+    }
+
+    fn flatten(types: Vec<Type>, union: bool) -> Type {
+        let (empty, full) = if union { (Type::Bot, Type::Top) }
+                            else { (Type::Top, Type::Bot) };
+        let mut flat: Vec<Type> = Vec::new();
+        for typ in types {
+            let parts = match typ {
+                Type::Union(parts) if union => parts,
+                Type::Inter(parts) if !union => parts,
+                typ => vec![typ]
+            };
+            for part in parts {
+                if part == full {
+                    return full;
+                }
+                let record = flat.iter().position(|t| matches!(t, Type::Record(_)));
+                match (part, record) {
+                    (Type::Record(fields), Some(i)) => {
+                        if let Type::Record(other) = flat.remove(i) {
+                            flat.insert(i, Type::Record(
+                                Self::merge_fields(other, fields, union)));
+                        }
+                    }
+                    (part, _) => if part != empty && !flat.contains(&part) {
+                        flat.push(part);
+                    }
+                }
+            }
+        }
+        match flat.len() {
+            0 => empty,
+            1 => flat.pop().unwrap(),
+            _ if union => Type::Union(flat),
+            _ => Type::Inter(flat)
+        }
+    }
+
+    /// The fields of the union or intersection of two records, sorted by name.
+    fn merge_fields(mut lhs: Vec<(Identifier, Type)>, rhs: Vec<(Identifier, Type)>,
+                    union: bool) -> Vec<(Identifier, Type)> {
+        let mut merged = Vec::new();
+        for (name, typ) in rhs {
+            match lhs.iter().position(|(n, _)| *n == name) {
+                Some(i) => {
+                    let (_, other) = lhs.remove(i);
+                    merged.push((name, Self::flatten(vec![other, typ], union)));
+                }
+                None if !union => merged.push((name, typ)),
+                None => {}
+            }
+        }
+        if !union {
+            merged.append(&mut lhs);
+        }
+        merged.sort_by(|(k0, _), (k1, _)| k0.cmp(k1));
+        merged
+    }
+
+    fn show(&self, names: &mut HashMap<TVarId, String>, outer: u8) -> String {
+        use Type::*;
+        let (prec, shown) = match self {
+            Top => return "top".into(),
+            Bot => return "bot".into(),
+            Primitive(name) => return name.to_string(),
+            Variable(key) => return Self::name(*key, names),
+            Record(fields) => return format!("{{{}}}", fields.iter()
+                .map(|(k, v)| format!("{}: {}", k, v.show(names, 0)))
+                .collect::<Vec<_>>().join(", ")),
+            Function(lhs, rhs) => {
+                let lhs = lhs.show(names, 11);
+                (10, format!("{} -> {}", lhs, rhs.show(names, 10)))
+            }
+            Union(types) => (20, types.iter().map(|t| t.show(names, 20))
+                             .collect::<Vec<_>>().join(" | ")),
+            Inter(types) => (25, types.iter().map(|t| t.show(names, 25))
+                             .collect::<Vec<_>>().join(" & ")),
+            Recursive(key, body) => {
+                let body = body.show(names, 31);
+                (30, format!("{} as {}", body, Self::name(*key, names)))
+            }
+        };
+        if outer > prec { format!("({})", shown) } else { shown }
+    }
+
+    /// Names type variables `'a`, `'b`, ... in the order they're shown.
+    fn name(key: TVarId, names: &mut HashMap<TVarId, String>) -> String {
+        let n = names.len();
+        names.entry(key).or_insert_with(|| {
+            let letter = (b'a' + (n % 26) as u8) as char;
+            if n < 26 { format!("'{}", letter) }
+            else { format!("'{}{}", letter, n / 26) }
+        }).clone()
+    }
+}
+
+impl fmt::Display for Type {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.show(&mut HashMap::new(), 0))
+    }
+}
@@ -0,0 +1,337 @@
+use crate::eval::{eval_atom, eval_line};
+use crate::ops;
+use crate::types::{Arity, Atom, NumType, Op};
+use crate::vm::{Identifier, Stack, SPECIAL_IDENTS};
+use crate::vm2::Env;
+
+use std::any::Any;
+use std::cell::Cell;
+use std::fmt;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::Once;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct EvalError {
+    pub message: String,
+}
+
+impl fmt::Display for EvalError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(&self.message)
+    }
+}
+
+impl std::error::Error for EvalError {}
+
+pub type EvalResult<T> = Result<T, EvalError>;
+
+fn fail<T>(message: String) -> EvalResult<T> {
+    Err(EvalError { message })
+}
+
+/// Values which can be taken off the stack.
+pub trait FromAtom: Sized {
+    /// What the values are called in errors, like `number`.
+    const KIND: &'static str;
+
+    /// Converts `atom`, or gives it back if it isn't one of these values.
+    fn from_atom(atom: Atom) -> Result<Self, Atom>;
+}
+
+impl FromAtom for Atom {
+    const KIND: &'static str = "value";
+
+    fn from_atom(atom: Atom) -> Result<Self, Atom> {
+        Ok(atom)
+    }
+}
+
+impl FromAtom for bool {
+    const KIND: &'static str = "boolean";
+
+    fn from_atom(atom: Atom) -> Result<Self, Atom> {
+        match atom {
+            Atom::Bool(b) => Ok(b),
+            atom => Err(atom),
+        }
+    }
+}
+
+impl FromAtom for NumType {
+    const KIND: &'static str = "number";
+
+    fn from_atom(atom: Atom) -> Result<Self, Atom> {
+        match atom {
+            Atom::Num(n) => Ok(n),
+            atom => Err(atom),
+        }
+    }
+}
+
+impl FromAtom for String {
+    const KIND: &'static str = "string";
+
+    fn from_atom(atom: Atom) -> Result<Self, Atom> {
+        match atom {
+            Atom::Str(s) => Ok(s.to_string()),
+            atom => Err(atom),
+        }
+    }
+}
+
+impl<T: FromAtom> FromAtom for Vec<T> {
+    const KIND: &'static str = "list";
+
+    fn from_atom(atom: Atom) -> Result<Self, Atom> {
+        match atom {
+            Atom::List(list) => list
+                .iter()
+                .cloned()
+                .map(T::from_atom)
+                .collect::<Result<_, _>>()
+                .map_err(|_| Atom::List(list)),
+            atom => Err(atom),
+        }
+    }
+}
+
+/// An interpreter for using Pancake from Rust, which keeps its definitions and
+/// stack between calls.
+///
+/// Errors, including panics in native words, are returned rather than
+/// unwinding into the host. What was evaluated before an error is kept.
+///
+/// The evaluator raises errors by panicking, so they're only caught if panics
+/// unwind: with `panic = "abort"`, an error aborts the process instead.
+pub struct Interpreter {
+    pub env: Env,
+}
+
+impl Default for Interpreter {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Interpreter {
+    pub fn new() -> Self {
+        Self { env: Env::new() }
+    }
+
+    /// Evaluates `source` a line at a time, on top of what was evaluated
+    /// before.
+    pub fn eval(&mut self, source: &str) -> EvalResult<()> {
+        self.catch(|env| {
+            for line in source.lines() {
+                eval_line(line, env);
+            }
+        })
+    }
+
+    pub fn push(&mut self, value: impl Into<Atom>) {
+        self.env.push_atom(value.into());
+    }
+
+    /// Pops the top value, leaving it on the stack if it isn't a `T`.
+    pub fn pop<T: FromAtom>(&mut self) -> EvalResult<T> {
+        let atom = match self.env.last_frame().stack.pop() {
+            Some(atom) => atom,
+            None => return fail("Popped from an empty stack.".to_string()),
+        };
+        T::from_atom(atom).or_else(|atom| {
+            let message = format!("Expected a {} but got {:?}.", T::KIND, atom);
+            self.env.push_atom(atom);
+            fail(message)
+        })
+    }
+
+    pub fn stack(&self) -> &Stack {
+        self.env.stack()
+    }
+
+    /// Calls the function or builtin `name` on `args` in a frame of its own,
+    /// returning what it leaves there. The stack is left as it was.
+    pub fn call(&mut self, name: &str, args: Vec<Atom>) -> EvalResult<Vec<Atom>> {
+        let ident = Identifier::from(name);
+        let word = match ops::get_op(name) {
+            Some(op) => Atom::Op(op),
+            None if self.env.find_var(&ident).is_some() => Atom::Plain(ident),
+            None => return fail(format!("Unrecognized identifier: {}", name)),
+        };
+        self.catch(|env| {
+            env.push_blank(false);
+            env.append_atoms(args);
+            eval_atom(word, env);
+            env.pop().unwrap().stack
+        })
+    }
+
+    /// Adds a word `name` which runs `f`, taking and leaving the numbers of
+    /// values in `arity` if it's known. It's bound like a top-level variable
+    /// of this interpreter, so no other interpreter sees it, and `redef` can
+    /// replace it.
+    pub fn register_native(
+        &mut self,
+        name: &str,
+        arity: Arity,
+        f: impl Fn(&mut Env) + 'static,
+    ) -> EvalResult<()> {
+        if SPECIAL_IDENTS.contains(&name) || ops::is_builtin(name) {
+            return fail(format!("{} is already a builtin.", name));
+        }
+        let ident = Identifier::from(name);
+        if self.env.find_var(&ident).is_some() {
+            return fail(format!("{} is already defined.", name));
+        }
+        // Interned names live as long as the thread, so the op can borrow one.
+        let op = Op::new(ident.as_str(), f, arity);
+        self.env.redefine_var(ident, Atom::Op(op));
+        Ok(())
+    }
+
+    /// Runs `f`, turning a panic into an error and dropping the frames it
+    /// left behind. Panics on this thread aren't reported by the panic hook
+    /// meanwhile, so that errors aren't printed.
+    fn catch<T>(&mut self, f: impl FnOnce(&mut Env) -> T) -> EvalResult<T> {
+        let depth = self.env.depth();
+        let flags = (
+            self.env.loop_like,
+            self.env.using_for_else,
+            self.env.for_else,
+        );
+        let env = &mut self.env;
+        silence_caught_panics();
+        let catching = CATCHING.with(|flag| flag.replace(true));
+        let result = panic::catch_unwind(AssertUnwindSafe(|| f(env)));
+        CATCHING.with(|flag| flag.set(catching));
+        result.or_else(|payload| {
+            while self.env.depth() > depth {
+                self.env.pop();
+            }
+            let (loop_like, using_for_else, for_else) = flags;
+            self.env.loop_like = loop_like;
+            self.env.using_for_else = using_for_else;
+            self.env.for_else = for_else;
+            fail(panic_message(payload))
+        })
+    }
+}
+
+thread_local! {
+    /// Whether this thread is running `Interpreter::catch`.
+    static CATCHING: Cell<bool> = Cell::new(false);
+}
+
+/// Wraps the panic hook, the first time it's called, so that it ignores
+/// panics on threads which are running `Interpreter::catch`. The hook is
+/// shared by all threads, so it's only replaced once rather than around each
+/// call.
+fn silence_caught_panics() {
+    static WRAP_HOOK: Once = Once::new();
+    WRAP_HOOK.call_once(|| {
+        let hook = panic::take_hook();
+        panic::set_hook(Box::new(move |info| {
+            if !CATCHING.with(Cell::get) {
+                hook(info);
+            }
+        }));
+    });
+}
+
+fn panic_message(payload: Box<dyn Any + Send>) -> String {
+    if let Some(message) = payload.downcast_ref::<&str>() {
+        message.to_string()
+    } else if let Some(message) = payload.downcast_ref::<String>() {
+        message.clone()
+    } else {
+        "Evaluation failed.".to_string()
+    }
+}
+
+#[test]
+fn test_interpreter() {
+    let mut interp = Interpreter::new();
+    interp.eval("[dup *] 'square fn").unwrap();
+    interp.eval("3 square\n1 +").unwrap();
+    assert_eq!(Ok(10), interp.pop::<NumType>());
+
+    interp.push(vec![1, 2]);
+    interp.push(true);
+    assert_eq!(
+        Err(EvalError {
+            message: "Expected a number but got Bool(true).".to_string()
+        }),
+        interp.pop::<NumType>()
+    );
+    assert_eq!(Ok(true), interp.pop());
+    assert_eq!(Ok(vec![1, 2]), interp.pop::<Vec<NumType>>());
+    assert!(interp.pop::<Atom>().is_err());
+
+    interp.push("kept");
+    assert_eq!(
+        Ok(vec![Atom::Num(16)]),
+        interp.call("square", vec![4.into()])
+    );
+    assert_eq!(
+        Ok(vec![Atom::Num(3)]),
+        interp.call("+", vec![1.into(), 2.into()])
+    );
+    assert!(interp.call("cube", vec![]).is_err());
+    assert_eq!(Ok("kept".to_string()), interp.pop());
+}
+
+#[test]
+fn test_interpreter_errors() {
+    let mut interp = Interpreter::new();
+    interp.eval("1 'one let").unwrap();
+    let err = interp.eval("2 'two let\n[1 nope] list").unwrap_err();
+    assert_eq!("Unrecognized identifier: nope", err.to_string());
+    assert_eq!(1, interp.env.depth());
+    assert!(interp.stack().is_empty());
+    interp.eval("one two +").unwrap();
+    assert_eq!(Ok(3), interp.pop::<NumType>());
+}
+
+#[test]
+fn test_register_native() {
+    use std::cell::Cell;
+    use std::rc::Rc;
+
+    let mut interp = Interpreter::new();
+    let total = Rc::new(Cell::new(0));
+    let counter = total.clone();
+    interp
+        .register_native("tally", Some((1, 0)), move |env| match env.pop_atom() {
+            Atom::Num(n) => counter.set(counter.get() + n),
+            atom => panic!("Expected a number to tally but got {:?}.", atom),
+        })
+        .unwrap();
+    interp.eval("1 tally\n2 3 tally tally").unwrap();
+    assert_eq!(6, total.get());
+    assert!(interp.eval("true tally").is_err());
+    interp.call("tally", vec![4.into()]).unwrap();
+    assert_eq!(10, total.get());
+
+    interp.eval("fn tally2 a b = a tally b tally").unwrap();
+    interp.eval("5 6 tally2").unwrap();
+    assert_eq!(21, total.get());
+
+    assert!(interp.register_native("tally", None, |_| {}).is_err());
+    assert!(interp.register_native("dup", None, |_| {}).is_err());
+    interp.eval("5 'five let").unwrap();
+    assert!(interp.register_native("five", None, |_| {}).is_err());
+
+    // Natives belong to the interpreter which registered them.
+    let mut other = Interpreter::new();
+    other
+        .register_native("five", Some((0, 1)), |env| env.push_atom(Atom::Num(-5)))
+        .unwrap();
+    other.eval("five").unwrap();
+    assert_eq!(Ok(-5), other.pop::<NumType>());
+    interp.eval("five").unwrap();
+    assert_eq!(Ok(5), interp.pop::<NumType>());
+    assert!(Interpreter::new().eval("five").is_err());
+    assert!(Interpreter::new().eval("1 tally").is_err());
+    other.eval("redef five = 55\nfive").unwrap();
+    assert_eq!(Ok(55), other.pop::<NumType>());
+}
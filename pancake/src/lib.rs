@@ -3,14 +3,20 @@
 //#[macro_use] extern crate flamer;
 
 pub mod arity;
+pub mod bytecode;
+pub mod check;
 pub mod eval;
+pub mod interpreter;
 pub mod ops;
 pub mod parse;
 pub mod types;
 pub mod vm;
 
 pub mod ast;
+pub mod lower;
 pub mod vm2;
 pub mod inference_data;
 pub mod typeck;
+pub mod prelude;
+pub mod smt;
 
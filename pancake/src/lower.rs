@@ -0,0 +1,794 @@
+//! Lowers concatenative programs into the lambda calculus of `ast`, so that
+//! they can be typed by `typeck`.
+//!
+//! Lowering runs a program symbolically: the stack holds terms rather than
+//! values, and a word which takes `n` values and leaves one pushes its
+//! application to the terms it pops. A function becomes a lambda over the
+//! values it pops, so it must leave exactly one value. Quotations are inlined
+//! where they're called, and only become lambdas when they're passed to a
+//! function.
+
+use crate::arity::{arity_atom, nest_quotations, StaticArity};
+use crate::ast::{Literal, Pgrm, Term};
+use crate::check::{builtin_name, builtin_signature, primitive};
+use crate::ops::parse_shuffle;
+use crate::parse::parse_line;
+use crate::types::{Arity, Atom};
+use crate::vm::{Identifier, InlinableString};
+use crate::vm2::Env;
+
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::rc::Rc;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct LowerError {
+    pub line: usize,
+    pub message: String,
+}
+
+impl fmt::Display for LowerError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "line {}: {}", self.line, self.message)
+    }
+}
+
+type LowerResult<T> = Result<T, LowerError>;
+
+/// A value on the symbolic stack. Quotations, symbols and strings are kept as
+/// they are, since they're mostly consumed by combinators.
+#[derive(Debug, Clone, PartialEq)]
+enum Value {
+    Term(Term),
+    Quotation(Vec<Identifier>, Vec<Atom>),
+    Symbol(Identifier),
+    Str(InlinableString),
+}
+
+#[derive(Debug, Clone)]
+enum Binding {
+    Value(Value),
+    /// A function bound with `fn` inside an expression, which is called when
+    /// it's used.
+    Callable(Value),
+}
+
+struct Lowerer {
+    stack: Vec<Value>,
+    /// The names of the values popped from below the stack by the function
+    /// being lowered, deepest first.
+    inputs: Vec<Identifier>,
+    /// Whether popping from an empty stack takes an input, which it does in a
+    /// function body but not at the top level.
+    allow_inputs: bool,
+    /// How many values the function being lowered is declared to take, apart
+    /// from its parameters.
+    declared_inputs: Option<usize>,
+    scopes: Vec<HashMap<Identifier, Binding>>,
+    /// Top-level variables.
+    globals: HashSet<Identifier>,
+    /// The number of values each top-level function takes.
+    arities: HashMap<Identifier, usize>,
+    /// The top-level functions, used to infer the arities of those which are
+    /// called before they're lowered.
+    env: Env,
+    line: usize,
+}
+
+impl Lowerer {
+    fn new() -> Self {
+        Self {
+            stack: Vec::new(),
+            inputs: Vec::new(),
+            allow_inputs: false,
+            declared_inputs: None,
+            scopes: vec![HashMap::new()],
+            globals: HashSet::new(),
+            arities: HashMap::new(),
+            env: Env::new(),
+            line: 0,
+        }
+    }
+
+    fn fail<T>(&self, message: String) -> LowerResult<T> {
+        Err(LowerError {
+            line: self.line,
+            message,
+        })
+    }
+
+    fn push(&mut self, term: Term) {
+        self.stack.push(Value::Term(term));
+    }
+
+    fn pop(&mut self) -> LowerResult<Value> {
+        if let Some(value) = self.stack.pop() {
+            return Ok(value);
+        }
+        if !self.allow_inputs {
+            return self.fail("stack underflow".into());
+        }
+        let ident = Identifier::from(format!("_{}", self.inputs.len()));
+        self.inputs.insert(0, ident.clone());
+        Ok(Value::Term(Term::Var(ident)))
+    }
+
+    /// Pops `n` values, keeping them in stack order.
+    fn pop_n(&mut self, n: usize) -> LowerResult<Vec<Value>> {
+        let mut values = (0..n)
+            .map(|_| self.pop())
+            .collect::<LowerResult<Vec<_>>>()?;
+        values.reverse();
+        Ok(values)
+    }
+
+    fn pop_term(&mut self) -> LowerResult<Term> {
+        let value = self.pop()?;
+        self.term(value)
+    }
+
+    fn term(&mut self, value: Value) -> LowerResult<Term> {
+        match value {
+            Value::Term(term) => Ok(term),
+            Value::Quotation(params, body) => Ok(self.lower_function(&params, &body, None)?.1),
+            Value::Symbol(ident) => self.fail(format!("the symbol '{} can't be lowered", ident)),
+            Value::Str(s) => self.fail(format!("the string {:?} can't be lowered", s.to_string())),
+        }
+    }
+
+    /// Pushes the application of `f` to the top `n` values.
+    fn apply(&mut self, f: Term, n: usize) -> LowerResult<()> {
+        let mut term = f;
+        for value in self.pop_n(n)? {
+            term = Term::App(Rc::new(term), Rc::new(self.term(value)?));
+        }
+        self.push(term);
+        Ok(())
+    }
+
+    /// Lowers a function body to a lambda over the values it takes, returning
+    /// how many values that is.
+    fn lower_function(
+        &mut self,
+        params: &[Identifier],
+        body: &[Atom],
+        declared: Arity,
+    ) -> LowerResult<(usize, Term)> {
+        let stack = std::mem::take(&mut self.stack);
+        let inputs = std::mem::take(&mut self.inputs);
+        let allow_inputs = std::mem::replace(&mut self.allow_inputs, true);
+        let declared_inputs = std::mem::replace(
+            &mut self.declared_inputs,
+            declared.map(|(num_in, _)| (num_in as usize).saturating_sub(params.len())),
+        );
+
+        let scope = params
+            .iter()
+            .map(|p| (p.clone(), Binding::Value(Value::Term(Term::Var(p.clone())))))
+            .collect();
+        self.scopes.push(scope);
+        let result = self.lower_atoms(body).and_then(|_| match self.stack.len() {
+            1 => self.pop_term(),
+            n => self.fail(format!(
+                "a function must leave 1 value to be lowered, not {}",
+                n
+            )),
+        });
+        self.scopes.pop();
+
+        let taken: Vec<Identifier> = std::mem::replace(&mut self.inputs, inputs);
+        self.stack = stack;
+        self.allow_inputs = allow_inputs;
+        self.declared_inputs = declared_inputs;
+
+        let term = taken
+            .iter()
+            .chain(params)
+            .rev()
+            .fold(result?, |body, param| {
+                Term::Lam(param.clone(), Rc::new(body))
+            });
+        Ok((taken.len() + params.len(), term))
+    }
+
+    fn call(&mut self, callee: Value) -> LowerResult<()> {
+        match callee {
+            Value::Quotation(params, body) => {
+                let values = self.pop_n(params.len())?;
+                let scope = params
+                    .into_iter()
+                    .zip(values)
+                    .map(|(param, value)| (param, Binding::Value(value)))
+                    .collect();
+                self.scopes.push(scope);
+                let result = self.lower_atoms(&body);
+                self.scopes.pop();
+                result
+            }
+            // The arity of a term like a parameter isn't known, so it takes
+            // the rest of the stack, and any inputs the function is declared to
+            // take which haven't been used.
+            Value::Term(f) => {
+                let undeclared = self
+                    .declared_inputs
+                    .map_or(0, |n| n.saturating_sub(self.inputs.len()));
+                self.apply(f, self.stack.len() + undeclared)
+            }
+            _ => self.fail("only quotations and functions can be called".into()),
+        }
+    }
+
+    /// The number of values `callee` takes and leaves, found by lowering it
+    /// on an empty stack.
+    fn arity(&mut self, callee: &Value) -> LowerResult<(usize, usize)> {
+        let stack = std::mem::take(&mut self.stack);
+        let inputs = std::mem::take(&mut self.inputs);
+        let allow_inputs = std::mem::replace(&mut self.allow_inputs, true);
+        let result = self.call(callee.clone());
+        let arity = (self.inputs.len(), self.stack.len());
+        self.stack = stack;
+        self.inputs = inputs;
+        self.allow_inputs = allow_inputs;
+        result.map(|_| arity)
+    }
+
+    /// Calls each of `branches` on the current stack, of which one will run,
+    /// and merges what they leave with `cond` on `condition`.
+    fn branches(&mut self, condition: Term, branches: Vec<Value>) -> LowerResult<()> {
+        let saved = std::mem::take(&mut self.stack);
+        let mut results: Vec<Vec<Value>> = Vec::new();
+        let mut taken = 0;
+        for branch in branches {
+            // Values taken from below the stack by earlier branches are on
+            // the stack for this one too, and vice versa.
+            let before = self.inputs.len();
+            self.stack = self.input_values(taken);
+            self.stack.extend(saved.iter().cloned());
+            self.call(branch)?;
+            let new = self.inputs.len() - before;
+            for result in &mut results {
+                let mut stack = self.input_values(new);
+                stack.append(result);
+                *result = stack;
+            }
+            taken += new;
+            results.push(std::mem::take(&mut self.stack));
+        }
+
+        let mut results = results.into_iter();
+        let first = results.next().unwrap();
+        let second = results.next().unwrap();
+        if first.len() != second.len() {
+            return self
+                .fail("the branches of a conditional leave different numbers of values".into());
+        }
+        for (a, b) in first.into_iter().zip(second) {
+            if a == b {
+                self.stack.push(a);
+            } else {
+                let cond = Term::Var("cond".into());
+                let (a, b) = (self.term(a)?, self.term(b)?);
+                let term = [condition.clone(), a, b]
+                    .iter()
+                    .fold(cond, |f, x| Term::App(Rc::new(f), Rc::new(x.clone())));
+                self.push(term);
+            }
+        }
+        Ok(())
+    }
+
+    /// The first `n` inputs of the function being lowered, as values.
+    fn input_values(&self, n: usize) -> Vec<Value> {
+        self.inputs[..n]
+            .iter()
+            .map(|ident| Value::Term(Term::Var(ident.clone())))
+            .collect()
+    }
+
+    fn spread(&mut self, objects: Vec<Value>, quots: Vec<Value>) -> LowerResult<()> {
+        for (object, q) in objects.into_iter().zip(quots) {
+            self.stack.push(object);
+            self.call(q)?;
+        }
+        Ok(())
+    }
+
+    /// The quotations in a literal quotation of quotations, as taken by
+    /// `cleave` and `spread`.
+    fn quotations(&mut self) -> LowerResult<Vec<Value>> {
+        match self.pop()? {
+            Value::Quotation(params, quots) if params.is_empty() => Ok(quots
+                .iter()
+                .map(|q| match q {
+                    Atom::Quotation(body) => Value::Quotation(Vec::new(), body.to_vec()),
+                    atom => Value::Quotation(Vec::new(), vec![atom.clone()]),
+                })
+                .collect()),
+            _ => self.fail("expected a literal quotation of quotations".into()),
+        }
+    }
+
+    /// Lowers the body of a `record`, which runs in a new frame, into its
+    /// fields.
+    fn record(&mut self, body: &[Atom]) -> LowerResult<Vec<(Identifier, Rc<Term>)>> {
+        let stack = std::mem::take(&mut self.stack);
+        let allow_inputs = std::mem::replace(&mut self.allow_inputs, false);
+        self.scopes.push(HashMap::new());
+        let result = self.lower_atoms(body);
+        self.scopes.pop();
+        let values = std::mem::replace(&mut self.stack, stack);
+        self.allow_inputs = allow_inputs;
+        result?;
+
+        if !values.chunks_exact(2).remainder().is_empty() {
+            return self.fail("expected a field name and value for each field".into());
+        }
+        let mut fields: Vec<(Identifier, Rc<Term>)> = Vec::new();
+        let mut values = values.into_iter();
+        while let (Some(name), Some(value)) = (values.next(), values.next()) {
+            match name {
+                Value::Symbol(name) if fields.iter().any(|(field, _)| *field == name) => {
+                    return self.fail(format!("duplicate field {}", name))
+                }
+                Value::Symbol(name) => fields.push((name, Rc::new(self.term(value)?))),
+                _ => return self.fail("expected a symbol naming a field".into()),
+            }
+        }
+        Ok(fields)
+    }
+
+    fn builtin(&mut self, name: &'static str) -> LowerResult<()> {
+        if let Some(signature) = builtin_signature(name) {
+            let mut sides = signature.split("--");
+            let inputs: Vec<&str> = sides.next().unwrap().split_whitespace().collect();
+            let outputs: Vec<&str> = sides.next().unwrap().split_whitespace().collect();
+            let positions: Option<Vec<usize>> = outputs
+                .iter()
+                .map(|o| match primitive(o) {
+                    Some(_) => None,
+                    None => inputs.iter().position(|i| i == o),
+                })
+                .collect();
+            return match positions {
+                // Shuffles only move terms around.
+                Some(positions) => {
+                    let values = self.pop_n(inputs.len())?;
+                    for i in positions {
+                        self.stack.push(values[i].clone());
+                    }
+                    Ok(())
+                }
+                None if outputs.len() == 1 => self.apply(Term::Var(name.into()), inputs.len()),
+                None => self.fail(format!("{} can't be lowered", name)),
+            };
+        }
+
+        match name {
+            "cond" => {
+                let else_q = self.pop()?;
+                let if_q = self.pop()?;
+                let condition = self.pop_term()?;
+                self.branches(condition, vec![if_q, else_q])
+            }
+            "if" => {
+                let body = self.pop()?;
+                let condition = self.pop_term()?;
+                self.branches(
+                    condition,
+                    vec![body, Value::Quotation(Vec::new(), Vec::new())],
+                )
+            }
+            "record" => match self.pop()? {
+                Value::Quotation(params, body) if params.is_empty() => {
+                    let fields = self.record(&body)?;
+                    self.push(Term::Rcd(fields));
+                    Ok(())
+                }
+                _ => self.fail("expected a literal quotation of fields".into()),
+            },
+            "shuffle" => match self.pop()? {
+                Value::Str(spec) => {
+                    let (num_in, outputs) = parse_shuffle(&spec);
+                    let inputs = self.pop_n(num_in)?;
+                    for i in outputs {
+                        self.stack.push(inputs[i].clone());
+                    }
+                    Ok(())
+                }
+                _ => self.fail("expected a literal shuffle spec".into()),
+            },
+            "keep" => {
+                let q = self.pop()?;
+                let (num_in, _) = self.arity(&q)?;
+                let kept = self.pop_n(num_in)?;
+                let stack = std::mem::replace(&mut self.stack, kept.clone());
+                let allow_inputs = std::mem::replace(&mut self.allow_inputs, false);
+                let result = self.call(q).and_then(|_| self.pop());
+                self.allow_inputs = allow_inputs;
+                self.stack = stack;
+                self.stack.extend(kept);
+                self.stack.push(result?);
+                Ok(())
+            }
+            "keep2" => {
+                let q = self.pop()?;
+                let kept = self.pop_n(2)?;
+                self.stack.extend(kept.clone());
+                self.call(q)?;
+                self.stack.extend(kept);
+                Ok(())
+            }
+            "dip" | "2dip" => {
+                let q = self.pop()?;
+                let hidden = self.pop_n(if name == "dip" { 1 } else { 2 })?;
+                self.call(q)?;
+                self.stack.extend(hidden);
+                Ok(())
+            }
+            "bi" | "tri" => {
+                let quots = self.pop_n(if name == "bi" { 2 } else { 3 })?;
+                let x = self.pop()?;
+                self.spread(vec![x; quots.len()], quots)
+            }
+            "cleave" => {
+                let quots = self.quotations()?;
+                let x = self.pop()?;
+                self.spread(vec![x; quots.len()], quots)
+            }
+            "bi*" | "tri*" => {
+                let quots = self.pop_n(if name == "bi*" { 2 } else { 3 })?;
+                let objects = self.pop_n(quots.len())?;
+                self.spread(objects, quots)
+            }
+            "bi@" => {
+                let q = self.pop()?;
+                let objects = self.pop_n(2)?;
+                self.spread(objects, vec![q; 2])
+            }
+            "spread" => {
+                let quots = self.quotations()?;
+                let objects = self.pop_n(quots.len())?;
+                self.spread(objects, quots)
+            }
+            "napply" => match self.pop()? {
+                Value::Term(Term::Lit(Literal::Int(n))) if n >= 0 => {
+                    let q = self.pop()?;
+                    let objects = self.pop_n(n as usize)?;
+                    self.spread(objects, vec![q; n as usize])
+                }
+                _ => self.fail("expected a literal count".into()),
+            },
+            name => self.fail(format!("{} can't be lowered yet", name)),
+        }
+    }
+
+    fn lookup(&self, ident: &Identifier) -> Option<Binding> {
+        self.scopes
+            .iter()
+            .rev()
+            .find_map(|scope| scope.get(ident).cloned())
+    }
+
+    fn word(&mut self, ident: &Identifier) -> LowerResult<()> {
+        match self.lookup(ident) {
+            Some(Binding::Value(value)) => {
+                self.stack.push(value);
+                return Ok(());
+            }
+            Some(Binding::Callable(value)) => return self.call(value),
+            None => (),
+        }
+        if self.globals.contains(ident) {
+            self.push(Term::Var(ident.clone()));
+            return Ok(());
+        }
+        let num_in = match self.arities.get(ident) {
+            Some(&num_in) => num_in,
+            // Recursive functions and those defined later.
+            None => match arity_atom(&Atom::Plain(ident.clone()), &self.env) {
+                StaticArity::Fixed(num_in, 1) => num_in as usize,
+                StaticArity::Fixed(..) => {
+                    return self.fail(format!("{} must leave 1 value to be lowered", ident))
+                }
+                _ => return self.fail(format!("unknown word {}", ident)),
+            },
+        };
+        self.apply(Term::Var(ident.clone()), num_in)
+    }
+
+    fn lower_atom(&mut self, atom: &Atom) -> LowerResult<()> {
+        use Atom::*;
+        match atom {
+            Num(n) => {
+                self.push(Term::Lit(Literal::Int(*n)));
+                Ok(())
+            }
+            Bool(b) => {
+                self.push(Term::Lit(Literal::Bool(*b)));
+                Ok(())
+            }
+            Str(s) => {
+                self.stack.push(Value::Str(s.clone()));
+                Ok(())
+            }
+            Symbol(ident) => {
+                self.stack.push(Value::Symbol(ident.clone()));
+                Ok(())
+            }
+            Quotation(body) => {
+                self.stack.push(Value::Quotation(Vec::new(), body.to_vec()));
+                Ok(())
+            }
+            Function(params, body, _) => {
+                self.stack
+                    .push(Value::Quotation(params.clone(), nest_quotations(body)));
+                Ok(())
+            }
+            Op(op) => match builtin_name(op) {
+                Some(name) => self.builtin(name),
+                None => self.fail("this builtin can't be lowered".into()),
+            },
+            Plain(ident) => self.word(ident),
+            Call => {
+                let callee = self.pop()?;
+                self.call(callee)
+            }
+            Concat(words) => self.concat(words),
+            Select(field) => {
+                let rcd = self.pop_term()?;
+                self.push(Term::Sel(Rc::new(rcd), field.clone()));
+                Ok(())
+            }
+            DefVarLiteral | DefFnLiteral => match self.pop()? {
+                Value::Symbol(ident) => {
+                    let value = self.pop()?;
+                    let binding = if *atom == DefVarLiteral {
+                        Binding::Value(value)
+                    } else {
+                        Binding::Callable(value)
+                    };
+                    self.scopes.last_mut().unwrap().insert(ident, binding);
+                    Ok(())
+                }
+                _ => self.fail("expected a literal symbol".into()),
+            },
+            List(_) => self.fail("lists can't be lowered yet".into()),
+            Record(_) => self.fail("record values can't be lowered".into()),
+            DefVar | RedefVar => self.fail("definitions must be on their own line".into()),
+            QuotationStart | QuotationEnd => self.fail("unbalanced brackets".into()),
+        }
+    }
+
+    fn lower_atoms(&mut self, atoms: &[Atom]) -> LowerResult<()> {
+        for atom in atoms {
+            self.lower_atom(atom)?;
+        }
+        Ok(())
+    }
+
+    /// Lowers a concatenation like `a,b`, where each word takes its values
+    /// from below those taken by the words after it.
+    fn concat(&mut self, words: &[Atom]) -> LowerResult<()> {
+        let words: Vec<Value> = words
+            .iter()
+            .map(|word| Value::Quotation(Vec::new(), vec![word.clone()]))
+            .collect();
+        let mut segments = Vec::new();
+        for word in words.iter().rev() {
+            let (num_in, _) = self.arity(word)?;
+            segments.push(self.pop_n(num_in)?);
+        }
+        segments.reverse();
+
+        let stack = std::mem::take(&mut self.stack);
+        let mut outputs = Vec::new();
+        for (word, segment) in words.into_iter().zip(segments) {
+            self.stack = segment;
+            self.call(word)?;
+            outputs.append(&mut self.stack);
+        }
+        self.stack = stack;
+        self.stack.append(&mut outputs);
+        Ok(())
+    }
+
+    /// Lowers a `let` definition, which must leave a single value.
+    fn lower_let(&mut self, expr: &[Atom]) -> LowerResult<Term> {
+        let stack = std::mem::take(&mut self.stack);
+        self.scopes.push(HashMap::new());
+        let result = self.lower_atoms(expr);
+        self.scopes.pop();
+        let mut values = std::mem::replace(&mut self.stack, stack);
+        result?;
+        match values.len() {
+            1 => self.term(values.pop().unwrap()),
+            n => self.fail(format!("expected 1 value but {} were left", n)),
+        }
+    }
+
+    fn lower_line(&mut self, atoms: Vec<Atom>) -> LowerResult<Option<(bool, Identifier, Term)>> {
+        use Atom::*;
+        match atoms.as_slice() {
+            [Quotation(expr), Symbol(ident), DefVar]
+            | [Quotation(expr), Symbol(ident), RedefVar] => match expr.as_slice() {
+                [Function(params, body, declared)] => {
+                    let f = Function(params.clone(), body.clone(), *declared);
+                    self.env.redefine_var(ident, f);
+                    self.globals.remove(ident);
+                    let body = nest_quotations(body);
+                    let (num_in, term) = self.lower_function(params, &body, *declared)?;
+                    self.arities.insert(ident.clone(), num_in);
+                    Ok(Some((mentions(&body, ident), ident.clone(), term)))
+                }
+                expr => {
+                    let term = self.lower_let(&nest_quotations(expr))?;
+                    self.arities.remove(ident);
+                    self.globals.insert(ident.clone());
+                    self.env.redefine_var(ident, Num(0));
+                    Ok(Some((false, ident.clone(), term)))
+                }
+            },
+            atoms => {
+                self.lower_atoms(&nest_quotations(atoms))?;
+                Ok(None)
+            }
+        }
+    }
+}
+
+/// Whether `atoms` refer to `ident`, so that a function defined by them is
+/// recursive.
+fn mentions(atoms: &[Atom], ident: &Identifier) -> bool {
+    atoms.iter().any(|atom| match atom {
+        Atom::Plain(name) => name == ident,
+        Atom::Quotation(body) | Atom::Function(_, body, _) => mentions(body, ident),
+        Atom::Concat(words) => mentions(words, ident),
+        _ => false,
+    })
+}
+
+/// Lowers a program into its definitions. Other lines are lowered as if they
+/// were run, and the values they leave on the stack at the end become
+/// definitions named `_`, which can't be referred to.
+pub fn lower_program(program: &str) -> LowerResult<Pgrm> {
+    let mut lowerer = Lowerer::new();
+    let lines: Vec<Vec<Atom>> = program.split('\n').map(parse_line).collect();
+
+    // Functions may call those defined after them.
+    for atoms in &lines {
+        if let [Atom::Quotation(expr), Atom::Symbol(ident), Atom::DefVar] = atoms.as_slice() {
+            if let [f @ Atom::Function(..)] = expr.as_slice() {
+                lowerer.env.redefine_var(ident, f.clone());
+            }
+        }
+    }
+
+    let mut defs = Vec::new();
+    let mut fns = HashSet::new();
+    for (i, atoms) in lines.into_iter().enumerate() {
+        lowerer.line = i + 1;
+        let is_fn = match atoms.as_slice() {
+            [Atom::Quotation(expr), Atom::Symbol(_), _] => {
+                matches!(expr.as_slice(), [Atom::Function(..)])
+            }
+            _ => false,
+        };
+        if let Some(def) = lowerer.lower_line(atoms)? {
+            if is_fn {
+                fns.insert(defs.len());
+            }
+            defs.push(def);
+        }
+    }
+    for value in std::mem::take(&mut lowerer.stack) {
+        defs.push((false, "_".into(), lowerer.term(value)?));
+    }
+    Ok(Pgrm { defs, fns })
+}
+
+#[cfg(test)]
+fn var(ident: &str) -> Term {
+    Term::Var(ident.into())
+}
+
+#[cfg(test)]
+fn app(f: Term, args: Vec<Term>) -> Term {
+    args.into_iter()
+        .fold(f, |f, x| Term::App(Rc::new(f), Rc::new(x)))
+}
+
+#[cfg(test)]
+fn lam(param: &str, body: Term) -> Term {
+    Term::Lam(param.into(), Rc::new(body))
+}
+
+#[cfg(test)]
+fn int(n: i32) -> Term {
+    Term::Lit(Literal::Int(n))
+}
+
+#[test]
+fn test_lower_functions() {
+    let pgrm = lower_program(
+        r"
+fn inc = 1 +
+fn sq n = n n *
+fn f = dup [1 +] [2 *] bi* +
+fn fib = dup 2 <= [ drop 1 ] [ 1 - dup 1 - fib swap fib + ] cond
+let a = 2 sq
+a inc
+",
+    )
+    .unwrap();
+    let defs: Vec<(bool, &str, Term)> = pgrm
+        .defs
+        .iter()
+        .map(|(rec, ident, term)| (*rec, ident.as_ref(), term.clone()))
+        .collect();
+    assert_eq!((0..4).collect::<HashSet<_>>(), pgrm.fns);
+
+    let inc = lam("_0", app(var("+"), vec![var("_0"), int(1)]));
+    assert_eq!((false, "inc", inc), defs[0]);
+    let sq = lam("n", app(var("*"), vec![var("n"), var("n")]));
+    assert_eq!((false, "sq", sq), defs[1]);
+    let f = lam(
+        "_0",
+        app(
+            var("+"),
+            vec![
+                app(var("+"), vec![var("_0"), int(1)]),
+                app(var("*"), vec![var("_0"), int(2)]),
+            ],
+        ),
+    );
+    assert_eq!((false, "f", f), defs[2]);
+
+    let n_1 = app(var("-"), vec![var("_0"), int(1)]);
+    let n_2 = app(var("-"), vec![n_1.clone(), int(1)]);
+    let recurse = app(
+        var("+"),
+        vec![app(var("fib"), vec![n_2]), app(var("fib"), vec![n_1])],
+    );
+    let fib = lam(
+        "_0",
+        app(
+            var("cond"),
+            vec![app(var("<="), vec![var("_0"), int(2)]), int(1), recurse],
+        ),
+    );
+    assert_eq!((true, "fib", fib), defs[3]);
+
+    assert_eq!((false, "a", app(var("sq"), vec![int(2)])), defs[4]);
+    assert_eq!((false, "_", app(var("inc"), vec![var("a")])), defs[5]);
+}
+
+#[test]
+fn test_lower_call_param() {
+    let pgrm = lower_program(
+        r"
+fn apply x q = x q call
+fn apply_to q ( n q -- n ) = q call
+fn thunk q = q call
+",
+    )
+    .unwrap();
+    let apply = lam("x", lam("q", app(var("q"), vec![var("x")])));
+    assert_eq!((false, "apply".into(), apply), pgrm.defs[0]);
+    let apply_to = lam("_0", lam("q", app(var("q"), vec![var("_0")])));
+    assert_eq!((false, "apply_to".into(), apply_to), pgrm.defs[1]);
+    assert_eq!((false, "thunk".into(), lam("q", var("q"))), pgrm.defs[2]);
+}
+
+#[test]
+fn test_lower_errors() {
+    let error = |program: &str| lower_program(program).unwrap_err();
+    assert_eq!(
+        "line 1: a function must leave 1 value to be lowered, not 2",
+        error("fn f = 1 2").to_string()
+    );
+    assert_eq!("line 2: stack underflow", error("1\n+ +").to_string());
+    assert_eq!(
+        "line 1: only quotations and functions can be called",
+        error("fn f = 'a call").to_string()
+    );
+}
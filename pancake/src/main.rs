@@ -1,16 +1,68 @@
 use std::io;
 
+use pancake::check::{self, CheckError};
 use pancake::eval::eval_line;
+use pancake::ops;
+use pancake::smt;
 use pancake::types::{Atom, Op};
-use pancake::vm::{Context, Env};
+use pancake::vm::Identifier;
+use pancake::vm2::{Context, Env};
+
+type Checker = fn(&str) -> Result<Vec<(Identifier, String)>, CheckError>;
+
+/// Checks the program in `path` without running it, printing the stack effect
+/// of each function.
+fn check(path: &str, check_program: Checker) {
+    let program = std::fs::read_to_string(path).expect("Failed to read file");
+    match check_program(&program) {
+        Ok(effects) => {
+            for (ident, effect) in effects {
+                println!("{} {}", ident, effect);
+            }
+        }
+        Err(e) => {
+            eprintln!("{}: {}", path, e);
+            std::process::exit(1);
+        }
+    }
+}
+
+/// Prints each builtin whose name starts with `prefix`, with its stack effect
+/// and documentation.
+fn describe_builtins(prefix: &str) {
+    for name in ops::builtin_names() {
+        if name.starts_with(prefix) {
+            println!("{}", ops::describe(name).unwrap());
+        }
+    }
+}
 
 fn main() {
+    let args: Vec<String> = std::env::args().collect();
+    if let [_, flag] = args.as_slice() {
+        if flag == "--docs" {
+            return describe_builtins("");
+        }
+    }
+    if let [_, flag, path] = args.as_slice() {
+        match flag.as_str() {
+            "--check" => return check(path, check::check_program),
+            // Checks with a local SMT solver instead.
+            "--smt" => return check(path, smt::check_program),
+            _ => {}
+        }
+    }
+
     println!("Op size in bytes: {}", std::mem::size_of::<Op>());
-    println!("Context size in bytes: {}", std::mem::size_of::<Context>());
+    println!(
+        "Context size in bytes: {}",
+        std::mem::size_of::<Context<Atom>>()
+    );
     println!("Atom size in bytes: {}", std::mem::size_of::<Atom>());
     println!("Env size in bytes: {}", std::mem::size_of::<Env>());
 
     let mut env = Env::new();
+    env.relaxed = true;
     loop {
         let mut line = String::new();
 
@@ -18,6 +70,11 @@ fn main() {
             .read_line(&mut line)
             .expect("Failed to read line");
 
+        // `?prefix` lists the builtins starting with `prefix`.
+        if let Some(prefix) = line.trim().strip_prefix('?') {
+            describe_builtins(prefix);
+            continue;
+        }
         eval_line(&line, &mut env);
     }
 }
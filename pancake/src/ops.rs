@@ -1,74 +1,419 @@
 use crate::arity::arity_fn;
-use crate::eval::{eval_call, eval_call_function};
+use crate::eval::{eval_call, eval_call_function, eval_call_quotation};
 use crate::types::Op as O;
 use crate::types::Atom;
-use crate::vm::Env;
+use crate::types::NumType;
+use crate::vm::Identifier;
+use crate::vm2::Env;
 
 use Atom::*;
 
-use pancake_macro::{atomify, binops, shuffle};
+use pancake_macro::{atomify, binops, shuffles};
 
-pub fn get_arithmetic_op(op: &str) -> Option<O> {
+use im_rc::Vector;
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::rc::Rc;
+
+/// Takes the atoms out of a body, only copying them if it's shared.
+pub fn unshare(atoms: Rc<Vec<Atom>>) -> Vec<Atom> {
+    Rc::try_unwrap(atoms).unwrap_or_else(|atoms| (*atoms).clone())
+}
+
+/// The quotations in a quotation or list, as taken by `cleave` and `spread`.
+fn quotations(seq: Atom) -> Vec<Atom> {
+    match seq {
+        Quotation(q) => unshare(q),
+        List(list) => list.into_iter().collect(),
+        _ => panic!("Expected a quotation or list of quotations."),
+    }
+}
+
+/// Pairs up values like `'a 1 'b 2` into the fields of a record, sorted by
+/// name.
+pub fn record_fields(values: Vec<Atom>) -> Vec<(Identifier, Atom)> {
+    if !values.chunks_exact(2).remainder().is_empty() {
+        panic!("Expected a field name and value for each field of a record.");
+    }
+    let mut fields: Vec<(Identifier, Atom)> = Vec::with_capacity(values.len() / 2);
+    let mut values = values.into_iter();
+    while let (Some(name), Some(value)) = (values.next(), values.next()) {
+        match name {
+            Symbol(name) if fields.iter().any(|(field, _)| *field == name) => {
+                panic!("Duplicate field {} in record.", name)
+            }
+            Symbol(name) => fields.push((name, value)),
+            name => panic!("Expected a symbol naming a field but got {:?}.", name),
+        }
+    }
+    fields.sort_by(|(a, _), (b, _)| a.cmp(b));
+    fields
+}
+
+/// Takes `field` from a record, or the element at index `field` of a list.
+pub fn select(value: Atom, field: &Identifier) -> Atom {
+    match value {
+        Record(fields) => match fields.into_iter().find(|(name, _)| name == field) {
+            Some((_, value)) => value,
+            None => panic!("Record has no field {}.", field),
+        },
+        List(list) => match field.parse::<usize>().ok().and_then(|i| list.get(i)) {
+            Some(value) => value.clone(),
+            None => panic!(
+                "Cannot index list of length {} with .{}.",
+                list.len(),
+                field
+            ),
+        },
+        value => panic!("Cannot select .{} from {:?}.", field, value),
+    }
+}
+
+/// Pushes each object in turn and calls the corresponding quotation on it.
+fn spread(objects: Vec<Atom>, quots: Vec<Atom>, env: &mut Env) {
+    for (object, q) in objects.into_iter().zip(quots) {
+        env.push_atom(object);
+        eval_call_quotation(q, env);
+    }
+}
+
+/// The stack effect and documentation of each builtin. Quotations are written
+/// with their own effects, like `[ a -- b ]`.
+pub const DOCS: [(&str, &str, &str); 59] = [
+    ("+", "int int -- int", "Adds two numbers."),
+    (
+        "-",
+        "int int -- int",
+        "Subtracts the top number from the one below it.",
+    ),
+    ("*", "int int -- int", "Multiplies two numbers."),
+    (
+        "/",
+        "int int -- int",
+        "Divides the second number by the top one, rounding towards zero.",
+    ),
+    (
+        "%",
+        "int int -- int",
+        "The remainder of dividing the second number by the top one.",
+    ),
+    (
+        "<",
+        "int int -- bool",
+        "Whether the second number is less than the top one.",
+    ),
+    (
+        ">",
+        "int int -- bool",
+        "Whether the second number is greater than the top one.",
+    ),
+    (
+        "<=",
+        "int int -- bool",
+        "Whether the second number is at most the top one.",
+    ),
+    (
+        ">=",
+        "int int -- bool",
+        "Whether the second number is at least the top one.",
+    ),
+    ("==", "int int -- bool", "Whether two numbers are equal."),
+    ("!=", "int int -- bool", "Whether two numbers differ."),
+    (
+        "and",
+        "bool bool -- bool",
+        "Whether both booleans are true.",
+    ),
+    ("or", "bool bool -- bool", "Whether either boolean is true."),
+    ("not", "bool -- bool", "Negates a boolean."),
+    (
+        "cond",
+        "bool [ -- ] [ -- ] --",
+        "Calls the first quotation if the condition is true, and the second otherwise.",
+    ),
+    (
+        "if",
+        "bool [ -- ] --",
+        "Calls the quotation if the condition is true.",
+    ),
+    (
+        "list",
+        "[ -- ] -- list",
+        "Makes a list of what the quotation leaves on a new stack.",
+    ),
+    (
+        "record",
+        "[ -- ] -- record",
+        "Makes a record from the names and values the quotation leaves on a new stack.",
+    ),
+    (
+        "map",
+        "list [ a -- b ] -- list",
+        "Calls the quotation on each element of the list, making a list of the results.",
+    ),
+    (
+        "reduce_inner",
+        "list [ a a -- a ] -- list",
+        "Folds the list with the quotation, leaving a list of what remains.",
+    ),
+    ("splat", "list -- ...", "Pushes each element of the list."),
+    (
+        "repeat",
+        "[ -- ] int --",
+        "Calls the quotation the given number of times.",
+    ),
+    (
+        "for_else",
+        "[ -- ] --",
+        "Calls the quotation if no `if` in the preceding loop ran its body.",
+    ),
+    (
+        "for_if",
+        "[ -- ] --",
+        "Calls the quotation if an `if` in the preceding loop ran its body.",
+    ),
+    (
+        "shuffle",
+        "... str -- ...",
+        "Rearranges the stack as described by a spec like \"a b -- b a\".",
+    ),
+    ("print", "a --", "Prints the top value."),
+    ("debug", "--", "Prints the whole environment."),
+    (
+        "get",
+        "sym -- a",
+        "The value of the named variable, without calling it.",
+    ),
+    (
+        "keep",
+        "x [ x -- y ] -- x y",
+        "Calls the quotation, keeping its inputs below its output.",
+    ),
+    (
+        "keep2",
+        "x y [ x y -- z ] -- z x y",
+        "Calls the quotation on two values, keeping them above its outputs.",
+    ),
+    (
+        "dip",
+        "x [ -- ] -- x",
+        "Calls the quotation under the top value.",
+    ),
+    (
+        "2dip",
+        "x y [ -- ] -- x y",
+        "Calls the quotation under the top two values.",
+    ),
+    (
+        "bi",
+        "x [ x -- ] [ x -- ] --",
+        "Calls both quotations on the value.",
+    ),
+    (
+        "tri",
+        "x [ x -- ] [ x -- ] [ x -- ] --",
+        "Calls all three quotations on the value.",
+    ),
+    (
+        "cleave",
+        "x quots --",
+        "Calls each quotation in a quotation or list on the value.",
+    ),
+    (
+        "bi*",
+        "x y [ x -- ] [ y -- ] --",
+        "Calls the first quotation on x and the second on y.",
+    ),
+    (
+        "tri*",
+        "x y z [ x -- ] [ y -- ] [ z -- ] --",
+        "Calls each quotation on the corresponding value.",
+    ),
+    (
+        "bi@",
+        "x y [ a -- ] --",
+        "Calls the quotation on both values.",
+    ),
+    (
+        "spread",
+        "... quots --",
+        "Calls each quotation in a quotation or list on the corresponding value.",
+    ),
+    (
+        "napply",
+        "... [ a -- ] int --",
+        "Calls the quotation on each of the top n values.",
+    ),
+    (
+        "at",
+        "list int -- a",
+        "The element of the list at the index.",
+    ),
+    (
+        "append",
+        "list a -- list",
+        "The list with the value added to its end.",
+    ),
+    (
+        "set_at",
+        "list int a -- list",
+        "The list with the element at the index replaced by the value.",
+    ),
+    (
+        "cat",
+        "list list -- list",
+        "The elements of both lists, in order.",
+    ),
+    ("drop", "a --", "Discards the top value."),
+    ("2drop", "a b --", "Discards the top two values."),
+    ("dup", "a -- a a", "Duplicates the top value."),
+    ("2dup", "a b -- a b a b", "Duplicates the top two values."),
+    ("dupd", "a b -- a a b", "Duplicates the second value."),
+    ("swap", "a b -- b a", "Swaps the top two values."),
+    (
+        "2swap",
+        "a b c d -- c d a b",
+        "Swaps the top two pairs of values.",
+    ),
+    (
+        "swapd",
+        "a b c -- b a c",
+        "Swaps the second and third values.",
+    ),
+    (
+        "over",
+        "a b -- a b a",
+        "Copies the second value to the top.",
+    ),
+    (
+        "pick",
+        "a b c -- a b c a",
+        "Copies the third value to the top.",
+    ),
+    ("nip", "a b -- b", "Discards the second value."),
+    (
+        "tuck",
+        "a b -- b a b",
+        "Copies the top value under the second.",
+    ),
+    (
+        "rot3",
+        "a b c -- b c a",
+        "Moves the third value to the top.",
+    ),
+    (
+        "-rot",
+        "a b c -- c a b",
+        "Moves the top value below the next two.",
+    ),
+    (
+        "rot4",
+        "a b c d -- b c d a",
+        "Moves the fourth value to the top.",
+    ),
+];
+
+/// Parses a shuffle spec like `a b -- b a` into the positions of the inputs
+/// which make up the outputs, along with the number of inputs.
+pub fn parse_shuffle(spec: &str) -> (usize, Vec<usize>) {
+    let mut sides = spec.split("--");
+    let (inputs, outputs) = match (sides.next(), sides.next(), sides.next()) {
+        (Some(inputs), Some(outputs), None) => (inputs, outputs),
+        _ => panic!(
+            "Expected a shuffle of the form 'a b -- b a' but got '{}'.",
+            spec
+        ),
+    };
+    let inputs: Vec<&str> = inputs.split_whitespace().collect();
+    let outputs = outputs
+        .split_whitespace()
+        .map(
+            |name| match inputs.iter().position(|&input| input == name) {
+                Some(i) => i,
+                None => panic!("Unknown name {} in shuffle '{}'.", name, spec),
+            },
+        )
+        .collect();
+    (inputs.len(), outputs)
+}
+
+/// The standard stack shuffles. Each of these can also be written at runtime as
+/// e.g. `"a b -- b a" shuffle`.
+fn shuffle_ops() -> Vec<O> {
+    shuffles!(
+        "drop" (a --)
+        "2drop" (a b --)
+        "dup" (a -- a a)
+        "2dup" (a b -- a b a b)
+        "dupd" (a b -- a a b)
+        "swap" (a b -- b a)
+        "2swap" (a b c d -- c d a b)
+        "swapd" (a b c -- b a c)
+        "over" (a b -- a b a)
+        "pick" (a b c -- a b c a)
+        "nip" (a b -- b)
+        "tuck" (a b -- b a b)
+        "rot3" (a b c -- b c a)
+        "-rot" (a b c -- c a b)
+        "rot4" (a b c d -- b c d a)
+    )
+}
+
+fn arithmetic_ops() -> Vec<O> {
     binops!(a"+" a"-" a"*" a"/" a"%" c"<" c">" c"<=" c">=" c"==" c"!=")
 }
 
-pub fn get_boolean_op(op: &str) -> Option<O> {
-    Some(match op {
-        "and" => atomify!("and" ((a:Bool,b:Bool)->Bool) {a && b}),
-        "or" => atomify!("or" ((a:Bool,b:Bool)->Bool) {a || b}),
-        "cond" => atomify!("cond" ((cond:Bool, if_q:Quotation, else_q:Quotation)) {
+fn boolean_ops() -> Vec<O> {
+    vec![
+        atomify!("and" ((a:Bool,b:Bool)->Bool) {a && b}),
+        atomify!("or" ((a:Bool,b:Bool)->Bool) {a || b}),
+        atomify!("cond" ((cond:Bool, if_q:Quotation, else_q:Quotation)) {
             let q = if cond { if_q } else { else_q };
-            eval_call(q, env);
+            eval_call(&q, env);
         }),
-        "not" => atomify!("not" ((a:Bool)->Bool) {!a}),
-        "if" => atomify!("if" ((cond:Bool, body_q:Quotation)) {
+        atomify!("not" ((a:Bool)->Bool) {!a}),
+        atomify!("if" ((cond:Bool, body_q:Quotation)) {
             if env.loop_like {
                 env.using_for_else = true;
             }
             if cond {
-                eval_call(body_q, env);
+                eval_call(&body_q, env);
                 if env.loop_like {
                     env.for_else = false;
                 }
             }
         }),
-        _ => {
-            return None;
-        }
-    })
+    ]
 }
 
-pub fn get_stack_op(op: &str) -> Option<O> {
-    Some(match op {
-        "drop" => shuffle!(_a -- ),
-        "swap" => shuffle!(a b -- b a),
-        "rot3" => shuffle!(a b c -- b c a),
-        "dup" => O::new(
-            |env| {
-                let a = env.pop_atom();
-                env.push_atom(a.clone());
-                env.push_atom(a);
-            },
-            Some((1, 2)),
-        ),
-        "list" => atomify!("list" ((q:Quotation)->List) {
+fn stack_ops() -> Vec<O> {
+    vec![
+        atomify!("list" ((q:Quotation)->List) {
+            {
+                env.push_blank(false);
+                eval_call(&q, env);
+                let stack = env.pop().unwrap().stack;
+                Vector::from(stack)
+            }
+        }),
+        atomify!("record" ((q:Quotation)->Record) {
             {
                 env.push_blank(false);
-                eval_call(q, env);
+                eval_call(&q, env);
                 let stack = env.pop().unwrap().stack;
-                stack
+                record_fields(stack)
             }
         }),
-        "map" => atomify!("map" ((list:List, q:Quotation)->List) {
+        atomify!("map" ((list:List, q:Quotation)->List) {
             {
                 env.for_else = true;
                 env.loop_like = true;
-                let new_list = list
+                let new_list: Vector<Atom> = list
                     .iter()
                     .map(|atom| {
                         env.push_blank(false);
                         env.push_atom(atom.clone());
-                        eval_call(q.clone(), env);
+                        eval_call(&q, env);
                         env.pop().unwrap().stack.pop().unwrap()
                     })
                     .collect();
@@ -76,30 +421,31 @@ pub fn get_stack_op(op: &str) -> Option<O> {
                 new_list
             }
         }),
-        "reduce_inner" => atomify!("reduce_inner" ((list:List, q:Quotation)->List) {
+        atomify!("reduce_inner" ((list:List, q:Quotation)->List) {
             {
                 env.for_else = true;
                 env.loop_like = true;
                 env.push_blank(false);
-                let mut list = list.drain(..);
+                let mut list = list.into_iter();
                 let first = list.next();
                 if let Some(a) = first {
                     env.push_atom(a);
                     for atom in list {
                         env.push_atom(atom);
-                        eval_call(q.clone(), env);
+                        eval_call(&q, env);
                     }
                     let res: Vec<Atom> = env.pop().unwrap().stack;
                     env.loop_like = false;
-                    res
+                    Vector::from(res)
                 } else {
                     env.loop_like = false;
-                    Vec::new()
+                    Vector::new()
                 }
             }
         }),
-        "splat" => atomify!("splat" ((list:List)) {env.append_atoms(list)}),
-        "repeat" => O::new(
+        atomify!("splat" ((list:List)) {env.append_atoms(list.into_iter().collect())}),
+        O::new(
+            "repeat",
             |env| {
                 env.for_else = true;
                 env.loop_like = true;
@@ -108,7 +454,7 @@ pub fn get_stack_op(op: &str) -> Option<O> {
                 let (p, b) = {
                     if let Quotation(q) = q {
                         (Vec::new(), q)
-                    } else if let Function(p, b) = q {
+                    } else if let Function(p, b, _) = q {
                         (p, b)
                     } else {
                         panic!("Tried to call a non-quotation.");
@@ -116,14 +462,15 @@ pub fn get_stack_op(op: &str) -> Option<O> {
                 };
                 if let Atom::Num(times) = n {
                     for _ in 0..times {
-                        eval_call_function(&p, b.clone(), env);
+                        eval_call_function(&p, &b, env);
                     }
                 }
                 env.loop_like = false;
             },
             None,
         ),
-        "for_else" => O::new(
+        O::new(
+            "for_else",
             |env| {
                 if !env.using_for_else {
                     panic!("No conditionals used by loop-like combinator.")
@@ -131,13 +478,14 @@ pub fn get_stack_op(op: &str) -> Option<O> {
                 let body = env.pop_atom();
                 if let Atom::Quotation(body_q) = body {
                     if env.for_else {
-                        eval_call(body_q, env);
+                        eval_call(&body_q, env);
                     }
                 }
             },
             None,
         ),
-        "for_if" => O::new(
+        O::new(
+            "for_if",
             |env| {
                 if !env.using_for_else {
                     panic!("No conditionals used by loop-like combinator.")
@@ -145,26 +493,44 @@ pub fn get_stack_op(op: &str) -> Option<O> {
                 let body = env.pop_atom();
                 if let Atom::Quotation(body_q) = body {
                     if !env.for_else {
-                        eval_call(body_q, env);
+                        eval_call(&body_q, env);
+                    }
+                }
+            },
+            None,
+        ),
+        O::new(
+            "shuffle",
+            |env| {
+                if let Str(spec) = env.pop_atom() {
+                    let (num_in, outputs) = parse_shuffle(&spec);
+                    let inputs = env.pop_atoms(num_in);
+                    for i in outputs {
+                        env.push_atom(inputs[i].clone());
                     }
+                } else {
+                    panic!("Expected '<spec> shuffle'.")
                 }
             },
             None,
         ),
-        "print" => O::new(
+        O::new(
+            "print",
             |env| {
                 println!("{:#?}", env.pop_atom());
             },
             Some((1, 0)),
         ),
-        "debug" => O::new(
+        O::new(
+            "debug",
             |env| {
                 println!("{:#?}", env);
             },
             Some((0, 0)),
         ),
         // TODO: probably want a different syntax for getting functions literally
-        "get" => O::new(
+        O::new(
+            "get",
             |env| {
                 if let Atom::Symbol(ident) = env.pop_atom() {
                     match env.find_var(&ident) {
@@ -173,12 +539,13 @@ pub fn get_stack_op(op: &str) -> Option<O> {
                     }
                 }
             },
-            None,
+            Some((1, 1)),
         ),
-        "keep" => O::new(
+        O::new(
+            "keep",
             |env| {
                 let q = env.pop_atom();
-                let arity = arity_fn(&q, env);
+                let arity = arity_fn(&q, env).fixed();
                 if let Some((num_in, _)) = arity {
                     let last_n: Vec<Atom>;
                     {
@@ -187,10 +554,10 @@ pub fn get_stack_op(op: &str) -> Option<O> {
                     }
                     env.push_blank(false);
                     env.last_frame().stack = last_n;
-                    if let Function(p, q) = q {
-                        eval_call_function(&p, q, env);
+                    if let Function(p, q, _) = q {
+                        eval_call_function(&p, &q, env);
                     } else if let Quotation(q) = q {
-                        eval_call(q, env);
+                        eval_call(&q, env);
                     } else {
                         panic!("Tried to call a non-quotation.")
                     }
@@ -203,14 +570,257 @@ pub fn get_stack_op(op: &str) -> Option<O> {
             },
             None,
         ),
-        "at" => atomify!("at" ((list:List,n:Num)->Any) {
+        O::new(
+            "keep2",
+            |env| {
+                let q = env.pop_atom();
+                let kept = env.pop_atoms(2);
+                env.append_atoms(kept.clone());
+                eval_call_quotation(q, env);
+                env.append_atoms(kept);
+            },
+            None,
+        ),
+        O::new(
+            "dip",
+            |env| {
+                let q = env.pop_atom();
+                let x = env.pop_atom();
+                eval_call_quotation(q, env);
+                env.push_atom(x);
+            },
+            None,
+        ),
+        O::new(
+            "2dip",
+            |env| {
+                let q = env.pop_atom();
+                let hidden = env.pop_atoms(2);
+                eval_call_quotation(q, env);
+                env.append_atoms(hidden);
+            },
+            None,
+        ),
+        O::new(
+            "bi",
+            |env| {
+                let quots = env.pop_atoms(2);
+                let x = env.pop_atom();
+                spread(vec![x; 2], quots, env);
+            },
+            None,
+        ),
+        O::new(
+            "tri",
+            |env| {
+                let quots = env.pop_atoms(3);
+                let x = env.pop_atom();
+                spread(vec![x; 3], quots, env);
+            },
+            None,
+        ),
+        O::new(
+            "cleave",
+            |env| {
+                let quots = quotations(env.pop_atom());
+                let x = env.pop_atom();
+                spread(vec![x; quots.len()], quots, env);
+            },
+            None,
+        ),
+        O::new(
+            "bi*",
+            |env| {
+                let quots = env.pop_atoms(2);
+                let objects = env.pop_atoms(2);
+                spread(objects, quots, env);
+            },
+            None,
+        ),
+        O::new(
+            "tri*",
+            |env| {
+                let quots = env.pop_atoms(3);
+                let objects = env.pop_atoms(3);
+                spread(objects, quots, env);
+            },
+            None,
+        ),
+        O::new(
+            "bi@",
+            |env| {
+                let q = env.pop_atom();
+                let objects = env.pop_atoms(2);
+                spread(objects, vec![q; 2], env);
+            },
+            None,
+        ),
+        O::new(
+            "spread",
+            |env| {
+                let quots = quotations(env.pop_atom());
+                let objects = env.pop_atoms(quots.len());
+                spread(objects, quots, env);
+            },
+            None,
+        ),
+        O::new(
+            "napply",
+            |env| {
+                let n = env.pop_atom();
+                let q = env.pop_atom();
+                if let Atom::Num(n @ 0..=NumType::MAX) = n {
+                    let objects = env.pop_atoms(n as usize);
+                    spread(objects, vec![q; n as usize], env);
+                } else {
+                    panic!("Expected '<quotation> <n> napply'.")
+                }
+            },
+            None,
+        ),
+        atomify!("at" ((list:List,n:Num)->Any) {
             list[n as usize].clone()
         }),
-        "append" => unimplemented!(),
-        "curry" => unimplemented!(),
-        "cat" => unimplemented!(),
-        _ => {
-            return None;
+        O::new(
+            "append",
+            |env| {
+                let a = env.pop_atom();
+                if let List(mut list) = env.pop_atom() {
+                    list.push_back(a);
+                    env.push_atom(List(list));
+                } else {
+                    panic!("Expected '<list> <value> append'.")
+                }
+            },
+            Some((2, 1)),
+        ),
+        O::new(
+            "set_at",
+            |env| {
+                let a = env.pop_atom();
+                let n = env.pop_atom();
+                if let (List(mut list), Num(n)) = (env.pop_atom(), n) {
+                    if n < 0 || n as usize >= list.len() {
+                        panic!("Cannot set index {} of list of length {}.", n, list.len());
+                    }
+                    list.set(n as usize, a);
+                    env.push_atom(List(list));
+                } else {
+                    panic!("Expected '<list> <index> <value> set_at'.")
+                }
+            },
+            Some((3, 1)),
+        ),
+        atomify!("cat" ((list:List,other:List)->List) {
+            {
+                list.append(other);
+                list
+            }
+        }),
+    ]
+}
+
+thread_local! {
+    /// Every builtin by name, built once per thread since ops aren't `Send`.
+    static REGISTRY: RefCell<HashMap<&'static str, O>> = RefCell::new(
+        arithmetic_ops()
+            .into_iter()
+            .chain(boolean_ops())
+            .chain(stack_ops())
+            .chain(shuffle_ops())
+            .map(|op| (op.name, op))
+            .collect()
+    );
+    static SHUFFLES: Vec<&'static str> = shuffle_ops().iter().map(|op| op.name).collect();
+}
+
+/// The builtin called `name`, if there is one.
+pub fn get_op(name: &str) -> Option<O> {
+    REGISTRY.with(|registry| registry.borrow().get(name).cloned())
+}
+
+pub fn is_builtin(name: &str) -> bool {
+    REGISTRY.with(|registry| registry.borrow().contains_key(name))
+}
+
+/// Whether `name` is one of the standard shuffles, whose effect is its
+/// signature.
+pub fn is_shuffle(name: &str) -> bool {
+    SHUFFLES.with(|shuffles| shuffles.contains(&name))
+}
+
+/// The names of all builtins, sorted.
+pub fn builtin_names() -> Vec<&'static str> {
+    let mut names: Vec<&'static str> =
+        REGISTRY.with(|registry| registry.borrow().keys().copied().collect());
+    names.sort_unstable();
+    names
+}
+
+/// Adds a builtin, which lines parsed on this thread afterwards can use.
+/// Builtins can't be replaced, since the checker and compiler know them by
+/// name.
+pub fn register(op: O) {
+    REGISTRY.with(|registry| {
+        let mut registry = registry.borrow_mut();
+        if registry.contains_key(op.name) {
+            panic!("Attempted to redefine builtin {}.", op.name);
         }
+        registry.insert(op.name, op);
     })
 }
+
+/// A line describing the builtin called `name`, like `dup ( a -- a a )
+/// Duplicates the top value.`
+pub fn describe(name: &str) -> Option<String> {
+    let op = get_op(name)?;
+    let mut line = String::from(op.name);
+    if !op.signature.is_empty() {
+        line += &format!(" ( {} )", op.signature);
+    }
+    if !op.doc.is_empty() {
+        line += &format!(" {}", op.doc);
+    }
+    Some(line)
+}
+
+#[test]
+fn test_op_docs() {
+    use crate::check::{builtin_signature, COMBINATORS};
+
+    for &(name, signature, doc) in DOCS.iter() {
+        let op = get_op(name).unwrap_or_else(|| panic!("{} isn't a builtin", name));
+        assert_eq!((name, signature, doc), (op.name, op.signature, op.doc));
+        let checked = COMBINATORS.contains(&name) || builtin_signature(name) == Some(signature);
+        assert!(checked, "{} isn't known to the checker", name);
+    }
+
+    let dup = get_op("dup").unwrap();
+    assert_eq!(dup, shuffle_ops()[2]);
+    assert_eq!("dup", dup.to_string());
+    assert_eq!("[Num(1), Op(dup)]", format!("{:?}", vec![Num(1), Op(dup)]));
+    assert_eq!(
+        Some("dup ( a -- a a ) Duplicates the top value.".to_string()),
+        describe("dup")
+    );
+    assert_eq!(DOCS.len(), builtin_names().len());
+}
+
+#[test]
+fn test_register() {
+    assert!(get_op("twice").is_none());
+    register(O::new(
+        "twice",
+        |env| {
+            let a = env.pop_atom();
+            env.push_atom(a.clone());
+            env.push_atom(a);
+        },
+        Some((1, 2)),
+    ));
+    assert!(is_builtin("twice"));
+    assert!(builtin_names().contains(&"twice"));
+    assert_eq!(Some("twice".to_string()), describe("twice"));
+    assert_eq!(None, crate::check::builtin_signature("twice"));
+    assert!(is_shuffle("rot3") && !is_shuffle("twice"));
+}
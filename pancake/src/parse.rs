@@ -1,11 +1,12 @@
 use crate::ops::*;
 use crate::types::{Atom, NumType};
+use crate::vm::Identifier;
 
 use inlinable_string::InlinableString;
 
 use nom::{
     branch::alt,
-    bytes::complete::tag,
+    bytes::complete::{tag, take_till},
     character::complete::char as nomchar,
     character::complete::*,
     combinator::{all_consuming, map, map_res, not, opt, recognize},
@@ -14,17 +15,16 @@ use nom::{
     IResult,
 };
 
+use std::rc::Rc;
+
 fn parse_num_nom_(token: &str) -> IResult<&str, Atom> {
-    map(
-        map_res(recognize(tuple((opt(nomchar('-')), digit1))), |s: &str| {
-            s.parse::<NumType>()
-        }),
-        Atom::Num,
-    )(&token)
+    // Words such as `2dip` start with digits.
+    let num = terminated(recognize(tuple((opt(nomchar('-')), digit1))), not(alpha1));
+    map(map_res(num, |s: &str| s.parse::<NumType>()), Atom::Num)(token)
 }
 
 fn parse_op_(token: &str) -> Atom {
-    if let Some(op) = get_arithmetic_op(token) {
+    if let Some(op) = get_op(token) {
         Atom::Op(op)
     } else {
         panic!("Unrecognized operator '{}'", token);
@@ -38,21 +38,13 @@ fn parse_special_ident_(token: &str) -> Option<Atom> {
         "fn" => Atom::DefFnLiteral,
         "true" => Atom::Bool(true),
         "false" => Atom::Bool(false),
-        s => {
-            if let Some(op) = get_boolean_op(s) {
-                Atom::Op(op)
-            } else if let Some(op) = get_stack_op(s) {
-                Atom::Op(op)
-            } else {
-                return None;
-            }
-        }
+        s => Atom::Op(get_op(s)?),
     })
 }
 
 fn parse_op_nom_(token: &str) -> IResult<&str, Atom> {
     map(
-        recognize(many1(one_of("+!@#$%^&*()<>,-=?/.|"))),
+        recognize(many1(one_of("+!@#$%^&*()<>-=?/.|"))),
         |s: &str| parse_op_(s),
     )(token)
 }
@@ -60,12 +52,16 @@ fn parse_op_nom_(token: &str) -> IResult<&str, Atom> {
 fn recognize_ident_nom_(token: &str) -> IResult<&str, &str> {
     let valid_char = alt((alphanumeric1, tag("_")));
     let valid_chars = many0(valid_char);
-    let valid_starting = tuple((alpha1, valid_chars));
+    // Allow Factor-style names like `2dip`, `bi*` and `bi@`.
+    let valid_suffix = many0(one_of("*@"));
+    let valid_starting = tuple((digit0, alpha1, valid_chars, valid_suffix));
     recognize(valid_starting)(token)
 }
 
 fn parse_special_ident_nom_(token: &str) -> IResult<&str, Atom> {
-    map_res(recognize_ident_nom_, |s: &str| {
+    // Builtin words may also start with a `-`, like `-rot`.
+    let word = recognize(tuple((opt(nomchar('-')), recognize_ident_nom_)));
+    map_res(word, |s: &str| {
         if let Some(a) = parse_special_ident_(s) {
             Ok(a)
         } else {
@@ -78,7 +74,7 @@ fn parse_ident_nom_(token: &str) -> IResult<&str, Atom> {
     map_res(recognize_ident_nom_, |s: &str| {
         let special = not(parse_special_ident_nom_)(s);
         if special.is_ok() {
-            Ok(Atom::Plain(InlinableString::from(s)))
+            Ok(Atom::Plain(Identifier::from(s)))
         } else {
             Err("Unexpected reserved identifier.")
         }
@@ -95,6 +91,39 @@ fn parse_symbol_nom_(token: &str) -> IResult<&str, Atom> {
     })(token)
 }
 
+fn parse_str_nom_(token: &str) -> IResult<&str, Atom> {
+    map(
+        delimited(nomchar('"'), take_till(|c| c == '"'), nomchar('"')),
+        |s: &str| Atom::Str(InlinableString::from(s)),
+    )(token)
+}
+
+/// Parse a selection like `.a`, which takes a field of a record, or `.0`,
+/// which indexes a list.
+fn parse_select_nom_(token: &str) -> IResult<&str, Atom> {
+    map(
+        preceded(nomchar('.'), alt((recognize_ident_nom_, digit1))),
+        |s: &str| Atom::Select(Identifier::from(s)),
+    )(token)
+}
+
+fn parse_word_nom_(token: &str) -> IResult<&str, Atom> {
+    alt((
+        parse_select_nom_,
+        parse_special_ident_nom_,
+        parse_op_nom_,
+        parse_ident_nom_,
+    ))(token)
+}
+
+/// Parse a concatenation of words like `+,+`.
+fn parse_concat_nom_(token: &str) -> IResult<&str, Atom> {
+    map(
+        tuple((parse_word_nom_, many1(preceded(nomchar(','), parse_word_nom_)))),
+        |(first, rest)| Atom::Concat(std::iter::once(first).chain(rest).collect()),
+    )(token)
+}
+
 fn parse_bracket_nom_(token: &str) -> IResult<&str, Atom> {
     map(alt((nomchar('['), nomchar(']'))), |c: char| match c {
         '[' => Atom::QuotationStart,
@@ -106,10 +135,13 @@ fn parse_bracket_nom_(token: &str) -> IResult<&str, Atom> {
 fn parse_token_nom_(token: &str) -> IResult<&str, Atom> {
     alt((
         parse_bracket_nom_,
+        parse_str_nom_,
+        parse_concat_nom_,
+        parse_select_nom_,
         parse_num_nom_,
         parse_symbol_nom_,
-        parse_op_nom_,
         parse_special_ident_nom_,
+        parse_op_nom_,
         parse_ident_nom_,
     ))(token)
 }
@@ -118,15 +150,13 @@ pub fn parse_token(token: &str) -> Atom {
     parse_token_nom_(token).unwrap().1
 }
 
-/// Parse a line definition of a variable like `let a = 100` or `fn inc = 1 +`.
+/// Parse a line definition of a variable like `let a = 100` or `fn inc = 1 +`,
+/// or a redefinition like `redef a = 200` or `fn! inc = 2 +`.
 pub fn parse_def(line: &str) -> Option<Vec<Atom>> {
-    if let Some(a) = parse_let(line) {
-        Some(a)
-    } else if let Some(a) = parse_fn(line) {
-        Some(a)
-    } else {
-        None
-    }
+    parse_let("let", Atom::DefVar, line)
+        .or_else(|| parse_let("redef", Atom::RedefVar, line))
+        .or_else(|| parse_fn("fn", Atom::DefVar, line))
+        .or_else(|| parse_fn("fn!", Atom::RedefVar, line))
 }
 
 fn parse_expr(expr: &str) -> Vec<Atom> {
@@ -141,8 +171,8 @@ fn parse_expr(expr: &str) -> Vec<Atom> {
     v
 }
 
-fn parse_let(line: &str) -> Option<Vec<Atom>> {
-    let prefix = tuple((tag("let"), multispace1));
+fn parse_let(keyword: &str, def: Atom, line: &str) -> Option<Vec<Atom>> {
+    let prefix = tuple((tag(keyword), multispace1));
     let suffix = tuple((multispace1, tag("=")));
     let parser = delimited(prefix, parse_ident_nom_, suffix);
 
@@ -151,9 +181,9 @@ fn parse_let(line: &str) -> Option<Vec<Atom>> {
     if let Ok((expr, ident_atom)) = result {
         if let Atom::Plain(ident) = ident_atom {
             Some(vec![
-                Atom::Quotation(parse_expr(expr)),
+                Atom::Quotation(Rc::new(parse_expr(expr))),
                 Atom::Symbol(ident),
-                Atom::DefVar,
+                def,
             ])
         } else {
             unreachable!();
@@ -163,22 +193,42 @@ fn parse_let(line: &str) -> Option<Vec<Atom>> {
     }
 }
 
-fn parse_fn(line: &str) -> Option<Vec<Atom>> {
+/// Parse a stack effect declaration like `( a b -- c )` into its arity.
+fn parse_effect_nom_(token: &str) -> IResult<&str, (u8, u8)> {
+    let names = || many0(preceded(multispace1, recognize_ident_nom_));
+    map(
+        tuple((
+            tag("("),
+            names(),
+            multispace1,
+            tag("--"),
+            names(),
+            multispace1,
+            tag(")"),
+        )),
+        |(_, ins, _, _, outs, _, _)| (ins.len() as u8, outs.len() as u8),
+    )(token)
+}
+
+fn parse_fn(keyword: &str, def: Atom, line: &str) -> Option<Vec<Atom>> {
     let parser = preceded(
-        tag("fn"),
+        tag(keyword),
         terminated(
-            delimited(
-                multispace1,
-                separated_list(multispace1, parse_ident_nom_),
-                multispace1,
-            ),
+            tuple((
+                delimited(
+                    multispace1,
+                    separated_list(multispace1, parse_ident_nom_),
+                    multispace1,
+                ),
+                opt(terminated(parse_effect_nom_, multispace1)),
+            )),
             tag("="),
         ),
     );
 
     let result = parser(line);
 
-    if let Ok((s, mut v)) = result {
+    if let Ok((s, (mut v, effect))) = result {
         let expr: &str = s;
         let mut d = v.drain(..);
         let v0: Atom = d.next().unwrap();
@@ -194,9 +244,13 @@ fn parse_fn(line: &str) -> Option<Vec<Atom>> {
                 })
                 .collect();
             return Some(vec![
-                Atom::Quotation(vec![Atom::Function(params, parse_expr(expr))]),
+                Atom::Quotation(Rc::new(vec![Atom::Function(
+                    params,
+                    Rc::new(parse_expr(expr)),
+                    effect,
+                )])),
                 Atom::Symbol(ident),
-                Atom::DefVar,
+                def,
             ]);
         }
     }
@@ -219,7 +273,29 @@ pub fn parse_line(line: &str) -> Vec<Atom> {
 
 #[test]
 fn test_parse_fn() {
-    assert_ne!(None, parse_fn("fn f a b c = 1 2 3"));
+    assert_ne!(None, parse_fn("fn", Atom::DefVar, "fn f a b c = 1 2 3"));
+}
+
+#[test]
+fn test_parse_redef() {
+    assert_eq!(Some(&Atom::RedefVar), parse_line("redef a = 1").last());
+    assert_eq!(Some(&Atom::RedefVar), parse_line("fn! f a = a a").last());
+    assert_eq!(Some(&Atom::DefVar), parse_line("fn f a = a a").last());
+}
+
+#[test]
+fn test_parse_effect() {
+    let effect = |line| match &parse_line(line)[0] {
+        Atom::Quotation(q) => match &q[0] {
+            Atom::Function(_, _, effect) => *effect,
+            _ => unreachable!(),
+        },
+        _ => unreachable!(),
+    };
+    assert_eq!(Some((1, 1)), effect("fn sq ( n -- n ) = dup *"));
+    assert_eq!(Some((2, 1)), effect("fn add a b ( a b -- c ) = a b +"));
+    assert_eq!(Some((0, 0)), effect("fn nop ( -- ) ="));
+    assert_eq!(None, effect("fn sq = dup *"));
 }
 
 #[test]
@@ -230,10 +306,59 @@ fn test_special_ident_fail() {
     }
 }
 
+#[test]
+fn test_parse_combinator_names() {
+    assert_eq!(Atom::Op(get_op("2dip").unwrap()), parse_token("2dip"));
+    assert_eq!(Atom::Op(get_op("bi*").unwrap()), parse_token("bi*"));
+    assert_eq!(Atom::Op(get_op("bi@").unwrap()), parse_token("bi@"));
+    assert_eq!(Atom::Num(2), parse_token("2"));
+    assert_eq!(Atom::Op(get_op("-rot").unwrap()), parse_token("-rot"));
+    assert_eq!(Atom::Op(get_op("-").unwrap()), parse_token("-"));
+}
+
+#[test]
+fn test_parse_str() {
+    assert_eq!(
+        vec![Atom::Num(1), Atom::Str(InlinableString::from("a b -- b a")), Atom::Num(2)],
+        parse_line(r#"1 "a b -- b a" 2"#)
+    );
+}
+
+#[test]
+fn test_parse_concat() {
+    let plus = Atom::Op(get_op("+").unwrap());
+    let dup = Atom::Op(get_op("dup").unwrap());
+    assert_eq!(Atom::Concat(vec![plus.clone(), plus.clone()]), parse_token("+,+"));
+    assert_eq!(
+        Atom::Concat(vec![dup, plus, Atom::Plain(Identifier::from("f"))]),
+        parse_token("dup,+,f")
+    );
+}
+
+#[test]
+fn test_parse_select() {
+    let select = |field| Atom::Select(Identifier::from(field));
+    assert_eq!(select("a"), parse_token(".a"));
+    assert_eq!(select("0"), parse_token(".0"));
+    assert_eq!(
+        Atom::Concat(vec![select("a"), select("b")]),
+        parse_token(".a,.b")
+    );
+    assert_eq!(
+        vec![
+            Atom::QuotationStart,
+            Atom::QuotationEnd,
+            Atom::Op(get_op("record").unwrap()),
+            select("x")
+        ],
+        parse_line("[ ] record .x")
+    );
+}
+
 #[test]
 fn test_parse_var_name() {
     assert_eq!(
-        Ok(("", Atom::Plain(InlinableString::from("a")))),
+        Ok(("", Atom::Plain(Identifier::from("a")))),
         parse_ident_nom_("a")
     );
 }
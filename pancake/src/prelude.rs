@@ -0,0 +1,257 @@
+//! The types of the builtins, for inferring the types of programs which use
+//! them with `typeck`.
+//!
+//! A builtin is typed as the curried function `lower` applies it as, taking
+//! its inputs in stack order. Quotations are typed as the functions they're
+//! lowered to, so one which takes no values is just the value it leaves. A
+//! word which leaves several values returns them as a record with fields `0`,
+//! `1`, ..., in stack order.
+
+use crate::check::{builtin_signatures, primitive};
+use crate::inference_data::{Provenance, SimpleType, TVarRegistry, TypeScheme};
+use crate::vm2::Context;
+
+use std::collections::HashMap;
+use std::iter::Peekable;
+use std::rc::Rc;
+use std::vec::IntoIter;
+
+type SimpleTypeRef = Rc<SimpleType>;
+
+/// The types of the builtins in `check::COMBINATORS` but not `UNTYPED`,
+/// written the way types are shown. A type variable which only appears in an
+/// input stands for any value, e.g. the operands of `list`.
+pub const COMBINATOR_TYPES: [(&str, &str); 18] = [
+    ("cond", "bool -> 'a -> 'a -> 'a"),
+    ("if", "'a -> bool -> ('a -> 'a) -> 'a"),
+    ("repeat", "'a -> ('a -> 'a) -> int -> 'a"),
+    ("for_else", "'a -> ('a -> 'a) -> 'a"),
+    ("for_if", "'a -> ('a -> 'a) -> 'a"),
+    ("list", "'a -> list"),
+    ("record", "'a -> {}"),
+    ("map", "list -> ('a -> 'b) -> list"),
+    ("reduce_inner", "list -> ('a -> 'b -> 'c) -> list"),
+    ("keep", "'a -> ('a -> 'b) -> {0: 'a, 1: 'b}"),
+    (
+        "keep2",
+        "'a -> 'b -> ('a -> 'b -> 'c) -> {0: 'c, 1: 'a, 2: 'b}",
+    ),
+    ("dip", "'a -> 'b -> ('a -> 'c) -> {0: 'c, 1: 'b}"),
+    (
+        "2dip",
+        "'a -> 'b -> 'c -> ('a -> 'd) -> {0: 'd, 1: 'b, 2: 'c}",
+    ),
+    ("bi", "'a -> ('a -> 'b) -> ('a -> 'c) -> {0: 'b, 1: 'c}"),
+    (
+        "tri",
+        "'a -> ('a -> 'b) -> ('a -> 'c) -> ('a -> 'd) -> {0: 'b, 1: 'c, 2: 'd}",
+    ),
+    (
+        "bi*",
+        "'a -> 'b -> ('a -> 'c) -> ('b -> 'd) -> {0: 'c, 1: 'd}",
+    ),
+    (
+        "tri*",
+        "'a -> 'b -> 'c -> ('a -> 'd) -> ('b -> 'e) -> ('c -> 'f) -> {0: 'd, 1: 'e, 2: 'f}",
+    ),
+    ("bi@", "'a -> 'a -> ('a -> 'b) -> {0: 'b, 1: 'b}"),
+];
+
+/// Combinators whose results could only be typed by variables which appear
+/// nowhere else, which would make them ⊥ and let any use of them check. They're
+/// left out, so that using them is an error.
+pub const UNTYPED: [&str; 6] = ["splat", "shuffle", "get", "cleave", "spread", "napply"];
+
+/// Converts a stack effect signature like `a b -- b a` into the type of a
+/// function like `'a -> 'b -> {0: 'b, 1: 'a}`.
+fn signature_type(signature: &str) -> String {
+    let name = |n: &str| match primitive(n) {
+        Some(_) => n.to_string(),
+        None => format!("'{}", n),
+    };
+    let mut sides = signature.split("--");
+    let inputs: Vec<String> = sides.next().unwrap().split_whitespace().map(name).collect();
+    let outputs: Vec<String> = sides.next().unwrap().split_whitespace().map(name).collect();
+    let output = match outputs.as_slice() {
+        [output] => output.clone(),
+        outputs => format!(
+            "{{{}}}",
+            outputs
+                .iter()
+                .enumerate()
+                .map(|(i, o)| format!("{}: {}", i, o))
+                .collect::<Vec<_>>()
+                .join(", ")
+        ),
+    };
+    inputs
+        .into_iter()
+        .rev()
+        .fold(output, |rhs, lhs| format!("{} -> {}", lhs, rhs))
+}
+
+/// Parses types written the way `Type` is shown. Type variables are created at
+/// level 1, so that they're generalised in a scheme at level 0.
+struct TypeParser<'a> {
+    tokens: Peekable<IntoIter<String>>,
+    vars: HashMap<String, SimpleTypeRef>,
+    tvars: &'a mut TVarRegistry,
+}
+
+impl<'a> TypeParser<'a> {
+    fn new(typ: &str, tvars: &'a mut TVarRegistry) -> Self {
+        let mut spaced = String::new();
+        for c in typ.chars() {
+            match c {
+                '(' | ')' | '{' | '}' | ':' | ',' => spaced.extend(&[' ', c, ' ']),
+                c => spaced.push(c),
+            }
+        }
+        let tokens: Vec<String> = spaced.split_whitespace().map(String::from).collect();
+        Self {
+            tokens: tokens.into_iter().peekable(),
+            vars: HashMap::new(),
+            tvars,
+        }
+    }
+
+    fn next(&mut self) -> String {
+        self.tokens.next().expect("Unexpected end of type.")
+    }
+
+    fn expect(&mut self, token: &str) {
+        let next = self.next();
+        if next != token {
+            panic!("Expected {} in type but found {}.", token, next);
+        }
+    }
+
+    fn parse(&mut self) -> SimpleTypeRef {
+        let lhs = self.atom();
+        if self.tokens.peek().map(String::as_str) != Some("->") {
+            return lhs;
+        }
+        self.next();
+        Rc::new(SimpleType::Function {
+            lhs,
+            rhs: self.parse(),
+            prov: Provenance::default(),
+        })
+    }
+
+    fn atom(&mut self) -> SimpleTypeRef {
+        let token = self.next();
+        match token.as_str() {
+            "(" => {
+                let typ = self.parse();
+                self.expect(")");
+                typ
+            }
+            "{" => {
+                let mut fields = HashMap::new();
+                while self.tokens.peek().map(String::as_str) != Some("}") {
+                    let name = self.next();
+                    self.expect(":");
+                    fields.insert(name.into(), self.parse());
+                    if self.tokens.peek().map(String::as_str) == Some(",") {
+                        self.next();
+                    }
+                }
+                self.expect("}");
+                Rc::new(SimpleType::Record {
+                    fields,
+                    prov: Provenance::default(),
+                })
+            }
+            name if name.starts_with('\'') => {
+                let tvars = &mut *self.tvars;
+                self.vars
+                    .entry(token)
+                    .or_insert_with(|| tvars.fresh_var(1))
+                    .clone()
+            }
+            name => Rc::new(SimpleType::Primitive {
+                name: name.into(),
+                prov: Provenance::default(),
+            }),
+        }
+    }
+}
+
+fn parse_type(typ: &str, tvars: &mut TVarRegistry) -> SimpleTypeRef {
+    let mut parser = TypeParser::new(typ, tvars);
+    let parsed = parser.parse();
+    if let Some(token) = parser.tokens.next() {
+        panic!("Unexpected {} at the end of type {}.", token, typ);
+    }
+    parsed
+}
+
+/// The types of all of the builtins, each generalised over its type
+/// variables.
+pub fn prelude(tvars: &mut TVarRegistry) -> Context<TypeScheme> {
+    let mut ctx = Context::new();
+    let signatures = builtin_signatures()
+        .into_iter()
+        .map(|(name, signature)| (name, signature_type(signature)));
+    let combinators = COMBINATOR_TYPES
+        .iter()
+        .map(|&(name, typ)| (name, typ.to_string()));
+    for (name, typ) in signatures.chain(combinators) {
+        let body = parse_type(&typ, tvars);
+        ctx.insert(name, TypeScheme::PolymorphicType { level: 0, body });
+    }
+    ctx
+}
+
+#[test]
+fn test_prelude_builtins() {
+    use crate::check::COMBINATORS;
+    use crate::ops::is_builtin;
+
+    let mut tvars = TVarRegistry::new();
+    let ctx = prelude(&mut tvars);
+    let names = builtin_signatures()
+        .into_iter()
+        .map(|(name, _)| name)
+        .chain(COMBINATORS.iter().copied())
+        .filter(|name| !UNTYPED.contains(name));
+    for name in names {
+        assert!(ctx.get(name).is_some(), "{} has no type", name);
+        assert!(is_builtin(name), "{} isn't a builtin", name);
+    }
+    for (name, _) in COMBINATOR_TYPES.iter() {
+        assert!(COMBINATORS.contains(name));
+    }
+    for name in UNTYPED.iter() {
+        assert!(ctx.get(*name).is_none(), "{} has a type", name);
+        assert!(is_builtin(name), "{} isn't a builtin", name);
+    }
+}
+
+#[test]
+fn test_prelude_types() {
+    use crate::ast::{Literal, Term};
+    use crate::typeck::{expand_type, infer_term};
+
+    let mut tvars = TVarRegistry::new();
+    let mut ctx = prelude(&mut tvars);
+    let mut show = |term: &Term| {
+        let typ = infer_term(&Rc::new(term.clone()), &mut ctx, &mut tvars, 0).unwrap();
+        expand_type(typ, &mut tvars).to_string()
+    };
+    let var = |name: &str| Term::Var(name.into());
+
+    assert_eq!("int -> int -> int", show(&var("+")));
+    assert_eq!("int -> int -> bool", show(&var("<=")));
+    assert_eq!("bool -> bool", show(&var("not")));
+    assert_eq!("'a -> {0: 'a, 1: 'a}", show(&var("dup")));
+    assert_eq!("'a -> 'b -> {0: 'b, 1: 'a}", show(&var("swap")));
+    assert_eq!("top -> {}", show(&var("drop")));
+    assert_eq!(
+        "'a -> 'b -> ('a -> 'c) -> {0: 'c, 1: 'b}",
+        show(&var("dip"))
+    );
+    let dup_one = Term::App(Rc::new(var("dup")), Rc::new(Term::Lit(Literal::Int(1))));
+    assert_eq!("{0: int, 1: int}", show(&dup_one));
+}
@@ -0,0 +1,579 @@
+//! An experimental stack effect checker which encodes the body of each `fn`
+//! with the definitions in `typeck.smt2`, and asks an SMT solver for its
+//! effect through `rsmt2`.
+//!
+//! A body is encoded as a chain of compositions: starting from the empty
+//! effect, `(gencompose I0 w1 I1)` composes the first word onto it, and so on,
+//! so that the type of the last `I` is the effect of the whole body. Each step
+//! is a named assertion, so that when the steps are unsatisfiable together the
+//! solver's unsat core names the words which conflict.
+//!
+//! Only parameters, `int` and `bool` literals and the builtins with a
+//! `check::builtin_signature` are encoded so far. The types in `typeck.smt2`
+//! have no row or type variables, so each type in an effect which the solver
+//! isn't forced to pick is shown as a variable instead, and types which are
+//! forced to be the same share one.
+
+use crate::check::{builtin_name, builtin_signature, describe, primitive, CheckError};
+use crate::parse::parse_line;
+use crate::types::Atom;
+use crate::vm::Identifier;
+
+use rsmt2::parse::{ExprParser, SymParser, ValueParser};
+use rsmt2::{SmtRes, Solver};
+
+use std::fmt;
+use std::io::Write;
+
+type CheckResult<T> = Result<T, CheckError>;
+
+const ENCODING: &str = include_str!("typeck.smt2");
+
+/// The definitions in `typeck.smt2`, without the examples after them.
+pub fn definitions() -> &'static str {
+    ENCODING.split("; Examples").next().unwrap()
+}
+
+/// One word of a body, composed with the words before it.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Step {
+    /// The name of the assertion, which unsat cores refer to it by.
+    pub name: String,
+    pub assertion: String,
+    /// The word, as it's shown in errors.
+    pub word: String,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Encoding {
+    /// Commands declaring the types of the words.
+    pub declarations: Vec<String>,
+    pub steps: Vec<Step>,
+    /// The constant holding the effect of the whole body.
+    pub result: String,
+}
+
+/// Builds an `LType` from `types`, bottom of the stack first.
+fn ltype(types: &[String]) -> String {
+    types.iter().rev().fold("nil".to_string(), |rest, ty| {
+        format!("(cons {} {})", ty, rest)
+    })
+}
+
+/// The type of a word, given the names of the constants standing for the type
+/// variables in its signature.
+fn signature_type(signature: &str, mut var: impl FnMut(&str) -> String) -> String {
+    let mut types = |side: &str| -> Vec<String> {
+        side.split_whitespace()
+            .map(|n| match primitive(n) {
+                Some(_) => n.to_string(),
+                None => var(n),
+            })
+            .collect()
+    };
+    let mut sides = signature.split("--");
+    let inputs = types(sides.next().unwrap());
+    let outputs = types(sides.next().unwrap());
+    format!("(func {} {})", ltype(&inputs), ltype(&outputs))
+}
+
+/// Encodes the body of a function which takes `params`. The parameters are
+/// popped by a first word, and each use of one pushes a value of its type.
+/// Fails with the word which can't be encoded.
+pub fn encode(params: &[Identifier], body: &[Atom]) -> Result<Encoding, (String, String)> {
+    let mut declarations = Vec::new();
+    let mut words = Vec::new();
+
+    let param_types: Vec<String> = (0..params.len()).map(|i| format!("p{}", i)).collect();
+    for ty in &param_types {
+        declarations.push(format!("(declare-const {} Type)", ty));
+    }
+    if !params.is_empty() {
+        let bind = format!("(func {} nil)", ltype(&param_types));
+        let shown: Vec<String> = params.iter().map(|p| p.to_string()).collect();
+        words.push((bind, shown.join(" ")));
+    }
+
+    for atom in body {
+        let word = describe(atom);
+        let ty = match atom {
+            Atom::Num(_) => "int".to_string(),
+            Atom::Bool(_) => "bool".to_string(),
+            Atom::Plain(ident) => match params.iter().rposition(|p| p == ident) {
+                Some(i) => format!("(func nil {})", ltype(&[param_types[i].clone()])),
+                None => return Err((word, "calls to other functions aren't encoded yet".into())),
+            },
+            Atom::Op(op) => match builtin_name(op).and_then(builtin_signature) {
+                Some(signature) => {
+                    let prefix = format!("w{}", words.len() + 1);
+                    signature_type(signature, |n| {
+                        let var = format!("{}_{}", prefix, n);
+                        let declaration = format!("(declare-const {} Type)", var);
+                        if !declarations.contains(&declaration) {
+                            declarations.push(declaration);
+                        }
+                        var
+                    })
+                }
+                None => return Err((word, "this builtin isn't encoded yet".into())),
+            },
+            _ => return Err((word, "this word isn't encoded yet".into())),
+        };
+        words.push((ty, word));
+    }
+
+    declarations.push("(define-fun I0 () Type (func nil nil))".into());
+    let mut steps = Vec::new();
+    for (i, (ty, word)) in words.into_iter().enumerate() {
+        let n = i + 1;
+        declarations.push(format!("(define-fun w{} () Type {})", n, ty));
+        declarations.push(format!("(declare-const I{} Type)", n));
+        steps.push(Step {
+            name: format!("s{}", n),
+            assertion: format!("(gencompose I{} w{} I{})", i, n, n),
+            word,
+        });
+    }
+    let result = format!("I{}", steps.len());
+    Ok(Encoding {
+        declarations,
+        steps,
+        result,
+    })
+}
+
+/// Splits an s-expression into its parentheses and atoms.
+fn tokenize(sexpr: &str) -> Vec<String> {
+    sexpr
+        .replace('(', " ( ")
+        .replace(')', " ) ")
+        .split_whitespace()
+        .map(String::from)
+        .collect()
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Sexpr {
+    Atom(String),
+    List(Vec<Sexpr>),
+}
+
+impl fmt::Display for Sexpr {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Sexpr::Atom(atom) => f.write_str(atom),
+            Sexpr::List(items) => {
+                let items: Vec<String> = items.iter().map(|item| item.to_string()).collect();
+                write!(f, "({})", items.join(" "))
+            }
+        }
+    }
+}
+
+fn parse_sexpr(tokens: &mut std::vec::IntoIter<String>) -> Option<Sexpr> {
+    let token = tokens.next()?;
+    match token.as_str() {
+        "(" => {
+            let mut items = Vec::new();
+            loop {
+                match tokens.as_slice().first().map(String::as_str) {
+                    Some(")") => {
+                        tokens.next();
+                        return Some(Sexpr::List(items));
+                    }
+                    Some(_) => items.push(parse_sexpr(tokens)?),
+                    None => return None,
+                }
+            }
+        }
+        ")" => None,
+        _ => Some(Sexpr::Atom(token)),
+    }
+}
+
+/// Shows a `Type` value from the solver like `check` shows types, with
+/// functions as quotations.
+fn show_type(value: &Sexpr) -> Option<String> {
+    match value {
+        Sexpr::Atom(name) => Some(name.clone()),
+        Sexpr::List(items) => match items.as_slice() {
+            [Sexpr::Atom(head), value, _] if head == "as" => show_type(value),
+            [Sexpr::Atom(head), inputs, outputs] if head == "func" => Some(format!(
+                "[ {} -- {} ]",
+                show_ltype(inputs)?,
+                show_ltype(outputs)?
+            ))
+            .map(|quot| quot.replace("[  --", "[ --").replace("--  ]", "-- ]")),
+            _ => None,
+        },
+    }
+}
+
+/// The elements of an `LType` value, bottom of the stack first.
+fn ltype_items(value: &Sexpr) -> Option<Vec<&Sexpr>> {
+    let mut types = Vec::new();
+    let mut value = value;
+    loop {
+        match value {
+            Sexpr::Atom(nil) if nil == "nil" => break,
+            Sexpr::List(items) => match items.as_slice() {
+                [Sexpr::Atom(head), nil, _] if head == "as" => value = nil,
+                [Sexpr::Atom(head), ty, rest] if head == "cons" => {
+                    types.push(ty);
+                    value = rest;
+                }
+                _ => return None,
+            },
+            _ => return None,
+        }
+    }
+    Some(types)
+}
+
+fn show_ltype(value: &Sexpr) -> Option<String> {
+    let types: Option<Vec<String>> = ltype_items(value)?.into_iter().map(show_type).collect();
+    Some(types?.join(" "))
+}
+
+/// Parses a value from the solver, which must be a single s-expression.
+fn parse_value(value: &str) -> Option<Sexpr> {
+    let mut tokens = tokenize(value).into_iter();
+    let value = parse_sexpr(&mut tokens)?;
+    if tokens.next().is_some() {
+        return None;
+    }
+    Some(value)
+}
+
+/// Shows the value of an effect from the solver like `( int int -- int )`.
+/// Values which aren't functions are pushed by an effect which takes nothing.
+pub fn show_effect(value: &str) -> Option<String> {
+    let quot = match show_type(&parse_value(value)?)? {
+        quot if quot.starts_with('[') => quot,
+        value => format!("[ -- {} ]", value),
+    };
+    Some(format!("({})", &quot[1..quot.len() - 1]))
+}
+
+/// The types taken and left by the value of the effect `effect`, bottom of
+/// the stack first, each with an expression which selects it from `effect`.
+fn effect_positions(value: &Sexpr, effect: &str) -> Option<Vec<Vec<(String, Sexpr)>>> {
+    let sides = match value {
+        Sexpr::List(items) => match items.as_slice() {
+            [Sexpr::Atom(head), value, _] if head == "as" => {
+                return effect_positions(value, effect)
+            }
+            [Sexpr::Atom(head), inputs, outputs] if head == "func" => {
+                [("in", inputs), ("out", outputs)]
+            }
+            _ => return None,
+        },
+        _ => return None,
+    };
+    let mut positions = Vec::new();
+    for &(selector, side) in sides.iter() {
+        let mut rest = format!("({} {})", selector, effect);
+        let mut types = Vec::new();
+        for ty in ltype_items(side)? {
+            types.push((format!("(head {})", rest), ty.clone()));
+            rest = format!("(tail {})", rest);
+        }
+        positions.push(types);
+    }
+    Some(positions)
+}
+
+/// The name of the `i`th type variable in an effect: `a`, `b`, ...
+fn var_name(i: usize) -> String {
+    match (b'a'..=b'z').nth(i) {
+        Some(c) => (c as char).to_string(),
+        None => format!("t{}", i),
+    }
+}
+
+/// Parses the solver's answers to `get-value` as the text it sent.
+#[derive(Clone, Copy)]
+struct Parser;
+
+impl<'a> ExprParser<String, (), &'a str> for Parser {
+    fn parse_expr(self, input: &'a str, _: ()) -> SmtRes<String> {
+        Ok(input.into())
+    }
+}
+
+impl<'a> ValueParser<String, &'a str> for Parser {
+    fn parse_value(self, input: &'a str) -> SmtRes<String> {
+        Ok(input.into())
+    }
+}
+
+impl<'a> SymParser<String, &'a str> for Parser {
+    fn parse_sym(self, input: &'a str) -> SmtRes<String> {
+        Ok(input.into())
+    }
+}
+
+/// Checks functions with a local solver, keeping the definitions from
+/// `typeck.smt2` loaded between them.
+pub struct SmtChecker {
+    solver: Solver<Parser>,
+}
+
+impl SmtChecker {
+    pub fn new() -> SmtRes<Self> {
+        let mut solver = Solver::default_z3(Parser)?;
+        // Z3 shrinks the cores it gives, so that they only name words which
+        // conflict.
+        solver.produce_unsat_cores()?;
+        solver.set_option(":smt.core.minimize", "true")?;
+        solver.write_all(definitions().as_bytes())?;
+        Ok(Self { solver })
+    }
+
+    /// Whether the assertions so far force `fact` to hold.
+    fn forced(&mut self, fact: &str) -> SmtRes<bool> {
+        self.solver.push(1)?;
+        self.solver.assert(format!("(not {})", fact))?;
+        let sat = self.solver.check_sat();
+        self.solver.pop(1)?;
+        Ok(!sat?)
+    }
+
+    /// Shows an effect with the types at `positions`, replacing those which
+    /// the solver isn't forced to pick by variables, one for each set of them
+    /// which are forced to be the same.
+    fn show_positions(&mut self, positions: &[Vec<(String, Sexpr)>]) -> SmtRes<String> {
+        let mut vars: Vec<&str> = Vec::new();
+        let mut sides = Vec::new();
+        for side in positions {
+            let mut types = Vec::new();
+            for (selector, value) in side {
+                if self.forced(&format!("(= {} {})", selector, value))? {
+                    types.push(show_type(value).unwrap_or_else(|| value.to_string()));
+                    continue;
+                }
+                let mut var = None;
+                for (i, other) in vars.iter().enumerate() {
+                    if self.forced(&format!("(= {} {})", selector, other))? {
+                        var = Some(i);
+                        break;
+                    }
+                }
+                let i = var.unwrap_or_else(|| {
+                    vars.push(selector);
+                    vars.len() - 1
+                });
+                types.push(var_name(i));
+            }
+            sides.push(types.join(" "));
+        }
+        Ok(format!("( {} -- {} )", sides[0], sides[1])
+            .replace("(  --", "( --")
+            .replace("--  )", "-- )"))
+    }
+
+    /// Infers the effect of a function body, or fails with the words whose
+    /// effects conflict.
+    fn effect(&mut self, encoding: &Encoding) -> SmtRes<Result<String, Vec<String>>> {
+        for command in &encoding.declarations {
+            writeln!(self.solver, "{}", command)?;
+        }
+        for step in &encoding.steps {
+            self.solver.named_assert(&step.name, &step.assertion)?;
+        }
+
+        if !self.solver.check_sat()? {
+            let core: Vec<String> = self.solver.get_unsat_core()?;
+            return Ok(Err(encoding
+                .steps
+                .iter()
+                .filter(|step| core.contains(&step.name))
+                .map(|step| step.word.clone())
+                .collect()));
+        }
+        let values = self.solver.get_values(&[encoding.result.as_str()])?;
+        let (_, value) = &values[0];
+        let positions = parse_value(value).and_then(|v| effect_positions(&v, &encoding.result));
+        let effect = match positions {
+            Some(positions) => self.show_positions(&positions)?,
+            None => value.clone(),
+        };
+        Ok(Ok(effect))
+    }
+
+    fn check_function(
+        &mut self,
+        params: &[Identifier],
+        body: &[Atom],
+    ) -> SmtRes<Result<String, (String, String)>> {
+        let encoding = match encode(params, body) {
+            Ok(encoding) => encoding,
+            Err(failure) => return Ok(Err(failure)),
+        };
+        self.solver.push(1)?;
+        let effect = self.effect(&encoding);
+        self.solver.pop(1)?;
+        Ok(effect?.map_err(|words| {
+            let shown: Vec<String> = words.iter().map(|w| format!("`{}`", w)).collect();
+            let word = words.last().cloned().unwrap_or_default();
+            (
+                word,
+                format!("the effects of {} conflict", shown.join(", ")),
+            )
+        }))
+    }
+
+    /// Checks each `fn` in `program`, returning their stack effects in order.
+    pub fn check_program(&mut self, program: &str) -> CheckResult<Vec<(Identifier, String)>> {
+        let mut effects = Vec::new();
+        for (i, line) in program.split('\n').enumerate() {
+            let atoms = parse_line(line);
+            let (ident, params, body) = match atoms.as_slice() {
+                [Atom::Quotation(expr), Atom::Symbol(ident), Atom::DefVar] => {
+                    match expr.as_slice() {
+                        [Atom::Function(params, body, _)] => (ident, params, body),
+                        _ => continue,
+                    }
+                }
+                _ => continue,
+            };
+            let fail = |word: String, message: String| CheckError {
+                line: i + 1,
+                function: Some(ident.clone()),
+                word,
+                message,
+            };
+            match self.check_function(params, body) {
+                Ok(Ok(effect)) => effects.push((ident.clone(), effect)),
+                Ok(Err((word, message))) => return Err(fail(word, message)),
+                Err(e) => return Err(fail(String::new(), format!("solver error: {}", e))),
+            }
+        }
+        Ok(effects)
+    }
+}
+
+/// Checks `program` with a new solver, returning the stack effect of each
+/// `fn`.
+pub fn check_program(program: &str) -> CheckResult<Vec<(Identifier, String)>> {
+    let mut checker = SmtChecker::new().map_err(|e| CheckError {
+        line: 0,
+        function: None,
+        word: String::new(),
+        message: format!("couldn't start the solver: {}", e),
+    })?;
+    checker.check_program(program)
+}
+
+#[test]
+fn test_smt_encoding() {
+    let params: Vec<Identifier> = vec!["n".into()];
+    let encoding = encode(&params, &parse_line("n dup + 1 swap")).unwrap();
+    let assertions: Vec<&str> = encoding
+        .steps
+        .iter()
+        .map(|s| s.assertion.as_str())
+        .collect();
+    assert_eq!(
+        vec![
+            "(gencompose I0 w1 I1)",
+            "(gencompose I1 w2 I2)",
+            "(gencompose I2 w3 I3)",
+            "(gencompose I3 w4 I4)",
+            "(gencompose I4 w5 I5)",
+            "(gencompose I5 w6 I6)",
+        ],
+        assertions
+    );
+    let words: Vec<&str> = encoding.steps.iter().map(|s| s.word.as_str()).collect();
+    assert_eq!(vec!["n", "n", "dup", "+", "1", "swap"], words);
+    assert_eq!("I6", encoding.result);
+
+    let declared = |d: &str| encoding.declarations.iter().any(|e| e == d);
+    assert!(declared("(define-fun w1 () Type (func (cons p0 nil) nil))"));
+    assert!(declared("(define-fun w2 () Type (func nil (cons p0 nil)))"));
+    assert!(declared(
+        "(define-fun w3 () Type (func (cons w3_a nil) (cons w3_a (cons w3_a nil))))"
+    ));
+    assert!(declared(
+        "(define-fun w4 () Type (func (cons int (cons int nil)) (cons int nil)))"
+    ));
+    assert!(declared("(define-fun w5 () Type int)"));
+    assert!(declared("(declare-const w6_b Type)"));
+
+    let (word, _) = encode(&[], &parse_line("1 inc")).unwrap_err();
+    assert_eq!("inc", word);
+}
+
+#[test]
+fn test_smt_show_effect() {
+    assert_eq!(
+        Some("( int int -- bool )".to_string()),
+        show_effect("(func (cons int (cons int nil)) (cons bool nil))")
+    );
+    assert_eq!(
+        Some("( -- [ int -- ] )".to_string()),
+        show_effect("(func nil (cons (func (cons int nil) (as nil LType)) nil))")
+    );
+    assert_eq!(Some("( -- int )".to_string()), show_effect("int"));
+    assert_eq!(None, show_effect("(func nil"));
+}
+
+#[test]
+fn test_smt_positions() {
+    let value = parse_value("(func (cons int (cons bool nil)) (as nil LType))").unwrap();
+    let positions = effect_positions(&value, "I2").unwrap();
+    let selectors: Vec<Vec<(&str, String)>> = positions
+        .iter()
+        .map(|side| {
+            side.iter()
+                .map(|(selector, ty)| (selector.as_str(), ty.to_string()))
+                .collect()
+        })
+        .collect();
+    assert_eq!(
+        vec![
+            vec![
+                ("(head (in I2))", "int".to_string()),
+                ("(head (tail (in I2)))", "bool".to_string())
+            ],
+            vec![]
+        ],
+        selectors
+    );
+    assert_eq!(None, effect_positions(&parse_value("int").unwrap(), "I0"));
+    assert_eq!("b", var_name(1));
+}
+
+// This needs Z3 on the `PATH`, as `pancake --smt` does.
+#[test]
+#[ignore]
+fn test_smt_check_program() {
+    let effects = check_program(
+        r"
+fn inc = 1 +
+fn sq n = n n *
+fn pos n = n 0 >
+fn flip a b = b a
+fn first a b = a
+fn one x = 1
+",
+    )
+    .unwrap();
+    let effect = |name: &str| {
+        effects
+            .iter()
+            .find(|(ident, _)| ident == name)
+            .map(|(_, effect)| effect.as_str())
+    };
+    assert_eq!(Some("( int -- int )"), effect("inc"));
+    assert_eq!(Some("( int -- int )"), effect("sq"));
+    assert_eq!(Some("( int -- bool )"), effect("pos"));
+    assert_eq!(Some("( a b -- b a )"), effect("flip"));
+    assert_eq!(Some("( a b -- a )"), effect("first"));
+    assert_eq!(Some("( a -- int )"), effect("one"));
+
+    let error = check_program("fn bad = 1 true +").unwrap_err();
+    assert_eq!(Some("bad".into()), error.function);
+    assert_eq!("the effects of `true`, `+` conflict", error.message);
+}
@@ -1,33 +1,88 @@
 use rsmt2::{Solver, SmtRes};
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
+use std::fmt;
 use std::rc::Rc;
 
-use crate::ast::{Term, Let, Literal};
+use crate::ast::{Term, Let, Literal, Pgrm};
 use crate::vm2::{Context, Identifier};
-use crate::inference_data::{TypeScheme, SimpleType, TypeVariable, TVarId, TVarRegistry};
+use crate::inference_data::{TypeScheme, SimpleType, TypeVariable, TVarId, TVarRegistry, Type,
+                             Provenance};
 
 type Ctx<'a> = &'a mut Context<TypeScheme>;
 type TVars<'a> = &'a mut TVarRegistry;
 type SimpleTypeRef = Rc<SimpleType>;
 
+/// An error found while inferring types, along with the terms which
+/// introduced the types involved.
+#[derive(Clone, Debug, PartialEq)]
+pub enum TypeError {
+    /// A value of type `lhs` was used where `rhs` was expected.
+    Mismatch {
+        lhs: Type,
+        rhs: Type,
+        lhs_prov: Provenance,
+        rhs_prov: Provenance
+    },
+    /// A record without `field` was used where one with it was expected.
+    MissingField {
+        field: Identifier,
+        record: Type,
+        record_prov: Provenance,
+        field_prov: Provenance
+    },
+    UnboundVariable(Identifier),
+    /// A builtin whose type can't be expressed, like `splat`, was used.
+    UntypedBuiltin(Identifier)
+}
+
+impl fmt::Display for TypeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        use TypeError::*;
+        match self {
+            Mismatch { lhs, rhs, lhs_prov, rhs_prov } => write!(
+                f, "expected {} (from {}) but found {} (from {})",
+                rhs, rhs_prov, lhs, lhs_prov),
+            MissingField { field, record, record_prov, field_prov } => write!(
+                f, "missing field {} (used by {}) in {} (from {})",
+                field, field_prov, record, record_prov),
+            UnboundVariable(name) => write!(f, "variable {} not found", name),
+            UntypedBuiltin(name) => write!(f, "untyped builtin {} can't be inferred", name)
+        }
+    }
+}
+
+type TypeResult<T> = Result<T, TypeError>;
+
 /// Constrains types to enforce subtyping relation `lhs` <: `rhs`
-pub fn constrain(lhs: SimpleTypeRef, rhs: SimpleTypeRef, tvars: TVars) {
+pub fn constrain(lhs: SimpleTypeRef, rhs: SimpleTypeRef, tvars: TVars)
+                 -> TypeResult<()> {
     use SimpleType::*;
-    if Rc::ptr_eq(&lhs, &rhs) { return }
-    // TODO: cache
+    if Rc::ptr_eq(&lhs, &rhs) { return Ok(()) }
+    if let (Variable(_), _) | (_, Variable(_)) = (&*lhs, &*rhs) {
+        if tvars.cache_constraint(&lhs, &rhs) { return Ok(()) }
+    }
 
     let mut added_constraints = Vec::new();
 
     let (lhs_match, rhs_match): (&SimpleType, &SimpleType) = (&lhs, &rhs);
     match (lhs_match, rhs_match) {
-        (Function { lhs: l0, rhs: r0 }, Function { lhs: l1, rhs: r1 }) => {
-            constrain(l1.clone(), l0.clone(), tvars);
-            constrain(r0.clone(), r1.clone(), tvars);
+        (Primitive { name: n0, .. }, Primitive { name: n1, .. }) if n0 == n1 => {}
+        (Function { lhs: l0, rhs: r0, .. }, Function { lhs: l1, rhs: r1, .. }) => {
+            constrain(l1.clone(), l0.clone(), tvars)?;
+            constrain(r0.clone(), r1.clone(), tvars)?;
         }
-        (Record { fields: fs0 }, Record { fields: fs1}) => {
+        (Record { fields: fs0, prov }, Record { fields: fs1, prov: prov1 }) => {
             for key in fs1.keys() {
                 let type1 = fs1.get(key).unwrap().clone();
-                let type0 = fs0.get(key).unwrap().clone();
+                let type0 = match fs0.get(key) {
+                    Some(type0) => type0.clone(),
+                    None => return Err(TypeError::MissingField {
+                        field: key.clone(),
+                        record: expand_type(lhs.clone(), tvars),
+                        record_prov: prov.clone(),
+                        field_prov: prov1.clone()
+                    })
+                };
                 added_constraints.push((type0, type1));
             }
         }
@@ -55,11 +110,17 @@ pub fn constrain(lhs: SimpleTypeRef, rhs: SimpleTypeRef, tvars: TVars) {
                               &mut HashMap::new());
             added_constraints.push((lhs, rhs));
         }
-        _ => { panic!(); }
+        _ => return Err(TypeError::Mismatch {
+            lhs_prov: lhs.prov(),
+            rhs_prov: rhs.prov(),
+            lhs: expand_type(lhs, tvars),
+            rhs: expand_type(rhs, tvars)
+        })
     }
     for (lhs, rhs) in added_constraints {
-        constrain(lhs, rhs, tvars);
+        constrain(lhs, rhs, tvars)?;
     }
+    Ok(())
 }
 
 pub fn extrude(typ: SimpleTypeRef, polarity: bool, lvl: i32, tvars: TVars,
@@ -69,13 +130,15 @@ pub fn extrude(typ: SimpleTypeRef, polarity: bool, lvl: i32, tvars: TVars,
         return typ;
     }
     match &*typ {
-        Function { lhs, rhs } => Rc::new(Function {
+        Function { lhs, rhs, prov } => Rc::new(Function {
             lhs: extrude(lhs.clone(), !polarity, lvl, tvars, cache),
-            rhs: extrude(rhs.clone(), polarity, lvl, tvars, cache)
+            rhs: extrude(rhs.clone(), polarity, lvl, tvars, cache),
+            prov: prov.clone()
         }),
-        Record { fields } => Rc::new(Record {
+        Record { fields, prov } => Rc::new(Record {
             fields: fields.iter().map(
-                |(k, v)| (k.clone(), extrude(v.clone(), polarity, lvl, tvars, cache))).collect()
+                |(k, v)| (k.clone(), extrude(v.clone(), polarity, lvl, tvars, cache))).collect(),
+            prov: prov.clone()
         }),
         Variable(key) => {
             let res = cache.get(key);
@@ -105,71 +168,306 @@ pub fn extrude(typ: SimpleTypeRef, polarity: bool, lvl: i32, tvars: TVars,
                 new_var
             }
         }
-        Primitive { name: _ } => typ.clone()
+        Primitive { .. } => typ.clone()
     }
 }
 
-pub fn infer_term(term: &Term, ctx: Ctx, tvars: TVars, lvl: i32) -> SimpleTypeRef {
+pub fn infer_term(term: &Rc<Term>, ctx: Ctx, tvars: TVars, lvl: i32)
+                  -> TypeResult<SimpleTypeRef> {
     use Literal::*;
     use SimpleType::*;
     use Term::*;
-    match term {
+    let prov = Provenance::of(term);
+    Ok(match &**term {
         Lit(lit) => match lit {
-            Int(_) => Rc::new(Primitive { name: "int".into() }),
+            Bool(_) => Rc::new(Primitive { name: "bool".into(), prov }),
+            Int(_) => Rc::new(Primitive { name: "int".into(), prov }),
         }
         App(f, x) => {
             let res = tvars.fresh_var(lvl);
-            let f_typ = infer_term(f, ctx, tvars, lvl);
-            let x_typ = infer_term(x, ctx, tvars, lvl);
+            let f_typ = infer_term(f, ctx, tvars, lvl)?;
+            let x_typ = infer_term(x, ctx, tvars, lvl)?;
             constrain(
                 f_typ,
                 Rc::new(Function {
                     lhs: x_typ,
-                    rhs: res.clone()
+                    rhs: res.clone(),
+                    prov
                 }),
                 tvars
-            );
+            )?;
             res
         }
         Let { binding, body } => {
-            let typ = infer_let(binding, ctx, tvars, lvl);
+            let typ = infer_let(binding, ctx, tvars, lvl)?;
             ctx.with_binding(&binding.name, typ,
-                             |ctx2| infer_term(body, ctx2, tvars, lvl))
+                             |ctx2| infer_term(body, ctx2, tvars, lvl))?
         }
         Var(name) => match ctx.get(name) {
-            Some(t) => t.instantiate(tvars, lvl),
-            None => panic!("Variable {} not found", name)
+            Some(t) => {
+                let typ = t.instantiate(tvars, lvl);
+                SimpleType::with_prov(&typ, &prov)
+            }
+            None if crate::ops::is_builtin(name) =>
+                return Err(TypeError::UntypedBuiltin(name.clone())),
+            None => return Err(TypeError::UnboundVariable(name.clone()))
+        }
+        Rcd(fields) => Rc::new(Record {
+            fields: fields.iter()
+                .map(|(name, rhs)| Ok((name.clone(), infer_term(rhs, ctx, tvars, lvl)?)))
+                .collect::<TypeResult<_>>()?,
+            prov
+        }),
+        Sel(rcd, field) => {
+            let res = tvars.fresh_var(lvl);
+            let rcd_typ = infer_term(rcd, ctx, tvars, lvl)?;
+            constrain(
+                rcd_typ,
+                Rc::new(Record {
+                    fields: std::iter::once((field.clone(), res.clone())).collect(),
+                    prov
+                }),
+                tvars
+            )?;
+            res
         }
         Lam(name, body) => {
             let param = tvars.fresh_var(lvl);
             let body_typ = ctx.with_binding(name, param.clone().into(), |ctx2|
-                infer_term(body, ctx2, tvars, lvl));
+                infer_term(body, ctx2, tvars, lvl))?;
             Rc::new(Function {
                 lhs: param,
-                rhs: body_typ
+                rhs: body_typ,
+                prov
             })
         }
-    }
+    })
 }
 
-pub fn infer_let(binding: &Let, ctx: Ctx, tvars: TVars, lvl: i32) -> TypeScheme {
+pub fn infer_let(binding: &Let, ctx: Ctx, tvars: TVars, lvl: i32)
+                 -> TypeResult<TypeScheme> {
     use TypeScheme::*;
     let res = if binding.rec {
         let e = tvars.fresh_var(lvl + 1);
         let typ = ctx.with_binding(&binding.name, SimpleType(e.clone()),
-            |ctx2| infer_term(&binding.rhs, ctx2, tvars, lvl+1));
-        constrain(typ, e.clone(), tvars);
+            |ctx2| infer_term(&binding.rhs, ctx2, tvars, lvl+1))?;
+        constrain(typ, e.clone(), tvars)?;
         e
     } else {
-        infer_term(&binding.rhs, ctx, tvars, lvl+1)
+        infer_term(&binding.rhs, ctx, tvars, lvl+1)?
     };
 
-    TypeScheme::PolymorphicType {
+    Ok(TypeScheme::PolymorphicType {
         level: lvl,
         body: res
+    })
+}
+
+/// Infers the types of mutually recursive definitions, each of which may refer
+/// to all of them, generalising them together.
+pub fn infer_rec_group(bindings: &[Let], ctx: Ctx, tvars: TVars, lvl: i32)
+                       -> TypeResult<Vec<TypeScheme>> {
+    use TypeScheme::*;
+    let vars: Vec<SimpleTypeRef> = bindings.iter()
+        .map(|_| tvars.fresh_var(lvl + 1))
+        .collect();
+    ctx.in_child_scope(|ctx2| {
+        for (binding, e) in bindings.iter().zip(&vars) {
+            ctx2.insert(&binding.name, SimpleType(e.clone()));
+        }
+        for (binding, e) in bindings.iter().zip(&vars) {
+            let typ = infer_term(&binding.rhs, ctx2, tvars, lvl + 1)?;
+            constrain(typ, e.clone(), tvars)?;
+        }
+        Ok(())
+    })?;
+    Ok(vars.into_iter()
+        .map(|body| PolymorphicType { level: lvl, body })
+        .collect())
+}
+
+/// The definitions each definition in `pgrm` refers to, by name and index. A
+/// name refers to the latest definition of it before the one using it, or else
+/// to the first one after it if that's a function, as functions can be called
+/// before they're defined but values can't be used before they're bound.
+fn pgrm_deps(pgrm: &Pgrm) -> Vec<Vec<(Identifier, usize)>> {
+    let defs = &pgrm.defs;
+    defs.iter().enumerate().map(|(i, (rec, own, rhs))| {
+        let mut names = HashSet::new();
+        rhs.free_vars(&mut names);
+        let mut names: Vec<Identifier> = names.into_iter().collect();
+        names.sort();
+        names.into_iter().filter_map(|name| {
+            let def = if *rec && *own == name { Some(i) } else {
+                (0..i).rev().find(|&j| defs[j].1 == name)
+                    .or_else(|| (i + 1..defs.len()).find(|&j| defs[j].1 == name)
+                              .filter(|j| pgrm.fns.contains(j)))
+            };
+            def.map(|j| (name, j))
+        }).collect()
+    }).collect()
+}
+
+/// Splits the definitions into groups which refer to each other, i.e. the
+/// strongly connected components of `deps`, with each group after those it
+/// refers to.
+fn pgrm_groups(deps: &[Vec<(Identifier, usize)>]) -> Vec<Vec<usize>> {
+    struct Tarjan<'a> {
+        deps: &'a [Vec<(Identifier, usize)>],
+        index: Vec<Option<usize>>,
+        low: Vec<usize>,
+        stack: Vec<usize>,
+        on_stack: Vec<bool>,
+        next: usize,
+        groups: Vec<Vec<usize>>
+    }
+
+    impl Tarjan<'_> {
+        fn visit(&mut self, i: usize) {
+            let index = self.next;
+            self.next += 1;
+            self.index[i] = Some(index);
+            self.low[i] = index;
+            self.stack.push(i);
+            self.on_stack[i] = true;
+            for &(_, j) in &self.deps[i] {
+                match self.index[j] {
+                    None => {
+                        self.visit(j);
+                        self.low[i] = self.low[i].min(self.low[j]);
+                    }
+                    Some(index) if self.on_stack[j] =>
+                        self.low[i] = self.low[i].min(index),
+                    Some(_) => ()
+                }
+            }
+            if self.low[i] == index {
+                let start = self.stack.iter().position(|&j| j == i).unwrap();
+                let mut group = self.stack.split_off(start);
+                for &j in &group { self.on_stack[j] = false; }
+                group.sort_unstable();
+                self.groups.push(group);
+            }
+        }
+    }
+
+    let n = deps.len();
+    let mut tarjan = Tarjan {
+        deps,
+        index: vec![None; n],
+        low: vec![0; n],
+        stack: Vec::new(),
+        on_stack: vec![false; n],
+        next: 0,
+        groups: Vec::new()
+    };
+    for i in 0..n {
+        if tarjan.index[i].is_none() { tarjan.visit(i) }
+    }
+    tarjan.groups
+}
+
+/// Infers the types of the definitions in `pgrm`, binding each one in `ctx`.
+/// Definitions which refer to each other, like mutually recursive functions,
+/// are inferred as a group, and each group is generalised before the ones
+/// which use it. The types are returned in the order of the definitions.
+pub fn infer_pgrm(pgrm: &Pgrm, ctx: Ctx, tvars: TVars)
+                  -> TypeResult<Vec<(Identifier, SimpleTypeRef)>> {
+    let deps = pgrm_deps(pgrm);
+    let mut schemes: Vec<Option<TypeScheme>> = vec![None; pgrm.defs.len()];
+    for group in pgrm_groups(&deps) {
+        // Names may be redefined, so bind the definitions this group uses.
+        for &i in &group {
+            for (name, j) in &deps[i] {
+                if let Some(scheme) = &schemes[*j] {
+                    ctx.insert(name, scheme.clone());
+                }
+            }
+        }
+        let bindings: Vec<Let> = group.iter().map(|&i| {
+            let (rec, name, rhs) = &pgrm.defs[i];
+            Let { rec: *rec, name: name.clone(), rhs: Rc::new(rhs.clone()) }
+        }).collect();
+        let group_schemes = match bindings.as_slice() {
+            [binding] => vec![infer_let(binding, ctx, tvars, 0)?],
+            bindings => infer_rec_group(bindings, ctx, tvars, 0)?
+        };
+        for (&i, scheme) in group.iter().zip(group_schemes) {
+            schemes[i] = Some(compact_scheme(scheme, tvars));
+        }
+    }
+    pgrm.defs.iter().zip(schemes).map(|((_, name, _), scheme)| {
+        let scheme = scheme.unwrap();
+        ctx.insert(name, scheme.clone());
+        Ok((name.clone(), scheme.instantiate(tvars, 0)))
+    }).collect()
+}
+
+/// Replaces the body of `scheme` with one rebuilt from its simplified type, so
+/// that using the definition doesn't copy every bound found while inferring
+/// it. Without this, each definition in a chain instantiates a bigger type
+/// than the one before it. The scheme is kept as it is if the simplified type
+/// can't be rebuilt.
+fn compact_scheme(scheme: TypeScheme, tvars: TVars) -> TypeScheme {
+    match scheme {
+        TypeScheme::PolymorphicType { level, body } => {
+            let typ = expand_type(body.clone(), tvars);
+            let mut vars = HashMap::new();
+            let body = rebuild_type(&typ, true, level + 1, &mut vars, tvars)
+                .unwrap_or(body);
+            TypeScheme::PolymorphicType { level, body }
+        }
+        scheme => scheme
     }
 }
 
+/// Builds a `SimpleType` at level `lvl` standing for `typ`, with unions in
+/// positive positions and intersections in negative ones as the bounds of new
+/// type variables. Returns `None` for the types which can't be written this way.
+fn rebuild_type(typ: &Type, polarity: bool, lvl: i32,
+                vars: &mut HashMap<TVarId, SimpleTypeRef>, tvars: TVars)
+                -> Option<SimpleTypeRef> {
+    let prov = Provenance::default();
+    let bounded = |types: &[Type], vars: &mut HashMap<TVarId, SimpleTypeRef>,
+                   tvars: &mut TVarRegistry| {
+        let bounds = types.iter()
+            .map(|t| rebuild_type(t, polarity, lvl, vars, tvars))
+            .collect::<Option<Vec<_>>>()?;
+        let v = tvars.fresh_var(lvl);
+        let var = tvars.get_mut(&v.tvar_id().unwrap());
+        if polarity { var.lower_bounds = bounds } else { var.upper_bounds = bounds }
+        Some(v)
+    };
+    Some(match typ {
+        Type::Top if !polarity => tvars.fresh_var(lvl),
+        Type::Bot if polarity => tvars.fresh_var(lvl),
+        Type::Union(types) if polarity => bounded(types, vars, tvars)?,
+        Type::Inter(types) if !polarity => bounded(types, vars, tvars)?,
+        Type::Top | Type::Bot | Type::Union(_) | Type::Inter(_) => return None,
+        Type::Function(lhs, rhs) => Rc::new(SimpleType::Function {
+            lhs: rebuild_type(lhs, !polarity, lvl, vars, tvars)?,
+            rhs: rebuild_type(rhs, polarity, lvl, vars, tvars)?,
+            prov
+        }),
+        Type::Record(fields) => Rc::new(SimpleType::Record {
+            fields: fields.iter()
+                .map(|(k, t)| Some((k.clone(), rebuild_type(t, polarity, lvl, vars, tvars)?)))
+                .collect::<Option<_>>()?,
+            prov
+        }),
+        Type::Primitive(name) => Rc::new(SimpleType::Primitive { name: name.clone(), prov }),
+        Type::Variable(id) => vars.entry(*id)
+            .or_insert_with(|| tvars.fresh_var(lvl)).clone(),
+        Type::Recursive(id, body) => {
+            let v = vars.entry(*id).or_insert_with(|| tvars.fresh_var(lvl)).clone();
+            let body = rebuild_type(body, polarity, lvl, vars, tvars)?;
+            let var = tvars.get_mut(&v.tvar_id().unwrap());
+            if polarity { var.lower_bounds.push(body) } else { var.upper_bounds.push(body) }
+            v
+        }
+    })
+}
+
 pub fn freshen_above(lim: i32, typ: SimpleTypeRef, tvars: TVars, lvl: i32,
                      freshened: &mut HashMap<TVarId, TVarId>) -> SimpleTypeRef {
     use SimpleType::*;
@@ -204,26 +502,207 @@ pub fn freshen_above(lim: i32, typ: SimpleTypeRef, tvars: TVars, lvl: i32,
                 Variable(key2)
             }
         }
-        Function { lhs, rhs } => Function {
+        Function { lhs, rhs, prov } => Function {
             lhs: freshen_above(lim, lhs.clone(), tvars, lvl, freshened),
             rhs: freshen_above(lim, rhs.clone(), tvars, lvl, freshened),
+            prov: prov.clone()
         },
-        Record { fields } => Record {
+        Record { fields, prov } => Record {
             fields: fields.iter().map(|(k, v)| (
                 k.clone(),
                 freshen_above(lim, v.clone(), tvars, lvl, freshened)
-            )).collect()
+            )).collect(),
+            prov: prov.clone()
         },
-        Primitive { name } => Primitive { name: name.clone() },
+        Primitive { .. } => return typ,
     };
     Rc::new(res)
 }
 
-// fn infer_types()
+/// Coalesces the bounds of the type variables in `typ` into a `Type`, with the
+/// lower bounds of those in positive positions as unions and the upper bounds
+/// of those in negative positions as intersections.
+pub fn coalesce_type(typ: SimpleTypeRef, tvars: TVars) -> Type {
+    coalesce(typ, true, &mut HashSet::new(), &mut HashMap::new(), &HashSet::new(),
+             tvars)
+}
+
+/// `unguarded` holds the variables whose bounds are being coalesced, and
+/// which haven't been left by going into a function or record type since.
+fn coalesce(typ: SimpleTypeRef, polarity: bool,
+            in_process: &mut HashSet<(TVarId, bool)>,
+            recursive: &mut HashMap<(TVarId, bool), TVarId>,
+            unguarded: &HashSet<(TVarId, bool)>,
+            tvars: TVars) -> Type {
+    use SimpleType::*;
+    match &*typ {
+        Primitive { name, .. } => Type::Primitive(name.clone()),
+        Function { lhs, rhs, .. } => Type::Function(
+            Box::new(coalesce(lhs.clone(), !polarity, in_process, recursive,
+                              &HashSet::new(), tvars)),
+            Box::new(coalesce(rhs.clone(), polarity, in_process, recursive,
+                              &HashSet::new(), tvars))
+        ),
+        Record { fields, .. } => {
+            let mut fields: Vec<_> = fields.iter().map(|(k, v)| (
+                k.clone(),
+                coalesce(v.clone(), polarity, in_process, recursive,
+                         &HashSet::new(), tvars)
+            )).collect();
+            fields.sort_by(|(k0, _), (k1, _)| k0.cmp(k1));
+            Type::Record(fields)
+        }
+        Variable(key) => {
+            let polar = (*key, polarity);
+            // A variable which is among its own bounds adds nothing to them.
+            if unguarded.contains(&polar) {
+                return if polarity { Type::Bot } else { Type::Top };
+            }
+            // A variable whose bounds refer back to it is a recursive type.
+            if in_process.contains(&polar) {
+                if let Some(var) = recursive.get(&polar) {
+                    return Type::Variable(*var);
+                }
+                let var = tvars.fresh_var(0).tvar_id().unwrap();
+                recursive.insert(polar, var);
+                return Type::Variable(var);
+            }
+            let bounds = if polarity { tvars.get(key).lower_bounds.clone() }
+                         else { tvars.get(key).upper_bounds.clone() };
+            in_process.insert(polar);
+            let mut unguarded = unguarded.clone();
+            unguarded.insert(polar);
+            let mut types = vec![Type::Variable(*key)];
+            for bound in bounds {
+                types.push(coalesce(bound, polarity, in_process, recursive,
+                                    &unguarded, tvars));
+            }
+            in_process.remove(&polar);
+            let res = if polarity { Type::union(types) } else { Type::inter(types) };
+            match recursive.get(&polar) {
+                Some(var) => Type::Recursive(*var, Box::new(res)),
+                None => res
+            }
+        }
+    }
+}
+
+/// The other variables and primitives which a variable occurs alongside in a
+/// union or intersection, for each of its occurrences of each polarity.
+type Occurrences = HashMap<TVarId, (Vec<Vec<Type>>, Vec<Vec<Type>>)>;
+
+fn occurrences(typ: &Type, polarity: bool, occs: &mut Occurrences,
+               recursive: &mut HashSet<TVarId>) {
+    let occur = |occs: &mut Occurrences, key: &TVarId, others: Vec<Type>| {
+        let entry = occs.entry(*key).or_default();
+        if polarity { entry.0.push(others) } else { entry.1.push(others) }
+    };
+    match typ {
+        Type::Variable(key) => occur(occs, key, Vec::new()),
+        Type::Union(types) | Type::Inter(types) => for typ in types {
+            match typ {
+                Type::Variable(key) => occur(occs, key, types.iter()
+                    .filter(|t| *t != typ && matches!(t, Type::Variable(_) | Type::Primitive(_)))
+                    .cloned().collect()),
+                typ => occurrences(typ, polarity, occs, recursive)
+            }
+        }
+        Type::Function(lhs, rhs) => {
+            occurrences(lhs, !polarity, occs, recursive);
+            occurrences(rhs, polarity, occs, recursive);
+        }
+        Type::Record(fields) => for (_, typ) in fields {
+            occurrences(typ, polarity, occs, recursive);
+        }
+        Type::Recursive(key, body) => {
+            recursive.insert(*key);
+            occurrences(body, polarity, occs, recursive);
+        }
+        Type::Top | Type::Bot | Type::Primitive(_) => {}
+    }
+}
+
+/// The types which occur alongside a variable in every one of `occs`.
+fn always_with(occs: &[Vec<Type>]) -> Vec<Type> {
+    match occs.split_first() {
+        Some((first, rest)) => first.iter()
+            .filter(|t| rest.iter().all(|others| others.contains(t)))
+            .cloned().collect(),
+        None => Vec::new()
+    }
+}
+
+/// Simplifies a coalesced type by removing type variables which don't
+/// constrain anything: those which occur in only one polarity, and those
+/// which always occur alongside the same primitive or variable.
+pub fn simplify_type(typ: Type) -> Type {
+    let mut occs = Occurrences::new();
+    let mut recursive = HashSet::new();
+    occurrences(&typ, true, &mut occs, &mut recursive);
+
+    let mut keys: Vec<TVarId> = occs.keys().cloned().collect();
+    keys.sort();
+    let mut subst: HashMap<TVarId, Option<TVarId>> = HashMap::new();
+    for key in keys {
+        if recursive.contains(&key) {
+            continue;
+        }
+        let (pos, neg) = &occs[&key];
+        if pos.is_empty() || neg.is_empty() {
+            subst.insert(key, None);
+            continue;
+        }
+        let (pos, neg) = (always_with(pos), always_with(neg));
+        for typ in pos.iter().filter(|t| neg.contains(t)) {
+            match typ {
+                Type::Primitive(_) => {
+                    subst.insert(key, None);
+                    break;
+                }
+                Type::Variable(other) if !subst.contains_key(other) => {
+                    subst.insert(key, Some(*other));
+                    break;
+                }
+                _ => {}
+            }
+        }
+    }
+    substitute(typ, true, &subst)
+}
+
+fn substitute(typ: Type, polarity: bool,
+              subst: &HashMap<TVarId, Option<TVarId>>) -> Type {
+    match typ {
+        Type::Variable(key) => match subst.get(&key) {
+            Some(Some(other)) => Type::Variable(*other),
+            Some(None) => if polarity { Type::Bot } else { Type::Top },
+            None => Type::Variable(key)
+        }
+        Type::Union(types) => Type::union(types.into_iter()
+            .map(|t| substitute(t, polarity, subst)).collect()),
+        Type::Inter(types) => Type::inter(types.into_iter()
+            .map(|t| substitute(t, polarity, subst)).collect()),
+        Type::Function(lhs, rhs) => Type::Function(
+            Box::new(substitute(*lhs, !polarity, subst)),
+            Box::new(substitute(*rhs, polarity, subst))
+        ),
+        Type::Record(fields) => Type::Record(fields.into_iter()
+            .map(|(k, t)| (k, substitute(t, polarity, subst))).collect()),
+        Type::Recursive(key, body) =>
+            Type::Recursive(key, Box::new(substitute(*body, polarity, subst))),
+        typ => typ
+    }
+}
+
+/// Coalesces and simplifies an inferred type for showing to users.
+pub fn expand_type(typ: SimpleTypeRef, tvars: TVars) -> Type {
+    simplify_type(coalesce_type(typ, tvars))
+}
+
 
 #[test]
 pub fn test_smt() -> SmtRes<()> {
-    let mut solver = Solver::default(())?;
+    let mut solver = Solver::default_z3(())?;
 
     solver.declare_const("n", "Int")?;
     solver.declare_const("m", "Int")?;
@@ -237,7 +716,7 @@ pub fn test_smt() -> SmtRes<()> {
 
 #[test]
 pub fn test_smt2() -> SmtRes<()> {
-    let mut solver = Solver::default(())?;
+    let mut solver = Solver::default_z3(())?;
 
     solver.declare_sort("Type", 0)?;
     solver.declare_const("int", "Type")?;
@@ -260,24 +739,167 @@ pub fn test_smt2() -> SmtRes<()> {
 
 #[test]
 pub fn test_inference() {
+    use crate::prelude::prelude;
     use SimpleType::*;
     use Term::*;
-    let mut ctx: Context<TypeScheme> = Context::new();
-    let bool_type: Rc<_> = Primitive { name: "bool".into() }.into();
-    let not_type = Function { lhs: bool_type.clone(),
-                              rhs: bool_type.clone() }.into();
-    ctx.insert("true", bool_type.clone().into());
-    ctx.insert("not", not_type);
-
     let mut tvars = TVarRegistry::new();
+    let mut ctx = prelude(&mut tvars);
 
     let not_true_type =
-        infer_term(&App(Box::new(Var("not".into())),
-                        Box::new(Var("true".into()))), &mut ctx, &mut tvars, 0);
+        infer_term(&Rc::new(App(Rc::new(Var("not".into())),
+                                Rc::new(Lit(Literal::Bool(true))))), &mut ctx, &mut tvars, 0)
+        .unwrap();
 
     if let Variable(key) = *not_true_type {
         assert_eq!(tvars.get(&key).lower_bounds.len(), 1);
-        assert_eq!(*tvars.get(&key).lower_bounds.get(0).unwrap(),
-                   bool_type.clone());
+        // The result of `not` is attributed to it.
+        let result_type = Primitive {
+            name: "bool".into(),
+            prov: Provenance::of(&Rc::new(Var("not".into())))
+        };
+        assert_eq!(*tvars.get(&key).lower_bounds[0], result_type);
+    }
+    assert_eq!("bool", expand_type(not_true_type, &mut tvars).to_string());
+    let not_type = infer_term(&Rc::new(Var("not".into())), &mut ctx, &mut tvars, 0).unwrap();
+    assert_eq!("bool -> bool", expand_type(not_type, &mut tvars).to_string());
+}
+
+#[test]
+pub fn test_infer_pgrm() {
+    use crate::lower::lower_program;
+    use crate::prelude::prelude;
+    let mut tvars = TVarRegistry::new();
+    let mut ctx = prelude(&mut tvars);
+    let pgrm = lower_program(r"
+fn inc = 1 +
+fn fib = dup 2 <= [ drop 1 ] [ 1 - dup 1 - fib swap fib + ] cond
+let a = 5 fib inc
+fn id x = x
+fn first x y = x
+fn select c x y = c [x] [y] cond
+fn getx r = r .x
+fn norm1 r = r .x r .y +
+let p = [ 'x 1 'y true ] record
+let px = p getx
+fn even n = n 2 % 0 ==
+fn xor a b = a b or a b and not and
+let t = true not
+").unwrap();
+    let types = infer_pgrm(&pgrm, &mut ctx, &mut tvars).unwrap();
+
+    let types: Vec<(String, String)> = types.into_iter()
+        .map(|(name, typ)| (name.to_string(),
+                            expand_type(typ, &mut tvars).to_string()))
+        .collect();
+    let expected = [
+        ("inc", "int -> int"),
+        ("fib", "int -> int"),
+        ("a", "int"),
+        ("id", "'a -> 'a"),
+        ("first", "'a -> top -> 'a"),
+        ("select", "bool -> 'a -> 'a -> 'a"),
+        ("getx", "{x: 'a} -> 'a"),
+        ("norm1", "{x: int, y: int} -> int"),
+        ("p", "{x: int, y: bool}"),
+        ("px", "int"),
+        ("even", "int -> bool"),
+        ("xor", "bool -> bool -> bool"),
+        ("t", "bool"),
+    ];
+    assert_eq!(expected.len(), types.len());
+    for ((name, typ), (expected_name, expected_typ)) in types.iter().zip(&expected) {
+        assert_eq!((expected_name, expected_typ), (&name.as_str(), &typ.as_str()));
+    }
+}
+
+#[test]
+pub fn test_infer_pgrm_groups() {
+    use crate::lower::lower_program;
+    use crate::prelude::prelude;
+    let mut tvars = TVarRegistry::new();
+    let mut ctx = prelude(&mut tvars);
+    let pgrm = lower_program(r"
+fn id a = a
+let i = 1 id
+let b = true id
+fn even n = n 0 == [ true ] [ n 1 - odd ] cond
+fn odd n = n 0 == [ false ] [ n 1 - even ] cond
+let e = 4 even
+").unwrap();
+    let types = infer_pgrm(&pgrm, &mut ctx, &mut tvars).unwrap();
+    let types: Vec<(String, String)> = types.into_iter()
+        .map(|(name, typ)| (name.to_string(),
+                            expand_type(typ, &mut tvars).to_string()))
+        .collect();
+    let expected = [
+        ("id", "'a -> 'a"),
+        ("i", "int"),
+        ("b", "bool"),
+        ("even", "int -> bool"),
+        ("odd", "int -> bool"),
+        ("e", "bool"),
+    ];
+    assert_eq!(expected.len(), types.len());
+    for ((name, typ), (expected_name, expected_typ)) in types.iter().zip(&expected) {
+        assert_eq!((expected_name, expected_typ), (&name.as_str(), &typ.as_str()));
     }
 }
+
+#[test]
+pub fn test_recursive_constraints() {
+    use crate::lower::lower_program;
+    use crate::prelude::prelude;
+    let mut tvars = TVarRegistry::new();
+    let mut ctx = prelude(&mut tvars);
+    // The parameter of `f` and the value it's called with are bounded by each
+    // other, so this only terminates because constraints are cached.
+    let pgrm = lower_program(
+        "fn clamp n x = n 0 == [ x ] [ n 1 - x 0 > [ x ] [ 0 ] cond clamp ] cond").unwrap();
+    let types = infer_pgrm(&pgrm, &mut ctx, &mut tvars).unwrap();
+    let (_, typ) = types.into_iter().next().unwrap();
+    assert_eq!("int -> int -> int", expand_type(typ, &mut tvars).to_string());
+}
+
+#[test]
+pub fn test_type_errors() {
+    use crate::lower::lower_program;
+    use crate::prelude::prelude;
+    let error = |program: &str| {
+        let mut tvars = TVarRegistry::new();
+        let mut ctx = prelude(&mut tvars);
+        let pgrm = lower_program(program).unwrap();
+        infer_pgrm(&pgrm, &mut ctx, &mut tvars).unwrap_err()
+    };
+
+    let e = error("fn bad = true 1 +");
+    assert_eq!("expected int (from `+`) but found bool (from `true`)", e.to_string());
+    let e = error("fn bad = 1 [2] [3] cond");
+    assert_eq!("expected bool (from `cond`) but found int (from `1`)", e.to_string());
+    let e = error("fn bad x = x [2] [true] cond 1 +");
+    assert_eq!("expected int (from `+`) but found bool (from `true`)", e.to_string());
+    let e = error("fn bad = [ 'x 1 ] record .y");
+    assert_eq!("missing field y (used by `{x = 1}.y`) in {x: int} (from `{x = 1}`)",
+               e.to_string());
+
+    // Only functions can be used before they're defined.
+    let mut pgrm = Pgrm {
+        defs: vec![(false, "x".into(), Term::Var("y".into())),
+                   (false, "y".into(), Term::Lit(Literal::Int(1)))],
+        fns: HashSet::new()
+    };
+    let mut tvars = TVarRegistry::new();
+    let mut ctx = prelude(&mut tvars);
+    let e = infer_pgrm(&pgrm, &mut ctx, &mut tvars);
+    assert_eq!(Err(TypeError::UnboundVariable("y".into())), e);
+    pgrm.fns.insert(1);
+    let mut tvars = TVarRegistry::new();
+    let mut ctx = prelude(&mut tvars);
+    assert!(infer_pgrm(&pgrm, &mut ctx, &mut tvars).is_ok());
+
+    let mut tvars = TVarRegistry::new();
+    let mut ctx = prelude(&mut tvars);
+    let e = infer_term(&Rc::new(Term::Var("undefined".into())), &mut ctx, &mut tvars, 0);
+    assert_eq!(Err(TypeError::UnboundVariable("undefined".into())), e);
+    let e = infer_term(&Rc::new(Term::Var("splat".into())), &mut ctx, &mut tvars, 0);
+    assert_eq!("untyped builtin splat can't be inferred", e.unwrap_err().to_string());
+}
@@ -12,7 +12,7 @@
 (define-const int1 LType (cons_int nil))
 (define-const int2 LType (cons_int int1))
 (define-const int3 LType (cons_int int2))
-(define-const bool1 LType (cons_int nil))
+(define-const bool1 LType (cons_bool nil))
 
 (declare-datatypes (T) (
   (Option (none) (some (thing T)))
@@ -97,6 +97,8 @@
   )
 )
 
+; Examples
+
 (push)
 (define-const add Type (func int2 int1))
 (declare-const A Type)
@@ -1,47 +1,76 @@
-use crate::vm::{Env, Identifier};
+use crate::vm::{Identifier, InlinableString};
+use crate::vm2::Env;
+
+use im_rc::Vector;
+use std::rc::Rc;
 
 pub type NumType = i32;
 pub type IsFunction = bool;
 
 pub type Arity = Option<(u8, u8)>;
 
+/// A builtin word. Builtins are told apart by name, so an op is the same
+/// whichever thread's registry it came from.
 #[derive(Clone)]
 pub struct Op {
-    pub f: fn(&mut Env),
+    pub name: &'static str,
+    /// A closure rather than a function pointer, so that words registered by
+    /// embedders can capture state.
+    pub f: Rc<dyn Fn(&mut Env)>,
     pub arity: Arity,
+    /// The stack effect, like `int int -- int`.
+    pub signature: &'static str,
+    pub doc: &'static str,
 }
 
 use std::fmt;
 impl fmt::Debug for Op {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        write!(f, "Op {}", self.f as usize)
+        f.write_str(self.name)
+    }
+}
+
+impl fmt::Display for Op {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(self.name)
     }
 }
 
 impl PartialEq for Op {
     fn eq(&self, other: &Self) -> bool {
-        self.f as usize == other.f as usize
+        self.name == other.name
     }
 }
 
 impl PartialEq<&Op> for Op {
     fn eq(&self, other: &&Op) -> bool {
-        self.f as usize == other.f as usize
+        self.name == other.name
     }
 }
 
 use std::hash::{Hash, Hasher};
 impl Hash for Op {
     fn hash<H: Hasher>(&self, state: &mut H) {
-        (self.f as usize).hash(state);
+        self.name.hash(state);
     }
 }
 
 impl Eq for Op {}
 
 impl Op {
-    pub fn new(f: fn(&mut Env), arity: Arity) -> Self {
-        Self { f, arity }
+    /// The builtin called `name`, documented by its entry in `ops::DOCS`.
+    pub fn new(name: &'static str, f: impl Fn(&mut Env) + 'static, arity: Arity) -> Self {
+        let (signature, doc) = match crate::ops::DOCS.iter().find(|(n, _, _)| *n == name) {
+            Some(&(_, signature, doc)) => (signature, doc),
+            None => ("", ""),
+        };
+        Self {
+            name,
+            f: Rc::new(f),
+            arity,
+            signature,
+            doc,
+        }
     }
 }
 
@@ -49,22 +78,61 @@ impl Op {
 pub enum Atom {
     Bool(bool),
     Num(NumType),
+    Str(InlinableString),
 
-    List(Vec<Atom>),
+    /// A persistent vector, so that copying and updating lists is cheap.
+    List(Vector<Atom>),
+    /// Fields sorted by name, as built by `record`.
+    Record(Vec<(Identifier, Atom)>),
 
     Op(Op),
 
     QuotationStart, // [
     QuotationEnd,   // ]
-    Quotation(Vec<Atom>),
-    Function(Vec<Identifier>, Vec<Atom>),
+    /// Bodies are shared, so that copying them is cheap.
+    Quotation(Rc<Vec<Atom>>),
+    /// Parameters, body and declared stack effect.
+    Function(Vec<Identifier>, Rc<Vec<Atom>>, Arity),
 
     DefVar,
+    RedefVar,
     DefVarLiteral,
     DefFnLiteral,
 
     Call,
+    Concat(Vec<Atom>),  // +,+
+    Select(Identifier), // .a
 
     Symbol(Identifier),
     Plain(Identifier),
 }
+
+impl From<bool> for Atom {
+    fn from(b: bool) -> Self {
+        Atom::Bool(b)
+    }
+}
+
+impl From<NumType> for Atom {
+    fn from(n: NumType) -> Self {
+        Atom::Num(n)
+    }
+}
+
+impl From<&str> for Atom {
+    fn from(s: &str) -> Self {
+        Atom::Str(InlinableString::from(s))
+    }
+}
+
+impl From<String> for Atom {
+    fn from(s: String) -> Self {
+        Atom::Str(InlinableString::from(s))
+    }
+}
+
+impl<T: Into<Atom>> From<Vec<T>> for Atom {
+    fn from(v: Vec<T>) -> Self {
+        Atom::List(v.into_iter().map(Into::into).collect())
+    }
+}
@@ -1,138 +1,156 @@
 pub use inlinable_string::InlinableString;
-use std::collections::HashMap;
 use crate::types::Atom;
 
-pub const SPECIAL_IDENTS: [&str; 6] = ["call", "let", "fn", "true", "false", "not"];
+use std::cell::RefCell;
+use std::cmp::Ordering;
+use std::collections::HashMap;
+use std::fmt;
+use std::hash::{Hash, Hasher};
+use std::marker::PhantomData;
+use std::ops::Deref;
+
+pub const SPECIAL_IDENTS: [&str; 7] = ["call", "let", "fn", "redef", "true", "false", "not"];
 pub type Stack = Vec<Atom>;
-pub type Identifier = InlinableString;
 
-#[derive(Debug)]
-pub struct Context(HashMap<InlinableString, Atom>);
+/// The names interned so far, which are never freed, so names made up while
+/// running shouldn't be interned.
+#[derive(Default)]
+struct Interner {
+    ids: HashMap<&'static str, u32>,
+    names: Vec<&'static str>,
+}
 
-impl Context {
-    fn new() -> Context {
-        Context(HashMap::new())
-    }
+thread_local! {
+    static INTERNER: RefCell<Interner> = RefCell::new(Interner::default());
+}
 
-    fn with_capacity(cap: usize) -> Context {
-        Context(HashMap::with_capacity(cap))
-    }
+/// An interned name, so that comparing and hashing names is as cheap as for an
+/// integer. Names are interned per thread, so identifiers can't be sent
+/// between threads.
+#[derive(Clone, PartialEq, Eq)]
+pub struct Identifier {
+    id: u32,
+    thread: PhantomData<*const ()>,
+}
 
-    fn get(&self, ident: &str) -> Option<&Atom> {
-        self.0.get(ident)
+impl Identifier {
+    pub fn new(name: &str) -> Self {
+        let id = INTERNER.with(|interner| {
+            let mut interner = interner.borrow_mut();
+            if let Some(&id) = interner.ids.get(name) {
+                return id;
+            }
+            let name: &'static str = Box::leak(name.to_string().into_boxed_str());
+            let id = interner.names.len() as u32;
+            interner.names.push(name);
+            interner.ids.insert(name, id);
+            id
+        });
+        Self { id, thread: PhantomData }
     }
 
-    fn insert(&mut self, ident: &str, atom: Atom) {
-        use crate::ops;
-        if SPECIAL_IDENTS.contains(&ident)
-            || ops::get_boolean_op(ident).is_some()
-            || ops::get_stack_op(ident).is_some()
-        {
-            panic!("Attempted to rebind reserved word {}.", ident);
-        }
+    pub fn id(&self) -> u32 {
+        self.id
+    }
 
-        if self.0.insert(InlinableString::from(ident), atom).is_some() {
-            panic!("Attempted to rebind existing variable {}.", ident);
-        }
+    pub fn as_str(&self) -> &'static str {
+        INTERNER.with(|interner| interner.borrow().names[self.id as usize])
     }
 }
 
-#[derive(Debug)]
-pub struct Frame {
-    pub stack: Stack,
-    pub context: Context,
-    pub lazy: bool,
+impl Deref for Identifier {
+    type Target = str;
+    fn deref(&self) -> &str {
+        self.as_str()
+    }
 }
 
-fn blank_frame() -> Frame {
-    Frame {
-        stack: Stack::with_capacity(10),
-        context: Context::new(),
-        lazy: false,
+impl AsRef<str> for Identifier {
+    fn as_ref(&self) -> &str {
+        self.as_str()
     }
 }
 
-#[derive(Debug, Default)]
-pub struct Env {
-    frames: Vec<Frame>,
-    pub loop_like: bool,
-    pub using_for_else: bool,
-    pub for_else: bool,
+impl From<&str> for Identifier {
+    fn from(name: &str) -> Self {
+        Self::new(name)
+    }
 }
 
-impl Env {
-    pub fn new() -> Env {
-        Env {
-            frames: vec![blank_frame()],
-            loop_like: false,
-            using_for_else: false,
-            for_else: true,
-        }
+impl From<String> for Identifier {
+    fn from(name: String) -> Self {
+        Self::new(&name)
     }
+}
 
-    pub fn last_frame(&mut self) -> &mut Frame {
-        if let Some(frame) = self.frames.last_mut() {
-            frame
-        } else {
-            panic!("Tried to get a frame from an empty stack.")
-        }
+impl From<&String> for Identifier {
+    fn from(name: &String) -> Self {
+        Self::new(name)
     }
+}
 
-    pub fn append_atoms(&mut self, mut atoms: Vec<Atom>) {
-        self.last_frame().stack.append(&mut atoms)
+impl From<&Identifier> for Identifier {
+    fn from(ident: &Identifier) -> Self {
+        ident.clone()
     }
+}
 
-    pub fn push_atom(&mut self, atom: Atom) {
-        self.last_frame().stack.push(atom)
+impl Hash for Identifier {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.id.hash(state);
     }
+}
 
-    pub fn pop_atom(&mut self) -> Atom {
-        if let Some(a) = self.last_frame().stack.pop() {
-            a
+/// Identifiers are ordered by name, so that e.g. the fields of records are
+/// sorted alphabetically.
+impl Ord for Identifier {
+    fn cmp(&self, other: &Self) -> Ordering {
+        if self.id == other.id {
+            Ordering::Equal
         } else {
-            panic!("Popped atom from empty frame");
+            self.as_str().cmp(other.as_str())
         }
     }
+}
 
-    pub fn push_blank(&mut self, lazy: bool) {
-        let mut f = blank_frame();
-        f.lazy = lazy;
-        self.frames.push(f)
-    }
-
-    pub fn pop(&mut self) -> Option<Frame> {
-        self.frames.pop()
+impl PartialOrd for Identifier {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
     }
+}
 
-    pub fn bind_var(&mut self, ident: &str, atom: Atom) {
-        self.last_frame().context.insert(ident, atom)
+impl PartialEq<str> for Identifier {
+    fn eq(&self, other: &str) -> bool {
+        self.as_str() == other
     }
+}
 
-    pub fn bind_params(&mut self, idents: &Vec<Identifier>) {
-        let mut bound_params = Context::with_capacity(idents.len());
-        for ident in idents.iter().rev() {
-            bound_params.insert(ident, self.pop_atom())
-        }
-        self.push_blank(false);
-        self.last_frame().context = bound_params;
+impl PartialEq<&str> for Identifier {
+    fn eq(&self, other: &&str) -> bool {
+        self.as_str() == *other
     }
+}
 
-    pub fn unbind_params(&mut self) {
-        let mut frame = self.pop().unwrap();
-        self.last_frame().stack.append(&mut frame.stack)
+impl fmt::Debug for Identifier {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        fmt::Debug::fmt(self.as_str(), f)
     }
+}
 
-    pub fn find_var(&self, ident: &Identifier) -> Option<Atom> {
-        for frame in self.frames.iter().rev() {
-            let context = &frame.context;
-            if let Some(atom) = context.get(ident) {
-                return Some(atom.clone());
-            }
-        }
-        None
+impl fmt::Display for Identifier {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(self.as_str())
     }
+}
 
-    pub fn lazy_mode(&self) -> bool {
-        self.frames.last().unwrap().lazy
-    }
+#[test]
+fn test_identifiers() {
+    let a = Identifier::from("a");
+    let b = Identifier::from("b");
+    assert_eq!(a, Identifier::from(String::from("a")));
+    assert_ne!(a, b);
+    assert_eq!(b.id(), Identifier::from("b").id());
+    assert_eq!("a", &*a);
+    assert!(b > a);
+    assert_eq!("\"a\"", format!("{:?}", a));
 }
@@ -1,76 +1,363 @@
 pub use inlinable_string::InlinableString;
 use std::collections::HashMap;
-//use crate::types::Atom;
-//use crate::ast::Expr;
+use crate::arity::ArityCache;
+use crate::types::Atom;
+use crate::vm::{Stack, SPECIAL_IDENTS};
 
-pub const SPECIAL_IDENTS: [&str; 6] = ["call", "let", "fn", "true", "false", "not"];
-//pub type Stack = Vec<Expr>;
-pub type Identifier = InlinableString;
+pub use crate::vm::Identifier;
 
+/// Bindings from names to values in nested scopes. A name bound in a scope
+/// shadows its bindings in the outer ones until the scope is left, which
+/// restores them.
 #[derive(Clone, Debug)]
-// pub struct Context(HashMap<Identifier, Expr>);
 pub struct Context<T: Clone> {
-    map: HashMap<Identifier, T>,
-    stack: Vec<Identifier>,
-    counter: u32,
+    /// The bindings of each name, outermost first, with the depth of the scope
+    /// each is in.
+    map: HashMap<Identifier, Vec<(usize, T)>>,
+    /// The names bound in each scope, outermost first.
+    scopes: Vec<Vec<Identifier>>,
+    /// Values which were pushed without a name. They aren't given generated
+    /// names, as names are interned for as long as the thread runs.
+    stack: Vec<T>,
+}
+
+impl<T: Clone> Default for Context<T> {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl<T: Clone> Context<T> {
     pub fn new() -> Self {
         Self {
             map: HashMap::with_capacity(16),
-            stack: Vec::with_capacity(16),
-            counter: 0
+            scopes: vec![Vec::new()],
+            stack: Vec::with_capacity(16)
         }
     }
 
-    pub fn get(&self, ident: &str) -> Option<&T> {
-        self.map.get(ident)
+    /// The number of scopes, including the outermost one.
+    pub fn depth(&self) -> usize {
+        self.scopes.len()
+    }
+
+    pub fn get(&self, ident: impl Into<Identifier>) -> Option<&T> {
+        self.map.get(&ident.into())?.last().map(|(_, elem)| elem)
+    }
+
+    /// The depth of the scope which the binding of `ident` is in, where the
+    /// outermost scope is 0.
+    pub fn scope_of(&self, ident: impl Into<Identifier>) -> Option<usize> {
+        self.map.get(&ident.into())?.last().map(|&(depth, _)| depth)
+    }
+
+    /// Binds `ident` in the innermost scope, returning the value it replaced
+    /// if it was already bound there.
+    pub fn insert(&mut self, ident: impl Into<Identifier>, elem: T) -> Option<T> {
+        self.insert_at(self.scopes.len() - 1, ident, elem)
+    }
+
+    /// Binds `ident` in the outermost scope, under any bindings of it in the
+    /// inner ones.
+    pub fn insert_global(&mut self, ident: impl Into<Identifier>, elem: T) -> Option<T> {
+        self.insert_at(0, ident, elem)
     }
 
-    pub fn insert(&mut self, ident: &str, elem: T) -> Option<T> {
-        // use crate::ops;
-        // if SPECIAL_IDENTS.contains(&ident)
-        //     || ops::get_boolean_op(ident).is_some()
-        //     || ops::get_stack_op(ident).is_some()
-        // {
-        //     panic!("Attempted to rebind reserved word {}.", ident);
-        // }
+    fn insert_at(&mut self, depth: usize, ident: impl Into<Identifier>, elem: T) -> Option<T> {
+        let ident = ident.into();
+        let bindings = self.map.entry(ident.clone()).or_default();
+        let i = bindings.iter().position(|&(d, _)| d >= depth).unwrap_or(bindings.len());
+        match bindings.get_mut(i) {
+            Some((d, old)) if *d == depth => return Some(std::mem::replace(old, elem)),
+            _ => bindings.insert(i, (depth, elem))
+        }
+        self.scopes[depth].push(ident);
+        None
+    }
+
+    pub fn push_scope(&mut self) {
+        self.scopes.push(Vec::new());
+    }
 
-        self.map.insert(Identifier::from(ident), elem)
-        // if self.map.insert(InlinableString::from(ident), atom).is_some() {
-        //     panic!("Attempted to rebind existing variable {}.", ident);
-        // }
+    /// Leaves the innermost scope, removing its bindings.
+    pub fn pop_scope(&mut self) {
+        if self.scopes.len() == 1 {
+            panic!("Tried to leave the outermost scope.");
+        }
+        for ident in self.scopes.pop().unwrap() {
+            let bindings = self.map.get_mut(&ident).unwrap();
+            bindings.pop();
+            if bindings.is_empty() {
+                self.map.remove(&ident);
+            }
+        }
     }
 
+    /// Pushes `elem` without binding it to a name, for `pop` to take back.
     pub fn push(&mut self, elem: T) {
-        let new_id = Identifier::from(format!("_{}", self.counter));
-        self.map.insert(new_id.clone(), elem);
-        self.stack.push(new_id);
-        self.counter += 1;
+        self.stack.push(elem);
     }
 
-    pub fn pop(&mut self) -> &T {
-        self.map.get(&self.stack.pop().unwrap()).unwrap()
+    pub fn pop(&mut self) -> T {
+        self.stack.pop().unwrap()
     }
 
-    pub fn in_child_scope<R>(&self, cb: impl FnOnce(&mut Self) -> R) -> R {
-        // let temp = self.map.clone();
-        // let res = cb(self);
-        // self.map = temp;
-        // res
-        cb(&mut self.clone())
+    /// Runs `cb` in a new scope, so that what it binds is unbound afterwards.
+    pub fn in_child_scope<R>(&mut self, cb: impl FnOnce(&mut Self) -> R) -> R {
+        self.push_scope();
+        let res = cb(self);
+        self.pop_scope();
+        res
     }
 
-    pub fn with_binding<R>(&mut self, ident: &str, elem: T,
+    pub fn with_binding<R>(&mut self, ident: impl Into<Identifier>, elem: T,
                            cb: impl FnOnce(&mut Self) -> R) -> R {
-        let temp = self.insert(ident, elem);
-        let res = cb(self);
-        if let Some(prev) = temp {
-            self.insert(ident, prev);
+        self.in_child_scope(|ctx| {
+            ctx.insert(ident, elem);
+            cb(ctx)
+        })
+    }
+}
+
+fn check_reserved(ident: &str) {
+    if SPECIAL_IDENTS.contains(&ident) || crate::ops::is_builtin(ident) {
+        panic!("Attempted to rebind reserved word {}.", ident);
+    }
+}
+
+#[derive(Debug)]
+pub struct Frame {
+    pub stack: Stack,
+    pub lazy: bool,
+}
+
+fn blank_frame() -> Frame {
+    Frame {
+        stack: Stack::with_capacity(10),
+        lazy: false,
+    }
+}
+
+/// The state of a running program: a stack of frames, each with its own
+/// stack and a scope of the variables bound in it.
+#[derive(Debug)]
+pub struct Env {
+    frames: Vec<Frame>,
+    /// The variables, with a scope for each frame but the first.
+    context: Context<Atom>,
+    pub loop_like: bool,
+    pub using_for_else: bool,
+    pub for_else: bool,
+    /// Whether top-level `let` and `fn` may redefine existing variables, as
+    /// `redef` and `fn!` do. This is meant for the REPL.
+    pub relaxed: bool,
+    /// The functions which captured each variable when they were defined.
+    captured_by: HashMap<Identifier, Vec<Identifier>>,
+    /// Arities inferred so far, which stay valid until a top-level variable is
+    /// bound.
+    pub arities: ArityCache,
+}
+
+impl Default for Env {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Env {
+    pub fn new() -> Env {
+        Env {
+            frames: vec![blank_frame()],
+            context: Context::new(),
+            loop_like: false,
+            using_for_else: false,
+            for_else: true,
+            relaxed: false,
+            captured_by: HashMap::new(),
+            arities: ArityCache::default(),
         }
-        res
     }
+
+    pub fn last_frame(&mut self) -> &mut Frame {
+        if let Some(frame) = self.frames.last_mut() {
+            frame
+        } else {
+            panic!("Tried to get a frame from an empty stack.")
+        }
+    }
+
+    /// The number of frames, including the outermost one.
+    pub fn depth(&self) -> usize {
+        self.frames.len()
+    }
+
+    pub fn stack(&self) -> &Stack {
+        &self.frames.last().unwrap().stack
+    }
+
+    pub fn append_atoms(&mut self, mut atoms: Vec<Atom>) {
+        self.last_frame().stack.append(&mut atoms)
+    }
+
+    pub fn push_atom(&mut self, atom: Atom) {
+        self.last_frame().stack.push(atom)
+    }
+
+    pub fn pop_atom(&mut self) -> Atom {
+        if let Some(a) = self.last_frame().stack.pop() {
+            a
+        } else {
+            panic!("Popped atom from empty frame");
+        }
+    }
+
+    /// Pops the top `n` atoms, keeping them in stack order.
+    pub fn pop_atoms(&mut self, n: usize) -> Vec<Atom> {
+        let stack = &mut self.last_frame().stack;
+        if stack.len() < n {
+            panic!("Expected {} atoms but {} were given", n, stack.len());
+        }
+        stack.split_off(stack.len() - n)
+    }
+
+    pub fn push_blank(&mut self, lazy: bool) {
+        let mut f = blank_frame();
+        f.lazy = lazy;
+        self.frames.push(f);
+        self.context.push_scope();
+    }
+
+    /// Pops the last frame, unbinding the variables bound in it.
+    pub fn pop(&mut self) -> Option<Frame> {
+        let frame = self.frames.pop()?;
+        if !self.frames.is_empty() {
+            self.context.pop_scope();
+        }
+        Some(frame)
+    }
+
+    pub fn bind_var(&mut self, ident: impl Into<Identifier>, atom: Atom) {
+        let ident = ident.into();
+        if self.relaxed && self.frames.len() == 1 {
+            self.redefine_var(ident, atom);
+        } else {
+            if self.frames.len() == 1 {
+                self.arities.clear();
+            }
+            check_reserved(&ident);
+            if self.context.insert(&ident, atom).is_some() {
+                panic!("Attempted to rebind existing variable {}.", ident);
+            }
+        }
+    }
+
+    /// Bind a top-level variable, replacing any existing binding. Functions
+    /// which captured the old value keep using it, so warn about those.
+    pub fn redefine_var(&mut self, ident: impl Into<Identifier>, atom: Atom) {
+        let ident = ident.into();
+        self.arities.clear();
+        check_reserved(&ident);
+        let old = self.context.insert_global(&ident, atom);
+        // Whatever the new value captures is noted afresh.
+        for functions in self.captured_by.values_mut() {
+            functions.retain(|f| *f != ident);
+        }
+        if old.is_none() {
+            return;
+        }
+        if let Some(functions) = self.captured_by.get(&ident) {
+            for f in functions {
+                eprintln!(
+                    "Warning: {} still uses the old definition of {}. Redefine it to use the new one.",
+                    f, ident
+                );
+            }
+        }
+    }
+
+    /// Record the names captured by the function bound to `ident`.
+    pub fn note_captures(&mut self, ident: &Identifier, captured: Vec<Identifier>) {
+        for name in captured {
+            let functions = self.captured_by.entry(name).or_default();
+            if !functions.contains(ident) {
+                functions.push(ident.clone());
+            }
+        }
+    }
+
+    /// Pops a value for each of `idents`, and binds them in a new frame.
+    pub fn bind_params(&mut self, idents: &[Identifier]) {
+        let values = self.pop_atoms(idents.len());
+        self.push_blank(false);
+        for (ident, value) in idents.iter().zip(values) {
+            check_reserved(ident);
+            if self.context.insert(ident, value).is_some() {
+                panic!("Attempted to rebind existing variable {}.", ident);
+            }
+        }
+    }
+
+    pub fn unbind_params(&mut self) {
+        let mut frame = self.pop().unwrap();
+        self.last_frame().stack.append(&mut frame.stack)
+    }
+
+    pub fn find_var(&self, ident: &Identifier) -> Option<Atom> {
+        self.context.get(ident).cloned()
+    }
+
+    /// Whether `ident` is bound anywhere but the top level.
+    pub fn binds_locally(&self, ident: &Identifier) -> bool {
+        matches!(self.context.scope_of(ident), Some(depth) if depth > 0)
+    }
+
+    pub fn lazy_mode(&self) -> bool {
+        self.frames.last().unwrap().lazy
+    }
+}
+
+#[test]
+fn test_context_scopes() {
+    let mut ctx = Context::new();
+    ctx.insert("a", 1);
+    ctx.insert("b", 2);
+    ctx.in_child_scope(|ctx| {
+        assert_eq!(None, ctx.insert("a", 3));
+        assert_eq!(Some(3), ctx.insert("a", 4));
+        assert_eq!(None, ctx.insert_global("c", 5));
+        assert_eq!(Some(&4), ctx.get("a"));
+        assert_eq!(Some(1), ctx.scope_of("a"));
+        assert_eq!(Some(0), ctx.scope_of("b"));
+        // Inner bindings still shadow a redefined global.
+        assert_eq!(Some(1), ctx.insert_global("a", 6));
+        assert_eq!(Some(&4), ctx.get("a"));
+    });
+    assert_eq!(Some(&6), ctx.get("a"));
+    assert_eq!(Some(&2), ctx.get("b"));
+    assert_eq!(Some(&5), ctx.get("c"));
+    assert_eq!(1, ctx.depth());
+
+    ctx.with_binding("d", 7, |ctx| assert_eq!(Some(&7), ctx.get("d")));
+    assert_eq!(None, ctx.get("d"));
+    ctx.push(8);
+    ctx.push(9);
+    assert_eq!(None, ctx.get("_0"));
+    assert_eq!(9, ctx.pop());
+    assert_eq!(8, ctx.pop());
+}
+
+#[test]
+fn test_captures() {
+    let env = crate::eval::eval_program(
+        "let a = 1\nlet b = 2\nfn f = a\nfn! f = b\nfn g = c\nlet c = 3\n'g get 'h let",
+    );
+    let captured_by = |name: &str| {
+        let functions = env.captured_by.get(&Identifier::from(name));
+        functions.cloned().unwrap_or_default()
+    };
+    assert!(captured_by("a").is_empty());
+    assert_eq!(vec![Identifier::from("f")], captured_by("b"));
+    assert_eq!(vec![Identifier::from("h")], captured_by("c"));
 }
 
 
@@ -1,8 +1,11 @@
+use pancake::bytecode;
 use pancake::eval::eval_program;
 use pancake::types::{Atom, Atom::Bool, Atom::Num};
 
 fn assert_prog_output(expected_out: Vec<Atom>, prog: &str) {
     let mut env = eval_program(prog);
+    assert_eq!(expected_out, env.pop().unwrap().stack);
+    let mut env = bytecode::eval_program(prog);
     assert_eq!(expected_out, env.pop().unwrap().stack)
 }
 
@@ -218,3 +221,231 @@ L 11 0 10 bs
 ",
     );
 }
+
+#[test]
+fn dataflow_combinators() {
+    assert_prog_output(ntoa(vec![2, 1]), "1 1 [1 +] dip");
+    assert_prog_output(ntoa(vec![4, 1, 2]), "3 1 2 [1 +] 2dip");
+    assert_prog_output(ntoa(vec![3, 1, 2]), "1 2 [+] keep2");
+    assert_prog_output(ntoa(vec![4, 6]), "3 [1 +] [2 *] bi");
+    assert_prog_output(ntoa(vec![4, 6, 0]), "3 [1 +] [2 *] [drop 0] tri");
+    assert_prog_output(ntoa(vec![4, 6, 9]), "3 [[1 +] [2 *] [dup *]] cleave");
+    assert_prog_output(ntoa(vec![2, 6]), "1 3 [1 +] [2 *] bi*");
+    assert_prog_output(ntoa(vec![2, 6, 0]), "1 3 5 [1 +] [2 *] [drop 0] tri*");
+    assert_prog_output(ntoa(vec![2, 4]), "1 3 [1 +] bi@");
+    assert_prog_output(ntoa(vec![2, 6, 0]), "1 3 5 [[1 +] [2 *] [drop 0]] spread");
+    assert_prog_output(ntoa(vec![0, 2, 4, 6]), "0 1 3 5 [1 +] 3 napply");
+}
+
+#[test]
+#[should_panic]
+fn dataflow_combinator_underflow() {
+    assert_prog_output(ntoa(vec![]), "1 [+] dip");
+}
+
+#[test]
+fn shuffle_words() {
+    assert_prog_output(ntoa(vec![1, 2, 1]), "1 2 over");
+    assert_prog_output(ntoa(vec![2]), "1 2 nip");
+    assert_prog_output(ntoa(vec![2, 1, 2]), "1 2 tuck");
+    assert_prog_output(ntoa(vec![1, 2, 1, 2]), "1 2 2dup");
+    assert_prog_output(ntoa(vec![1]), "1 2 3 2drop");
+    assert_prog_output(ntoa(vec![3, 4, 1, 2]), "1 2 3 4 2swap");
+    assert_prog_output(ntoa(vec![1, 2, 3, 1]), "1 2 3 pick");
+    assert_prog_output(ntoa(vec![3, 1, 2]), "1 2 3 -rot");
+    assert_prog_output(ntoa(vec![2, 3, 4, 1]), "1 2 3 4 rot4");
+    assert_prog_output(ntoa(vec![1, 1, 2]), "1 2 dupd");
+    assert_prog_output(ntoa(vec![2, 1, 3]), "1 2 3 swapd");
+}
+
+#[test]
+fn runtime_shuffle() {
+    assert_prog_output(ntoa(vec![3, 1, 1]), r#"1 2 3 "a b c -- c a a" shuffle"#);
+    assert_prog_output(
+        ntoa(vec![2, 1, 2]),
+        r#"
+[ "a b -- b a b" shuffle ] 'my_tuck fn
+1 2 my_tuck
+"#,
+    );
+}
+
+#[test]
+fn concatenation() {
+    assert_prog_output(ntoa(vec![4, 6]), "2 2 3 3 +,+");
+    assert_prog_output(ntoa(vec![1, 1, 3, 2]), "1 2 3 4 dup,swap,drop");
+    assert_prog_output(
+        ntoa(vec![2, 9]),
+        r"
+fn inc = 1 +
+fn sq n = n n *
+1 3 inc,sq
+",
+    );
+}
+
+#[test]
+#[should_panic(expected = "unknown arity")]
+fn concatenation_unknown_arity() {
+    assert_prog_output(ntoa(vec![]), "1 [1 +] 2 call,+");
+}
+
+#[test]
+fn concatenation_user_words() {
+    assert_prog_output(
+        ntoa(vec![4, 1]),
+        r"
+fn sq = dup *
+fn fact = dup 1 <= [ drop 1 ] [ dup 1 - fact * ] cond
+2 3 sq,fact 5 -
+",
+    );
+}
+
+#[test]
+#[should_panic(expected = "variadic arity")]
+fn concatenation_variadic_arity() {
+    assert_prog_output(ntoa(vec![]), "[1 2] list 3 splat,dup");
+}
+
+#[test]
+fn eager_capture() {
+    assert_prog_output(
+        ntoa(vec![1]),
+        r"
+let a = 1
+fn f = a
+fn g a = f
+5 g
+",
+    );
+    assert_prog_output(
+        ntoa(vec![6, 7]),
+        r"
+fn adder n = [n +]
+let add5 = 5 adder
+fn apply n q = n q call
+1 add5 call 2 add5 apply
+",
+    );
+    assert_prog_output(
+        ntoa(vec![3]),
+        r"
+fn inc = 1 +
+fn inc_twice = [inc] [inc] bi* +
+fn go inc = 0 1 inc_twice
+0 go
+",
+    );
+}
+
+#[test]
+fn redefinition() {
+    assert_prog_output(
+        ntoa(vec![2, 3, 2]),
+        r"
+let a = 1
+fn f = a 1 +
+f
+redef a = 2
+fn! f = a 1 +
+f
+fn! inc x = x 1 +
+1 inc
+",
+    );
+}
+
+#[test]
+#[should_panic(expected = "Attempted to rebind existing variable")]
+fn rebinding_without_redef() {
+    assert_prog_output(ntoa(vec![]), "let a = 1\nlet a = 2");
+}
+
+#[test]
+fn relaxed_redefinition() {
+    use pancake::eval::eval_line;
+    use pancake::vm2::Env;
+
+    let mut env = Env::new();
+    env.relaxed = true;
+    for line in ["fn f = 1", "fn g = f f", "fn f = 2", "g f"].iter() {
+        eval_line(line, &mut env);
+    }
+    assert_eq!(ntoa(vec![1, 1, 2]), env.pop().unwrap().stack);
+}
+
+#[test]
+fn stack_effect_declarations() {
+    assert_prog_output(
+        ntoa(vec![9, 7]),
+        r"
+fn sq ( n -- n ) = dup *
+fn add a b ( a b -- c ) = a b +
+3 sq 3 4 add
+",
+    );
+}
+
+#[test]
+#[should_panic(expected = "declared to take 1 and leave 1 values")]
+fn stack_effect_mismatch() {
+    assert_prog_output(ntoa(vec![]), "fn sq ( n -- n ) = dup");
+}
+
+#[test]
+#[should_panic(expected = "expected 1 arguments but 0 were given")]
+fn stack_effect_underflow() {
+    assert_prog_output(
+        ntoa(vec![]),
+        r"
+fn f ( n -- n ) = 1 + g
+fn g = 0
+f
+",
+    );
+}
+
+#[test]
+fn records_and_selection() {
+    assert_prog_output(
+        ntoa(vec![3, 5, 2]),
+        r"
+let p = [ 'x 1 2 + 'y 2 ] record
+fn norm1 r = r .x r .y +
+p .x p norm1 [ 'a p 'b 10 ] record .a .y
+",
+    );
+    assert_prog_output(ntoa(vec![5]), "[ 4 5 ] list .1");
+    assert_prog_output(
+        vec![Atom::Record(vec![
+            ("x".into(), Num(1)),
+            ("y".into(), Num(2)),
+        ])],
+        "[ 'y 2 'x 1 ] record",
+    );
+}
+
+#[test]
+#[should_panic(expected = "Record has no field z")]
+fn record_missing_field() {
+    assert_prog_output(ntoa(vec![]), "[ 'x 1 ] record .z");
+}
+
+#[test]
+fn list_updates() {
+    assert_prog_output(
+        ntoa(vec![1, 2, 3, 4, 1, 2, 3]),
+        r"
+let L = [1 2 3]list
+L 4 append splat
+L 0 10 set_at L cat .3 L .1 L .2
+",
+    );
+}
+
+#[test]
+#[should_panic(expected = "Cannot set index 3 of list of length 3")]
+fn list_set_out_of_bounds() {
+    assert_prog_output(ntoa(vec![]), "[1 2 3]list 3 0 set_at");
+}